@@ -0,0 +1,249 @@
+// kernel/src/allocator/mod.rs
+// Kernel heap - linked-list allocator that grows on demand
+//
+// The heap lives in a fixed window of the kernel half of the address
+// space. It starts empty; whenever an allocation does not fit, more pages
+// are backed with frames from the frame allocator and handed to the heap,
+// up to HEAP_MAX_SIZE. When memory runs out the allocation returns null,
+// and syscalls that allocate through the fallible helpers in globals.rs
+// turn that into E_NOMEM.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::{null_mut, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use crate::frame::FRAME_SIZE;
+
+/// Start of the kernel heap window
+pub const HEAP_START: u64 = 0xFFFF_8800_0000_0000;
+
+/// Largest the kernel heap may grow
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;  // 64 MB
+
+/// Smallest step the heap grows by, to keep page mapping off the hot path
+pub const HEAP_GROW_STEP: usize = 64 * 1024;  // 64 KB
+
+/// Heap usage counters, in bytes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HeapStats {
+    pub size: usize,      // Mapped so far
+    pub used: usize,
+    pub free: usize,
+    pub max_size: usize,
+    pub failures: u64,    // Allocations refused for lack of memory
+}
+
+/// Usage of physical memory and the kernel heap, as returned by SYS_MEM_STATS
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    pub frames_total: u64,     // Usable physical frames
+    pub frames_free: u64,
+    pub frames_reserved: u64,  // Taken at boot (kernel image, modules, boot info)
+    pub heap_size: u64,        // Bytes of heap mapped so far
+    pub heap_used: u64,
+    pub heap_max: u64,
+    pub heap_failures: u64,
+    pub frames_allocated: u64,  // Handed out by the frame allocator (last, to keep the layout)
+}
+
+/// A heap window at `base` that maps pages as it grows
+pub struct GrowingHeap {
+    heap: Heap,
+    base: u64,
+    mapped: usize,
+    max_size: usize,
+    failures: u64,
+}
+
+impl GrowingHeap {
+    /// An empty heap over `[base, base + max_size)`; nothing is mapped yet
+    pub const fn new(base: u64, max_size: usize) -> Self {
+        Self {
+            heap: Heap::empty(),
+            base,
+            mapped: 0,
+            max_size,
+            failures: 0,
+        }
+    }
+
+    /// Allocate, growing the heap through `map_page` if needed
+    ///
+    /// `map_page` backs the page at the given address with memory and
+    /// reports whether it succeeded.
+    pub fn allocate(&mut self, layout: Layout, map_page: impl FnMut(u64) -> bool) -> *mut u8 {
+        if let Ok(ptr) = self.heap.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // Worst case the block needs padding up to its alignment
+        self.grow(layout.size() + layout.align(), map_page);
+
+        match self.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(()) => {
+                self.failures += 1;
+                null_mut()
+            }
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `allocate` on this heap with the same layout.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            self.heap.deallocate(ptr, layout);
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.mapped,
+            used: self.heap.used(),
+            free: self.heap.free(),
+            max_size: self.max_size,
+            failures: self.failures,
+        }
+    }
+
+    /// Map at least `min` more bytes onto the end of the heap
+    ///
+    /// Grows by whole pages, at least HEAP_GROW_STEP and at most up to the
+    /// window's end. If a page cannot be mapped, the pages mapped so far
+    /// are still added.
+    fn grow(&mut self, min: usize, mut map_page: impl FnMut(u64) -> bool) {
+        let page = FRAME_SIZE as usize;
+        let wanted = min.max(HEAP_GROW_STEP).div_ceil(page) * page;
+        let by = wanted.min(self.max_size - self.mapped);
+
+        let start = self.base + self.mapped as u64;
+        let mut added = 0;
+        while added < by && map_page(start + added as u64) {
+            added += page;
+        }
+
+        if added == 0 {
+            return;
+        }
+
+        unsafe {
+            if self.mapped == 0 {
+                self.heap.init(self.base as *mut u8, added);
+            } else {
+                self.heap.extend(added);
+            }
+        }
+        self.mapped += added;
+    }
+}
+
+/// The kernel heap behind the global allocator
+pub struct KernelHeap(Mutex<GrowingHeap>);
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.0
+            .lock()
+            .allocate(layout, |addr| crate::vm::map_kernel_page(addr).is_ok())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.lock().deallocate(ptr, layout)
+    }
+}
+
+#[cfg_attr(not(test), global_allocator)]
+static ALLOCATOR: KernelHeap = KernelHeap(Mutex::new(GrowingHeap::new(HEAP_START, HEAP_MAX_SIZE)));
+
+/// Current usage of the kernel heap
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.0.lock().stats()
+}
+
+/// Combined frame and heap usage
+pub fn memory_stats() -> MemoryStats {
+    let frames = crate::frame::frame_stats();
+    let heap = heap_stats();
+
+    MemoryStats {
+        frames_total: frames.total as u64,
+        frames_free: frames.free as u64,
+        frames_reserved: frames.reserved as u64,
+        heap_size: heap.size as u64,
+        heap_used: heap.used as u64,
+        heap_max: heap.max_size as u64,
+        heap_failures: heap.failures,
+        frames_allocated: frames.allocated as u64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const PAGE: usize = FRAME_SIZE as usize;
+
+    /// A heap over a buffer of `pages` pages, of which `backed` can be mapped
+    fn with_heap(pages: usize, backed: usize, test: impl FnOnce(&mut GrowingHeap, &mut dyn FnMut(u64) -> bool)) {
+        let mut arena = vec![0u8; (pages + 1) * PAGE];
+        let base = (arena.as_mut_ptr() as u64).next_multiple_of(FRAME_SIZE);
+        let mut heap = GrowingHeap::new(base, pages * PAGE);
+
+        let mut mapped = 0;
+        let mut map_page = |_addr: u64| {
+            mapped += 1;
+            mapped <= backed
+        };
+        test(&mut heap, &mut map_page);
+    }
+
+    #[test]
+    fn test_heap_grows_on_demand() {
+        with_heap(64, 64, |heap, map_page| {
+            assert_eq!(heap.stats().size, 0, "Nothing is mapped up front");
+
+            let small = Layout::from_size_align(64, 8).unwrap();
+            let a = heap.allocate(small, &mut *map_page);
+            assert!(!a.is_null());
+            assert_eq!(heap.stats().size, HEAP_GROW_STEP);
+
+            // Larger than one step: grows by as much as needed
+            let big = Layout::from_size_align(HEAP_GROW_STEP * 2, 8).unwrap();
+            let b = heap.allocate(big, &mut *map_page);
+            assert!(!b.is_null());
+            assert!(heap.stats().size >= HEAP_GROW_STEP * 3);
+            assert!(heap.stats().used >= HEAP_GROW_STEP * 2 + 64);
+
+            unsafe { heap.deallocate(b, big) };
+            assert!(heap.stats().used < HEAP_GROW_STEP);
+        });
+    }
+
+    #[test]
+    fn test_heap_exhaustion_returns_null() {
+        // Window larger than the memory behind it
+        with_heap(64, 20, |heap, map_page| {
+            let layout = Layout::from_size_align(HEAP_GROW_STEP, 8).unwrap();
+            assert!(!heap.allocate(layout, &mut *map_page).is_null());
+
+            // Only 4 more pages can be mapped; they are kept but do not fit the block
+            assert!(heap.allocate(layout, &mut *map_page).is_null());
+            let stats = heap.stats();
+            assert_eq!(stats.size, 20 * PAGE);
+            assert_eq!(stats.failures, 1);
+
+            let small = Layout::from_size_align(PAGE, 8).unwrap();
+            assert!(!heap.allocate(small, &mut *map_page).is_null(), "What was mapped is usable");
+        });
+
+        // Never larger than the window
+        with_heap(8, 64, |heap, map_page| {
+            let layout = Layout::from_size_align(9 * PAGE, 8).unwrap();
+            assert!(heap.allocate(layout, &mut *map_page).is_null());
+            assert_eq!(heap.stats().size, 8 * PAGE);
+        });
+    }
+}
@@ -7,3 +7,19 @@ pub fn kernel_main() -> ! {
     }
 }
 
+pub fn wait_for_interrupt() {
+    // ARM64 wfi instruction would go here
+}
+
+/// Load a process's translation tables (no MMU support yet)
+pub fn switch_address_space(_root: u64) {
+}
+
+/// Copy bytes between kernel and user memory (no fault recovery yet)
+///
+/// # Safety
+/// Both sides of the copy must be valid for `len` bytes.
+pub unsafe fn copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> bool {
+    core::ptr::copy_nonoverlapping(src, dst, len);
+    true
+}
@@ -0,0 +1,158 @@
+// kernel/src/arch/x86_64/clock.rs
+// Clock sources - the TSC, PIT ticks and the CMOS RTC
+//
+// Time since boot comes from the TSC when CPUID reports it invariant (it
+// then runs at a constant rate in every power state). Its rate is not
+// reported, so it is measured against PIT channel 2 at boot. Without an
+// invariant TSC, or if calibration fails, the clock instead advances by
+// one timer period on each timer interrupt, which is coarser and stands
+// still while interrupts are off.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::clock::{ticks_to_ns, RtcTime};
+use super::timer::PIT_FREQUENCY;
+
+/// TSC ticks per second, 0 when the TSC is not used
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value at monotonic time 0
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+/// Length of a timer period, and the time counted in timer periods
+static TICK_NS: AtomicU64 = AtomicU64::new(0);
+static TICKS_NS: AtomicU64 = AtomicU64::new(0);
+
+/// How long TSC calibration measures for
+const CALIBRATION_MS: u64 = 10;
+
+/// PIT channel 2, and the port that gates it and shows its output
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE: u16 = 0x61;
+const PIT_GATE_ON: u8 = 1 << 0;
+const PIT_SPEAKER_ON: u8 = 1 << 1;
+const PIT_OUT_2: u8 = 1 << 5;
+
+/// CMOS RTC ports and registers
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+const RTC_UPDATING: u8 = 0x80;  // In status A
+const RTC_TIME_REGISTERS: [u8; 7] = [0x00, 0x02, 0x04, 0x07, 0x08, 0x09, 0x32];
+
+/// Pick and calibrate the clock source
+///
+/// Runs with interrupts disabled, after timer::init_timer.
+pub fn init_clock() {
+    if !has_invariant_tsc() {
+        crate::serial::write_str("[clock] No invariant TSC; counting timer ticks\n");
+        return;
+    }
+
+    match calibrate_tsc() {
+        Some(hz) => {
+            TSC_START.store(rdtsc(), Ordering::Relaxed);
+            TSC_HZ.store(hz, Ordering::Relaxed);
+        }
+        None => crate::serial::write_str("[clock] TSC calibration failed; counting timer ticks\n"),
+    }
+}
+
+/// Nanoseconds since boot
+pub fn now_ns() -> u64 {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => TICKS_NS.load(Ordering::Relaxed),
+        hz => ticks_to_ns(rdtsc().wrapping_sub(TSC_START.load(Ordering::Relaxed)), hz),
+    }
+}
+
+/// Count one timer period; called by the timer interrupt
+pub fn on_tick() {
+    TICKS_NS.fetch_add(TICK_NS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// The timer now fires every `divisor` PIT cycles
+pub fn set_tick_divisor(divisor: u16) {
+    TICK_NS.store(ticks_to_ns(divisor as u64, PIT_FREQUENCY as u64), Ordering::Relaxed);
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// CPUID 0x8000_0007 EDX bit 8
+fn has_invariant_tsc() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Count TSC ticks while PIT channel 2 counts down CALIBRATION_MS
+fn calibrate_tsc() -> Option<u64> {
+    let count = PIT_FREQUENCY as u64 * CALIBRATION_MS / 1000;
+
+    let mut gate = Port::<u8>::new(PIT_GATE);
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel = Port::<u8>::new(PIT_CHANNEL_2);
+
+    unsafe {
+        let saved = gate.read();
+        gate.write((saved & !PIT_SPEAKER_ON) | PIT_GATE_ON);
+
+        // Channel 2, low then high byte, mode 0 (output goes high at zero)
+        command.write(0xB0);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        let start = rdtsc();
+        // Well past the countdown on any CPU, in case there is no PIT
+        let mut spins: u64 = 0;
+        while gate.read() & PIT_OUT_2 == 0 {
+            spins += 1;
+            if spins > 100_000_000 {
+                gate.write(saved);
+                return None;
+            }
+        }
+        let end = rdtsc();
+        gate.write(saved);
+
+        // Below 1 MHz the measurement is wrong, not the TSC slow
+        let hz = (end - start) * PIT_FREQUENCY as u64 / count;
+        if hz < 1_000_000 {
+            None
+        } else {
+            Some(hz)
+        }
+    }
+}
+
+/// Read the date and time from the RTC
+///
+/// The registers are read until two passes agree, so an update in the
+/// middle of a read is not seen. None if the RTC never settles.
+pub fn read_rtc() -> Option<RtcTime> {
+    let mut last = None;
+
+    // An update takes about 2 ms; this gives up after about a second
+    for _ in 0..1_000_000 {
+        if cmos_read(RTC_STATUS_A) & RTC_UPDATING != 0 {
+            continue;
+        }
+
+        let raw = RTC_TIME_REGISTERS.map(cmos_read);
+        if last == Some(raw) {
+            return Some(RtcTime::decode(raw, cmos_read(RTC_STATUS_B)));
+        }
+        last = Some(raw);
+    }
+    None
+}
+
+fn cmos_read(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
@@ -0,0 +1,321 @@
+// kernel/src/arch/x86_64/context.rs
+// Saved CPU state of a process that is not running
+//
+// Every process has its own kernel stack, and the user registers are saved
+// at its top on the way into the kernel (SyscallFrame or the interrupt
+// frame). Switching processes therefore only has to switch kernel stacks:
+// switch_context saves the registers the Rust ABI expects a call to
+// preserve, plus RFLAGS, and resumes the other process where it last
+// switched out. The rest of the per-process state is what the CPU keeps
+// outside the general registers: the FS/GS bases (both halves of the
+// swapgs pair) and the FPU/SSE/AVX registers, saved with XSAVE, or FXSAVE
+// on CPUs without it.
+
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ffi::c_void;
+use core::fmt;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{FsBase, GsBase, KernelGsBase};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+use x86_64::VirtAddr;
+use crate::error::E_OK;
+use crate::globals::try_vec;
+use super::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+
+/// Size of each process's kernel stack
+pub const TASK_KERNEL_STACK_SIZE: usize = 8 * 4096;  // 32 KB
+
+/// RFLAGS a process starts ring 3 with: interrupts enabled
+const USER_RFLAGS: u64 = 0x202;
+
+/// Whether init_fpu turned on XSAVE (otherwise FXSAVE is used)
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+/// Bytes XSAVE needs for the enabled state components
+static FPU_AREA_SIZE: AtomicUsize = AtomicUsize::new(512);  // FXSAVE's size
+
+/// State of a process that is not running
+///
+/// Filled by switch_to when the process switches out, and loaded again
+/// when another process switches to it.
+#[repr(C)]
+#[derive(Debug, Default)]
+pub struct CpuContext {
+    pub rsp: u64,  // Kernel stack pointer
+    pub rip: u64,  // Where to resume in the kernel
+    pub rflags: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub fs_base: u64,
+    pub gs_base: u64,
+    pub kernel_gs_base: u64,  // The other half of the swapgs pair
+    pub kernel_stack_top: u64,  // Loaded into RSP0 and the syscall stack; 0 until set up
+    pub fpu: FpuArea,
+}
+
+// switch_context hardcodes these offsets
+const _: () = {
+    assert!(offset_of!(CpuContext, rsp) == 0x00);
+    assert!(offset_of!(CpuContext, rip) == 0x08);
+    assert!(offset_of!(CpuContext, rflags) == 0x10);
+    assert!(offset_of!(CpuContext, rbx) == 0x18);
+    assert!(offset_of!(CpuContext, rbp) == 0x20);
+    assert!(offset_of!(CpuContext, r12) == 0x28);
+    assert!(offset_of!(CpuContext, r13) == 0x30);
+    assert!(offset_of!(CpuContext, r14) == 0x38);
+    assert!(offset_of!(CpuContext, r15) == 0x40);
+};
+
+// switch_context(from = rdi, to = rsi): save the current kernel context in
+// `from` and continue with `to`. Returns when something switches back.
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "    mov [rdi + 0x00], rsp",
+    "    lea rax, [rip + .Lswitch_context_resume]",
+    "    mov [rdi + 0x08], rax",
+    "    pushfq",
+    "    pop qword ptr [rdi + 0x10]",
+    "    mov [rdi + 0x18], rbx",
+    "    mov [rdi + 0x20], rbp",
+    "    mov [rdi + 0x28], r12",
+    "    mov [rdi + 0x30], r13",
+    "    mov [rdi + 0x38], r14",
+    "    mov [rdi + 0x40], r15",
+    "    mov rbx, [rsi + 0x18]",
+    "    mov rbp, [rsi + 0x20]",
+    "    mov r12, [rsi + 0x28]",
+    "    mov r13, [rsi + 0x30]",
+    "    mov r14, [rsi + 0x38]",
+    "    mov r15, [rsi + 0x40]",
+    "    mov rsp, [rsi + 0x00]",
+    "    push qword ptr [rsi + 0x10]",
+    "    popfq",
+    "    jmp qword ptr [rsi + 0x08]",
+    ".Lswitch_context_resume:",
+    "    ret",
+    "",
+    // First run of a process: its kernel stack holds an iretq frame
+    ".global enter_user_first",
+    "enter_user_first:",
+    "    iretq",
+);
+
+extern "C" {
+    fn switch_context(from: *mut c_void, to: *const c_void);  // CpuContexts
+    fn enter_user_first();
+}
+
+impl CpuContext {
+    /// Whether the context was made by boot_context or user_context, so
+    /// switch_to may save into or resume from it
+    pub fn is_set_up(&self) -> bool {
+        self.kernel_stack_top != 0 && !self.fpu.buf.is_empty()
+    }
+}
+
+/// A process's kernel stack
+pub struct KernelStack {
+    buf: Vec<u8>,
+}
+
+impl KernelStack {
+    pub fn new() -> Result<Self, u64> {
+        Ok(Self { buf: try_vec(0u8, TASK_KERNEL_STACK_SIZE)? })
+    }
+
+    /// Highest 16-byte aligned address of the stack (stacks grow down)
+    pub fn top(&self) -> u64 {
+        (self.buf.as_ptr() as u64 + self.buf.len() as u64) & !0xF
+    }
+}
+
+impl fmt::Debug for KernelStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KernelStack({:#x})", self.top())
+    }
+}
+
+/// A 64-byte aligned save area for XSAVE or FXSAVE
+#[derive(Default)]
+pub struct FpuArea {
+    buf: Vec<u8>,  // Empty until allocated
+}
+
+impl fmt::Debug for FpuArea {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FpuArea({} bytes)", self.buf.len())
+    }
+}
+
+impl FpuArea {
+    /// A save area holding the state the FPU has after FNINIT
+    pub fn new() -> Result<Self, u64> {
+        let mut area = Self { buf: try_vec(0u8, FPU_AREA_SIZE.load(Ordering::Relaxed) + 63)? };
+        let base = area.as_mut_ptr();
+        unsafe {
+            (base as *mut u16).write(0x037F);  // FCW: all x87 exceptions masked
+            (base.add(24) as *mut u32).write(0x1F80);  // MXCSR: all SSE exceptions masked
+        }
+        Ok(area)
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        let ptr = self.buf.as_mut_ptr();
+        ptr.wrapping_add(ptr.align_offset(64))
+    }
+}
+
+/// Enable the FPU, SSE and, where the CPU has them, XSAVE and AVX
+pub fn init_fpu() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+
+        let features = __cpuid(1);
+        if features.ecx & (1 << 26) != 0 {
+            Cr4::update(|flags| flags.insert(Cr4Flags::OSXSAVE));
+
+            let mut components = XCr0Flags::X87 | XCr0Flags::SSE;
+            if features.ecx & (1 << 28) != 0 {
+                components |= XCr0Flags::AVX;
+            }
+            XCr0::write(components);
+
+            // EBX of leaf 0xD is the area size for what XCR0 enables
+            FPU_AREA_SIZE.store(__cpuid_count(0xD, 0).ebx as usize, Ordering::Relaxed);
+            USE_XSAVE.store(true, Ordering::Relaxed);
+        }
+
+        asm!("fninit", options(nomem, nostack));
+    }
+}
+
+/// Context for the process already running on the boot stack
+///
+/// The registers are filled in the first time it switches out.
+pub fn boot_context() -> Result<CpuContext, u64> {
+    Ok(CpuContext {
+        kernel_stack_top: super::gdt::kernel_stack_top().as_u64(),
+        fpu: FpuArea::new()?,
+        ..CpuContext::default()
+    })
+}
+
+/// Context for a process that has never run
+///
+/// The top of `kernel_stack` is set up so that the first switch_to the
+/// process enters ring 3 at `entry` with the stack pointer `stack`.
+pub fn user_context(kernel_stack: &mut KernelStack, entry: u64, stack: u64) -> Result<CpuContext, u64> {
+    let top = kernel_stack.top();
+
+    // iretq frame, lowest address first
+    let frame = [
+        entry,
+        u64::from(USER_CODE_SELECTOR.0),
+        USER_RFLAGS,
+        stack,
+        u64::from(USER_DATA_SELECTOR.0),
+    ];
+    let rsp = top - core::mem::size_of_val(&frame) as u64;
+    unsafe { (rsp as *mut [u64; 5]).write(frame) };
+
+    Ok(CpuContext {
+        rsp,
+        rip: enter_user_first as *const () as u64,
+        rflags: 0x2,  // Interrupts stay off until iretq
+        kernel_stack_top: top,
+        // Ring 3 starts with the user GS base loaded, like after sysretq
+        kernel_gs_base: super::syscall::cpu_local_base(),
+        fpu: FpuArea::new()?,
+        ..CpuContext::default()
+    })
+}
+
+/// Save the running process's state in `from` and resume `to`
+///
+/// Returns E_OK once another switch_to resumes `from`. The caller switches
+/// the address space and must not hold locks the other process may take.
+///
+/// # Safety
+/// Both contexts must have been made by boot_context or user_context, and
+/// `to` must not be the running process. Interrupts must be disabled.
+pub unsafe fn switch_to(from: *mut CpuContext, to: *mut CpuContext) -> u64 {
+    let from = &mut *from;
+    let to = &mut *to;
+
+    save_fpu(from.fpu.as_mut_ptr());
+    from.fs_base = FsBase::read().as_u64();
+    from.gs_base = GsBase::read().as_u64();
+    from.kernel_gs_base = KernelGsBase::read().as_u64();
+
+    FsBase::write(VirtAddr::new(to.fs_base));
+    GsBase::write(VirtAddr::new(to.gs_base));
+    KernelGsBase::write(VirtAddr::new(to.kernel_gs_base));
+    restore_fpu(to.fpu.as_mut_ptr());
+
+    super::gdt::set_kernel_stack(VirtAddr::new(to.kernel_stack_top));
+    super::syscall::set_kernel_stack(to.kernel_stack_top);
+
+    switch_context(from as *mut CpuContext as *mut c_void, to as *const CpuContext as *const c_void);
+    E_OK
+}
+
+unsafe fn save_fpu(area: *mut u8) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+    } else {
+        asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+    }
+}
+
+unsafe fn restore_fpu(area: *mut u8) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+    } else {
+        asm!("fxrstor64 [{}]", in(reg) area, options(nostack));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_context_enters_ring3_at_entry() {
+        let mut stack = KernelStack::new().unwrap();
+        let context = user_context(&mut stack, 0x1_0000_1000, 0x1_0000_8000).unwrap();
+
+        assert!(context.is_set_up());
+        assert_eq!(context.kernel_stack_top, stack.top());
+        assert_eq!(context.kernel_stack_top % 16, 0);
+        assert_eq!(context.rip, enter_user_first as *const () as u64);
+
+        // What iretq pops: rip, cs, rflags, rsp, ss
+        let frame = unsafe { *(context.rsp as *const [u64; 5]) };
+        assert_eq!(frame, [
+            0x1_0000_1000,
+            u64::from(USER_CODE_SELECTOR.0),
+            USER_RFLAGS,
+            0x1_0000_8000,
+            u64::from(USER_DATA_SELECTOR.0),
+        ]);
+        assert_eq!(context.rsp + 40, context.kernel_stack_top);
+    }
+
+    #[test]
+    fn test_default_context_is_not_set_up() {
+        assert!(!CpuContext::default().is_set_up());
+    }
+}
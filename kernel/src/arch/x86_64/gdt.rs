@@ -0,0 +1,107 @@
+// kernel/src/arch/x86_64/gdt.rs
+// GDT and TSS - privilege levels and the stacks exceptions run on
+//
+// Long mode ignores segment bases and limits, but the CPU still takes the
+// privilege level from the code segment, so there are kernel and user code
+// and data segments. The TSS supplies the remaining stacks: RSP0, which
+// the CPU switches to when an interrupt or exception arrives in ring 3,
+// and the IST stacks that double fault, NMI and machine check always run
+// on. Those can strike while the current kernel stack is unusable (a
+// double fault is typically a kernel stack overflow), so each has its own.
+//
+// The segment order is fixed by SYSCALL/SYSRET: kernel data directly after
+// kernel code, and user data directly before user code.
+//
+// Each CPU needs its own TSS and stacks; only the boot CPU is brought up,
+// so there is one set.
+
+use core::ptr::{addr_of, addr_of_mut};
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+/// Segment selectors, in GDT order
+pub const KERNEL_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(1, PrivilegeLevel::Ring0);
+pub const KERNEL_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(2, PrivilegeLevel::Ring0);
+pub const USER_DATA_SELECTOR: SegmentSelector = SegmentSelector::new(3, PrivilegeLevel::Ring3);
+pub const USER_CODE_SELECTOR: SegmentSelector = SegmentSelector::new(4, PrivilegeLevel::Ring3);
+pub const TSS_SELECTOR: SegmentSelector = SegmentSelector::new(5, PrivilegeLevel::Ring0);
+
+/// Interrupt stack table slots
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// Size of each IST stack
+const IST_STACK_SIZE: usize = 5 * 4096;  // 20 KB
+
+/// Size of the stack entered from ring 3 (RSP0)
+const KERNEL_STACK_SIZE: usize = 16 * 4096;  // 64 KB
+
+#[repr(C, align(16))]
+struct Stack<const N: usize>([u8; N]);
+
+static mut DOUBLE_FAULT_STACK: Stack<IST_STACK_SIZE> = Stack([0; IST_STACK_SIZE]);
+static mut NMI_STACK: Stack<IST_STACK_SIZE> = Stack([0; IST_STACK_SIZE]);
+static mut MACHINE_CHECK_STACK: Stack<IST_STACK_SIZE> = Stack([0; IST_STACK_SIZE]);
+static mut KERNEL_STACK: Stack<KERNEL_STACK_SIZE> = Stack([0; KERNEL_STACK_SIZE]);
+
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+
+/// Top of a stack (stacks grow down)
+fn stack_top<const N: usize>(stack: *const Stack<N>) -> VirtAddr {
+    VirtAddr::from_ptr(stack) + N
+}
+
+/// Top of the stack the kernel runs on when entered from ring 3
+///
+/// Interrupts find it in the TSS (RSP0); SYSCALL, which does not switch
+/// stacks, is given it by syscall::init_syscalls.
+pub fn kernel_stack_top() -> VirtAddr {
+    stack_top(addr_of!(KERNEL_STACK))
+}
+
+/// Make `top` the stack interrupts from ring 3 arrive on
+///
+/// Each process has its own kernel stack; this is called on every switch.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*addr_of_mut!(TSS)).privilege_stack_table[0] = top };
+}
+
+/// Load the GDT and TSS and reload the segment registers
+///
+/// Must run before the IDT is loaded, as its IST entries point into the
+/// TSS.
+pub fn init_gdt() {
+    unsafe {
+        let tss = &mut *addr_of_mut!(TSS);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_top(addr_of!(DOUBLE_FAULT_STACK));
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = stack_top(addr_of!(NMI_STACK));
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = stack_top(addr_of!(MACHINE_CHECK_STACK));
+        tss.privilege_stack_table[0] = kernel_stack_top();
+
+        let gdt = &mut *addr_of_mut!(GDT);
+        let selectors = [
+            gdt.add_entry(Descriptor::kernel_code_segment()),
+            gdt.add_entry(Descriptor::kernel_data_segment()),
+            gdt.add_entry(Descriptor::user_data_segment()),
+            gdt.add_entry(Descriptor::user_code_segment()),
+            gdt.add_entry(Descriptor::tss_segment(&*addr_of!(TSS))),
+        ];
+        debug_assert_eq!(
+            selectors.map(|s| s.0),
+            [KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_DATA_SELECTOR, USER_CODE_SELECTOR, TSS_SELECTOR]
+                .map(|s| s.0)
+        );
+        (*addr_of!(GDT)).load();
+
+        CS::set_reg(KERNEL_CODE_SELECTOR);
+        SS::set_reg(KERNEL_DATA_SELECTOR);
+        DS::set_reg(KERNEL_DATA_SELECTOR);
+        ES::set_reg(KERNEL_DATA_SELECTOR);
+        load_tss(TSS_SELECTOR);
+    }
+}
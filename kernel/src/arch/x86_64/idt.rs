@@ -1,23 +1,35 @@
 use core::mem::size_of;
-use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+use crate::error::{FAULT_EXEC, FAULT_PROTECTION, FAULT_WRITE};
+use crate::fault::FaultAction;
+use super::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+use super::timer::{timer_interrupt_handler, TIMER_VECTOR};
 
 /// Global IDT that will be used
 static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
 
 /// Initialize IDT with exception and interrupt handlers
+///
+/// The GDT must be loaded first (gdt::init_gdt), for the IST stacks.
 pub fn init_idt() {
     unsafe {
         // Exceptions (0-31)
         IDT.divide_error.set_handler_fn(divide_error_handler);
         IDT.debug.set_handler_fn(debug_handler);
-        IDT.non_maskable_interrupt.set_handler_fn(nmi_handler);
+        IDT.non_maskable_interrupt
+            .set_handler_fn(nmi_handler)
+            .set_stack_index(NMI_IST_INDEX);
         IDT.breakpoint.set_handler_fn(breakpoint_handler);
         IDT.overflow.set_handler_fn(overflow_handler);
         IDT.bound_range_exceeded.set_handler_fn(bound_range_handler);
         IDT.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         IDT.device_not_available.set_handler_fn(device_not_available_handler);
-        // Double fault needs a special stack
-        // IDT.double_fault.set_handler_fn(double_fault_handler);
+        // Runs on its own stack, as the kernel stack may be what overflowed
+        IDT.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
         IDT.invalid_tss.set_handler_fn(invalid_tss_handler);
         IDT.segment_not_present.set_handler_fn(segment_not_present_handler);
         IDT.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
@@ -25,80 +37,223 @@ pub fn init_idt() {
         IDT.page_fault.set_handler_fn(page_fault_handler);
         IDT.x87_floating_point.set_handler_fn(floating_point_handler);
         IDT.alignment_check.set_handler_fn(alignment_check_handler);
-        IDT.machine_check.set_handler_fn(machine_check_handler);
+        IDT.machine_check
+            .set_handler_fn(machine_check_handler)
+            .set_stack_index(MACHINE_CHECK_IST_INDEX);
         IDT.simd_floating_point.set_handler_fn(simd_floating_point_handler);
 
+        // Hardware interrupts (32+)
+        IDT[TIMER_VECTOR as usize].set_handler_fn(timer_interrupt_handler);
+
         // Load the IDT
         IDT.load();
     }
 }
 
 // Exception handlers
-extern "x86-interrupt" fn divide_error_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn divide_error_handler(mut frame: InterruptStackFrame) {
+    if user_exception(&mut frame, 0, 0) {
+        return;
+    }
     panic!("Divide Error!");
 }
 
-extern "x86-interrupt" fn debug_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn debug_handler(mut frame: InterruptStackFrame) {
+    if user_exception(&mut frame, 1, 0) {
+        return;
+    }
     panic!("Debug Exception!");
 }
 
-extern "x86-interrupt" fn nmi_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn nmi_handler(_frame: InterruptStackFrame) {
     panic!("Non-Maskable Interrupt!");
 }
 
-extern "x86-interrupt" fn breakpoint_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn breakpoint_handler(_frame: InterruptStackFrame) {
     // Breakpoint - this is expected during debugging
 }
 
-extern "x86-interrupt" fn overflow_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn overflow_handler(mut frame: InterruptStackFrame) {
+    if user_exception(&mut frame, 4, 0) {
+        return;
+    }
     panic!("Overflow!");
 }
 
-extern "x86-interrupt" fn bound_range_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn bound_range_handler(mut frame: InterruptStackFrame) {
+    if user_exception(&mut frame, 5, 0) {
+        return;
+    }
     panic!("Bound Range Exceeded!");
 }
 
-extern "x86-interrupt" fn invalid_opcode_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn invalid_opcode_handler(mut frame: InterruptStackFrame) {
+    if user_exception(&mut frame, 6, 0) {
+        return;
+    }
     panic!("Invalid Opcode!");
 }
 
-extern "x86-interrupt" fn device_not_available_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn device_not_available_handler(mut frame: InterruptStackFrame) {
+    if user_exception(&mut frame, 7, 0) {
+        return;
+    }
     panic!("Device Not Available!");
 }
 
-extern "x86-interrupt" fn invalid_tss_handler(_frame: x86_64::structures::idt::InterruptStackFrame, _code: u64) {
+extern "x86-interrupt" fn double_fault_handler(frame: InterruptStackFrame, _code: u64) -> ! {
+    panic!(
+        "Double Fault! (kernel stack overflow?) rip={:#x} rsp={:#x}",
+        frame.instruction_pointer.as_u64(),
+        frame.stack_pointer.as_u64()
+    );
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(mut frame: InterruptStackFrame, code: u64) {
+    if user_exception(&mut frame, 10, code) {
+        return;
+    }
     panic!("Invalid TSS!");
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(_frame: x86_64::structures::idt::InterruptStackFrame, _code: u64) {
+extern "x86-interrupt" fn segment_not_present_handler(mut frame: InterruptStackFrame, code: u64) {
+    if user_exception(&mut frame, 11, code) {
+        return;
+    }
     panic!("Segment Not Present!");
 }
 
-extern "x86-interrupt" fn stack_segment_fault_handler(_frame: x86_64::structures::idt::InterruptStackFrame, _code: u64) {
+extern "x86-interrupt" fn stack_segment_fault_handler(mut frame: InterruptStackFrame, code: u64) {
+    if user_exception(&mut frame, 12, code) {
+        return;
+    }
     panic!("Stack Segment Fault!");
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(_frame: x86_64::structures::idt::InterruptStackFrame, _code: u64) {
+extern "x86-interrupt" fn general_protection_fault_handler(mut frame: InterruptStackFrame, code: u64) {
+    if resume_user_copy(&mut frame) || user_exception(&mut frame, 13, code) {
+        return;
+    }
     panic!("General Protection Fault!");
 }
 
-extern "x86-interrupt" fn page_fault_handler(_frame: x86_64::structures::idt::InterruptStackFrame, _code: u64) {
+extern "x86-interrupt" fn page_fault_handler(mut frame: InterruptStackFrame, code: PageFaultErrorCode) {
+    if resume_user_copy(&mut frame) {
+        return;
+    }
+
+    // Faults in user mode go to the process's pager or exception port
+    if from_user(&frame) {
+        let action = crate::fault::user_page_fault(
+            Cr2::read().as_u64(),
+            fault_access(code),
+            code.bits(),
+            frame.instruction_pointer.as_u64(),
+            frame.stack_pointer.as_u64(),
+        );
+        apply_fault_action(&mut frame, action);
+        return;
+    }
     panic!("Page Fault!");
 }
 
-extern "x86-interrupt" fn floating_point_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+/// FAULT_* bits describing a page fault
+fn fault_access(code: PageFaultErrorCode) -> u64 {
+    let mut access = 0;
+    if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        access |= FAULT_WRITE;
+    }
+    if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        access |= FAULT_EXEC;
+    }
+    if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        access |= FAULT_PROTECTION;
+    }
+    access
+}
+
+/// Whether the interrupted code ran in user mode (ring 3)
+fn from_user(frame: &InterruptStackFrame) -> bool {
+    frame.code_segment & 3 == 3
+}
+
+/// Hand exception `vector` to the exception port if it was raised in user
+/// mode, and carry out the answer
+///
+/// Returns true if the process is to continue. Returns false for kernel
+/// exceptions, which the caller turns into a panic.
+fn user_exception(frame: &mut InterruptStackFrame, vector: u64, code: u64) -> bool {
+    if !from_user(frame) {
+        return false;
+    }
+
+    let action = crate::fault::user_exception(
+        vector,
+        code,
+        frame.instruction_pointer.as_u64(),
+        frame.stack_pointer.as_u64(),
+        0,
+    );
+    apply_fault_action(frame, action);
+    true
+}
+
+/// Return to the faulting process as its fault handler decided, or kill it
+fn apply_fault_action(frame: &mut InterruptStackFrame, action: FaultAction) {
+    match action {
+        FaultAction::Retry => {}
+        FaultAction::Resume { rip, rsp } => unsafe {
+            frame.as_mut().update(|f| {
+                if rip != 0 {
+                    f.instruction_pointer = VirtAddr::new(rip);
+                }
+                if rsp != 0 {
+                    f.stack_pointer = VirtAddr::new(rsp);
+                }
+            });
+        },
+        FaultAction::Kill => crate::task::kill_current(),
+    }
+}
+
+/// If the fault hit a copy to or from user memory, make the copy return
+/// failure (the syscall then reports E_FAULT) instead of crashing the kernel
+fn resume_user_copy(frame: &mut InterruptStackFrame) -> bool {
+    match super::uaccess::fault_fixup(frame.instruction_pointer.as_u64()) {
+        Some(resume) => {
+            unsafe {
+                frame
+                    .as_mut()
+                    .update(|f| f.instruction_pointer = VirtAddr::new(resume));
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+extern "x86-interrupt" fn floating_point_handler(mut frame: InterruptStackFrame) {
+    if user_exception(&mut frame, 16, 0) {
+        return;
+    }
     panic!("x87 Floating Point Exception!");
 }
 
-extern "x86-interrupt" fn alignment_check_handler(_frame: x86_64::structures::idt::InterruptStackFrame, _code: u64) {
+extern "x86-interrupt" fn alignment_check_handler(mut frame: InterruptStackFrame, code: u64) {
+    if user_exception(&mut frame, 17, code) {
+        return;
+    }
     panic!("Alignment Check!");
 }
 
-extern "x86-interrupt" fn machine_check_handler(_frame: x86_64::structures::idt::InterruptStackFrame) -> ! {
+extern "x86-interrupt" fn machine_check_handler(_frame: InterruptStackFrame) -> ! {
     panic!("Machine Check!");
 }
 
-extern "x86-interrupt" fn simd_floating_point_handler(_frame: x86_64::structures::idt::InterruptStackFrame) {
+extern "x86-interrupt" fn simd_floating_point_handler(mut frame: InterruptStackFrame) {
+    if user_exception(&mut frame, 19, 0) {
+        return;
+    }
     panic!("SIMD Floating Point Exception!");
 }
 
@@ -1,15 +1,57 @@
 // kernel/src/arch/x86_64/mod.rs
 // x86_64 architecture-specific code
 
+pub mod clock;
+pub mod context;
+pub mod gdt;
 pub mod idt;
+#[cfg(feature = "qemu-test")]
+mod qemu_test;
+pub mod syscall;
+pub mod timer;
+pub mod uaccess;
+
+pub use context::{init_fpu, CpuContext, KernelStack};
+pub use uaccess::copy_user_bytes;
 
 pub fn kernel_main() -> ! {
+    gdt::init_gdt();
     idt::init_idt();
+    syscall::init_syscalls();
+    timer::init_timer();
+    clock::init_clock();
+    crate::clock::init();
+
+    crate::serial::write_str("[kernel] GDT, TSS, IDT, syscall entry, timer and clock initialized\n");
 
-    crate::serial::write_str("[kernel] IDT initialized\n");
+    #[cfg(feature = "qemu-test")]
+    qemu_test::run();
 
     loop {
         core::arch::x86_64::hlt();
     }
 }
 
+/// Load the page tables rooted at physical address `root` into CR3
+///
+/// Skipped if they are already loaded, which would only flush the TLB.
+pub fn switch_address_space(root: u64) {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PhysFrame;
+    use x86_64::PhysAddr;
+
+    let (current, flags) = Cr3::read();
+    if current.start_address().as_u64() != root {
+        unsafe { Cr3::write(PhysFrame::containing_address(PhysAddr::new(root)), flags) };
+    }
+}
+
+/// Halt until the next interrupt
+///
+/// Interrupts are enabled for the hlt only: the handler runs before this
+/// returns, and the caller continues with them disabled again, so no
+/// interrupt ever finds the kernel state locked.
+pub fn wait_for_interrupt() {
+    x86_64::instructions::interrupts::enable_and_hlt();
+    x86_64::instructions::interrupts::disable();
+}
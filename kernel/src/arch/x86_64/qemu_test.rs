@@ -0,0 +1,173 @@
+// kernel/src/arch/x86_64/qemu_test.rs
+// Boot-time test of the syscall path, built with `--features qemu-test`
+//
+// Instead of idling, kernel_main copies a few instructions into init's
+// address space and drops to ring 3 to run them. They make an echo
+// syscall that checks every argument register arrives and every register
+// but rcx and r11 survives, then an unknown syscall that must come back
+// from handle_syscall as E_INVALID_SYSCALL, and finally report the verdict
+// with an exit syscall. The kernel prints it and leaves QEMU through the
+// isa-debug-exit device (scripts/qemu_test.sh).
+
+use core::arch::{asm, global_asm};
+use x86_64::instructions::port::Port;
+use crate::error::{VM_EXEC, VM_READ, VM_WRITE};
+use crate::globals::kernel_state_mut;
+use crate::task::INIT_PID;
+use crate::vm::{self, AddressSpace, PHYS_OFFSET};
+use super::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+
+/// Test-only syscalls, answered before handle_syscall
+const QEMU_TEST_ECHO: u64 = 0x7E57_0001;
+const QEMU_TEST_EXIT: u64 = 0x7E57_0002;
+
+/// What QEMU_TEST_ECHO returns when its arguments arrive intact
+const ECHO_OK: u64 = 0x600D;
+const ECHO_BAD: u64 = 0xBAD;
+
+/// isa-debug-exit port; QEMU exits with status (value << 1) | 1
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+const EXIT_SUCCESS: u32 = 0x10;  // status 33
+const EXIT_FAILURE: u32 = 0x11;  // status 35
+
+// Position independent, as it runs from a copy in a user page
+global_asm!(
+    ".global qemu_test_user_start",
+    ".global qemu_test_user_end",
+    "qemu_test_user_start:",
+    "    mov rbp, rsp",
+    "    mov rbx, 0x1111",
+    "    mov r12, 0x1212",
+    "    mov r13, 0x1313",
+    "    mov r14, 0x1414",
+    "    mov r15, 0x1515",
+    "    mov eax, 0x7E570001",  // QEMU_TEST_ECHO
+    "    mov edi, 1",
+    "    mov esi, 2",
+    "    mov edx, 3",
+    "    mov r10d, 4",
+    "    mov r8d, 5",
+    "    mov r9d, 6",
+    "    syscall",
+    "    cmp rax, 0x600D",  // ECHO_OK
+    "    jne .Lqemu_test_fail",
+    "    cmp rdi, 1",
+    "    jne .Lqemu_test_fail",
+    "    cmp rsi, 2",
+    "    jne .Lqemu_test_fail",
+    "    cmp rdx, 3",
+    "    jne .Lqemu_test_fail",
+    "    cmp r10, 4",
+    "    jne .Lqemu_test_fail",
+    "    cmp r8, 5",
+    "    jne .Lqemu_test_fail",
+    "    cmp r9, 6",
+    "    jne .Lqemu_test_fail",
+    "    cmp rbx, 0x1111",
+    "    jne .Lqemu_test_fail",
+    "    cmp r12, 0x1212",
+    "    jne .Lqemu_test_fail",
+    "    cmp r13, 0x1313",
+    "    jne .Lqemu_test_fail",
+    "    cmp r14, 0x1414",
+    "    jne .Lqemu_test_fail",
+    "    cmp r15, 0x1515",
+    "    jne .Lqemu_test_fail",
+    "    cmp rsp, rbp",
+    "    jne .Lqemu_test_fail",
+    "    mov eax, 0xFFFF",  // no such syscall
+    "    syscall",
+    "    mov rcx, 0xFFFFFFFF0000000A",  // E_INVALID_SYSCALL
+    "    cmp rax, rcx",
+    "    jne .Lqemu_test_fail",
+    "    xor edi, edi",
+    "    jmp .Lqemu_test_exit",
+    ".Lqemu_test_fail:",
+    "    mov edi, 1",
+    ".Lqemu_test_exit:",
+    "    mov eax, 0x7E570002",  // QEMU_TEST_EXIT
+    "    syscall",
+    "    ud2",
+    "qemu_test_user_end:",
+);
+
+extern "C" {
+    static qemu_test_user_start: u8;
+    static qemu_test_user_end: u8;
+}
+
+/// Run the user half of the test as init_server; never returns
+pub fn run() -> ! {
+    crate::serial::write_str("[qemu-test] Entering ring 3\n");
+
+    let root = kernel_state_mut().process_mut(INIT_PID).map_or(0, |p| p.page_table_root);
+    if root == 0 {
+        fail("init_server has no address space");
+    }
+    super::switch_address_space(root);
+
+    let code = vm::vm_allocate(0, 4096, VM_READ | VM_EXEC);
+    let stack = vm::vm_allocate(0, 4096, VM_READ | VM_WRITE);
+    if code >= 0xFFFFFFFF_00000000 || stack >= 0xFFFFFFFF_00000000 {
+        fail("vm_allocate failed");
+    }
+
+    // The code page is not writable from user space, so fill it through
+    // the physical memory window
+    let phys = match AddressSpace::from_root(root).translate(code) {
+        Some(phys) => phys,
+        None => fail("code page not mapped"),
+    };
+    unsafe {
+        let start = &qemu_test_user_start as *const u8;
+        let len = &qemu_test_user_end as *const u8 as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, (PHYS_OFFSET + phys) as *mut u8, len);
+    }
+
+    // Interrupts stay off in ring 3, so timer ticks cannot preempt the test
+    unsafe {
+        asm!(
+            "push {ss}",
+            "push {rsp}",
+            "push {rflags}",
+            "push {cs}",
+            "push {rip}",
+            "iretq",
+            ss = in(reg) u64::from(USER_DATA_SELECTOR.0),
+            rsp = in(reg) stack + 4096,
+            rflags = in(reg) 0x2u64,
+            cs = in(reg) u64::from(USER_CODE_SELECTOR.0),
+            rip = in(reg) code,
+            options(noreturn),
+        );
+    }
+}
+
+/// Answer the test syscalls; None passes `num` on to handle_syscall
+pub fn syscall(num: u64, args: [u64; 6]) -> Option<u64> {
+    match num {
+        QEMU_TEST_ECHO => Some(if args == [1, 2, 3, 4, 5, 6] { ECHO_OK } else { ECHO_BAD }),
+        QEMU_TEST_EXIT if args[0] == 0 => {
+            crate::serial::write_str("[qemu-test] Syscall round trip passed\n");
+            exit_qemu(EXIT_SUCCESS)
+        }
+        QEMU_TEST_EXIT => fail("user code saw a wrong register after a syscall"),
+        _ => None,
+    }
+}
+
+fn fail(reason: &str) -> ! {
+    crate::serial::write_str("[qemu-test] FAILED: ");
+    crate::serial::write_str(reason);
+    crate::serial::write_str("\n");
+    exit_qemu(EXIT_FAILURE)
+}
+
+fn exit_qemu(code: u32) -> ! {
+    unsafe { Port::new(DEBUG_EXIT_PORT).write(code) };
+
+    // Not running under QEMU with isa-debug-exit
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,102 @@
+// kernel/src/arch/x86_64/syscall.rs
+// SYSCALL/SYSRET entry - how user code reaches syscall::handle_syscall
+//
+// User code puts the syscall number in rax and up to six arguments in rdi,
+// rsi, rdx, r10, r8 and r9 (r10 instead of rcx, which SYSCALL overwrites
+// with the return address). The result comes back in rax; rcx and r11 are
+// clobbered and every other register is preserved.
+
+use core::arch::global_asm;
+use core::ptr::{addr_of, addr_of_mut};
+use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+use crate::vm::USER_SPACE_END;
+use super::gdt::{self, KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+
+global_asm!(include_str!("syscall_entry.S"));
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// User registers saved by syscall_entry, lowest address first
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rflags: u64,  // from r11
+    pub rip: u64,     // from rcx
+    pub rsp: u64,
+}
+
+/// Per-CPU block GS points at while in the kernel
+#[repr(C)]
+struct CpuLocal {
+    kernel_stack: u64,  // gs:0
+    user_stack: u64,    // gs:8, scratch for syscall_entry
+}
+
+static mut CPU_LOCAL: CpuLocal = CpuLocal { kernel_stack: 0, user_stack: 0 };
+
+/// Point SYSCALL at syscall_entry
+///
+/// Must run after gdt::init_gdt, as STAR holds its selectors.
+pub fn init_syscalls() {
+    unsafe {
+        set_kernel_stack(gdt::kernel_stack_top().as_u64());
+        KernelGsBase::write(VirtAddr::new(cpu_local_base()));
+
+        Star::write(USER_CODE_SELECTOR, USER_DATA_SELECTOR, KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR)
+            .expect("GDT order does not suit SYSRET");
+        LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+        // Enter with interrupts off until the stack is switched, and with
+        // the direction flag clear as the Rust ABI expects
+        SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+/// Make `top` the stack syscall_entry switches to
+pub fn set_kernel_stack(top: u64) {
+    unsafe { (*addr_of_mut!(CPU_LOCAL)).kernel_stack = top };
+}
+
+/// Address of the CpuLocal block, the kernel's GS base
+pub fn cpu_local_base() -> u64 {
+    addr_of!(CPU_LOCAL) as u64
+}
+
+/// Called by syscall_entry with the saved user registers
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let num = frame.rax;
+    let args = [frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9];
+
+    #[cfg(feature = "qemu-test")]
+    if let Some(result) = super::qemu_test::syscall(num, args) {
+        frame.rax = result;
+        return;
+    }
+
+    frame.rax = crate::syscall::handle_syscall(num, args);
+
+    // SYSRET to a non-canonical address raises #GP in ring 0 on the user
+    // stack. That needs a syscall instruction at the very top of user
+    // space, whose return address is the first byte past it.
+    if frame.rip >= USER_SPACE_END {
+        crate::task::kill_current();
+    }
+}
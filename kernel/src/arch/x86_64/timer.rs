@@ -0,0 +1,66 @@
+// kernel/src/arch/x86_64/timer.rs
+// Timer interrupt - the 8254 PIT on IRQ 0, through the 8259 PICs
+//
+// The PICs are remapped above the CPU exception vectors (they start out on
+// top of them) and every IRQ but the timer is masked; drivers unmask theirs
+// as they are added.
+
+use spin::Mutex;
+use pic8259::ChainedPics;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::error::TIMER_DEFAULT_HZ;
+
+/// Vectors of IRQ 0-7 and 8-15
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// Vector of the timer interrupt (IRQ 0)
+pub const TIMER_VECTOR: u8 = PIC_1_OFFSET;
+
+/// Input clock of the PIT
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+/// PIT ports
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+/// PIC data ports, which take the interrupt masks
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xA1;
+
+static PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Remap the PICs and start the timer at TIMER_DEFAULT_HZ
+///
+/// The IDT must be loaded first, with timer_interrupt_handler at
+/// TIMER_VECTOR.
+pub fn init_timer() {
+    unsafe {
+        PICS.lock().initialize();
+        Port::<u8>::new(PIC_1_DATA).write(0xFE);  // Only IRQ 0
+        Port::<u8>::new(PIC_2_DATA).write(0xFF);
+    }
+    set_rate(TIMER_DEFAULT_HZ);
+}
+
+/// Tick `hz` times a second (TIMER_MIN_HZ..=TIMER_MAX_HZ)
+pub fn set_rate(hz: u32) {
+    let divisor = (PIT_FREQUENCY / hz.max(1)).clamp(1, 0xFFFF) as u16;
+    unsafe {
+        // Channel 0, low then high byte of the divisor, mode 2 (rate generator)
+        Port::<u8>::new(PIT_COMMAND).write(0x34);
+        let mut channel = Port::<u8>::new(PIT_CHANNEL_0);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    }
+    super::clock::set_tick_divisor(divisor);
+}
+
+pub extern "x86-interrupt" fn timer_interrupt_handler(_frame: InterruptStackFrame) {
+    // Acknowledge first: the tick may switch to another process, and the
+    // PIC holds back further ticks until it is acknowledged
+    unsafe { PICS.lock().notify_end_of_interrupt(TIMER_VECTOR) };
+    super::clock::on_tick();
+    crate::timer::timer_tick();
+}
@@ -0,0 +1,50 @@
+// kernel/src/arch/x86_64/uaccess.rs
+// Fault-tolerant byte copy for kernel accesses to user memory
+
+use core::arch::global_asm;
+
+// user_copy(dst = rdi, src = rsi, len = rdx) -> rax: 0 when every byte was
+// copied, 1 when a fault cut the copy short. The page fault and general
+// protection handlers resume a fault at user_copy_insn at user_copy_fault.
+global_asm!(
+    ".global user_copy",
+    ".global user_copy_insn",
+    ".global user_copy_fault",
+    "user_copy:",
+    "    mov rcx, rdx",
+    "user_copy_insn:",
+    "    rep movsb",
+    "    xor eax, eax",
+    "    ret",
+    "user_copy_fault:",
+    "    mov eax, 1",
+    "    ret",
+);
+
+extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> u64;
+    static user_copy_insn: u8;
+    static user_copy_fault: u8;
+}
+
+/// Copy `len` bytes between kernel and user memory, returning false if an
+/// unmapped or protected page stopped the copy
+///
+/// # Safety
+/// The kernel side of the copy must be valid for `len` bytes.
+pub unsafe fn copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> bool {
+    user_copy(dst, src, len) == 0
+}
+
+/// Address to resume at if a fault at `rip` happened inside copy_user_bytes
+pub fn fault_fixup(rip: u64) -> Option<u64> {
+    let (insn, fixup) = unsafe {
+        (&user_copy_insn as *const u8 as u64, &user_copy_fault as *const u8 as u64)
+    };
+
+    if rip == insn {
+        Some(fixup)
+    } else {
+        None
+    }
+}
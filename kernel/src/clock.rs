@@ -0,0 +1,179 @@
+// kernel/src/clock.rs
+// Clocks - monotonic time since boot and wall-clock time
+//
+// SYS_TIME returns nanoseconds since boot from arch::clock, which counts
+// an invariant TSC calibrated at boot, or timer ticks where the TSC cannot
+// be trusted. SYS_TIME_WALL returns nanoseconds since the Unix epoch: the
+// CMOS RTC is read once at boot and the monotonic clock carries it forward
+// from there, so wall-clock time never goes backwards. Nothing sets the
+// clock; the RTC is taken to be in UTC.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::error::E_INVAL;
+
+pub const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Wall-clock time at monotonic time 0, in ns since the Unix epoch (0 until
+/// the RTC has been read)
+static BOOT_WALL_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Date and time as the RTC holds it, already decoded to binary
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RtcTime {
+    pub year: u32,
+    pub month: u32,  // 1-12
+    pub day: u32,    // 1-31
+    pub hour: u32,   // 0-23
+    pub minute: u32,
+    pub second: u32,
+}
+
+/// RTC status register B bits
+const RTC_24_HOUR: u8 = 1 << 1;
+const RTC_BINARY: u8 = 1 << 2;
+
+/// Bit of the hour register that marks PM in 12-hour mode
+const RTC_PM: u8 = 0x80;
+
+impl RtcTime {
+    /// Decode the RTC registers `[second, minute, hour, day, month, year,
+    /// century]` in the format status register B describes
+    ///
+    /// A century of 0 means the RTC has no century register; years are
+    /// then taken to be 20xx.
+    pub fn decode(raw: [u8; 7], status_b: u8) -> Self {
+        let value = |v: u8| -> u32 {
+            if status_b & RTC_BINARY != 0 {
+                v as u32
+            } else {
+                (v >> 4) as u32 * 10 + (v & 0x0F) as u32
+            }
+        };
+
+        let mut hour = value(raw[2] & !RTC_PM);
+        if status_b & RTC_24_HOUR == 0 {
+            hour %= 12;  // 12 AM is hour 0
+            if raw[2] & RTC_PM != 0 {
+                hour += 12;
+            }
+        }
+
+        let century = if raw[6] == 0 { 20 } else { value(raw[6]) };
+
+        Self {
+            year: century * 100 + value(raw[5]),
+            month: value(raw[4]),
+            day: value(raw[3]),
+            hour,
+            minute: value(raw[1]),
+            second: value(raw[0]),
+        }
+    }
+
+    /// Seconds since the Unix epoch, or None for a date the RTC should not
+    /// hold (before 1970, or fields out of range)
+    pub fn unix_seconds(&self) -> Option<u64> {
+        if self.year < 1970
+            || !(1..=12).contains(&self.month)
+            || !(1..=31).contains(&self.day)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 59
+        {
+            return None;
+        }
+
+        // Days from the civil calendar date, counting years from March so
+        // the leap day comes last
+        let (year, month) = if self.month <= 2 {
+            (self.year as u64 - 1, self.month as u64 + 9)
+        } else {
+            (self.year as u64, self.month as u64 - 3)
+        };
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;  // 719468 = days from 0000-03-01 to 1970-01-01
+
+        Some(days * 86_400 + self.hour as u64 * 3_600 + self.minute as u64 * 60 + self.second as u64)
+    }
+}
+
+/// Convert `ticks` of a counter running at `hz` to nanoseconds
+pub fn ticks_to_ns(ticks: u64, hz: u64) -> u64 {
+    (ticks as u128 * NANOS_PER_SEC as u128 / hz as u128) as u64
+}
+
+/// Read the RTC and seed the wall clock from it
+///
+/// arch::clock must be running. If the RTC cannot be read SYS_TIME_WALL
+/// fails with E_INVAL.
+pub fn init() {
+    match crate::arch::clock::read_rtc().and_then(|t| t.unix_seconds()) {
+        Some(seconds) => {
+            let now = crate::arch::clock::now_ns();
+            BOOT_WALL_NS.store(seconds * NANOS_PER_SEC - now, Ordering::Relaxed);
+        }
+        None => crate::serial::write_str("[clock] RTC unreadable; no wall-clock time\n"),
+    }
+}
+
+/// Nanoseconds since boot
+pub fn clock_monotonic() -> u64 {
+    crate::arch::clock::now_ns()
+}
+
+/// Nanoseconds since the Unix epoch (UTC)
+pub fn clock_wall() -> u64 {
+    match BOOT_WALL_NS.load(Ordering::Relaxed) {
+        0 => E_INVAL,
+        boot => boot + clock_monotonic(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtc_decode() {
+        // 2024-02-29 23:59:58 in BCD, 24-hour mode
+        let bcd = RtcTime::decode([0x58, 0x59, 0x23, 0x29, 0x02, 0x24, 0x20], RTC_24_HOUR);
+        let binary = RtcTime::decode([58, 59, 23, 29, 2, 24, 20], RTC_24_HOUR | RTC_BINARY);
+        let expected = RtcTime { year: 2024, month: 2, day: 29, hour: 23, minute: 59, second: 58 };
+        assert_eq!(bcd, expected);
+        assert_eq!(binary, expected);
+
+        // 12-hour mode: 12 AM is hour 0 and 11 PM hour 23; no century register
+        assert_eq!(RtcTime::decode([0, 0, 0x12, 1, 1, 0x25, 0], 0).hour, 0);
+        assert_eq!(RtcTime::decode([0, 0, 0x12 | RTC_PM, 1, 1, 0x25, 0], 0).hour, 12);
+        assert_eq!(RtcTime::decode([0, 0, 0x11 | RTC_PM, 1, 1, 0x25, 0], 0), RtcTime {
+            year: 2025, month: 1, day: 1, hour: 23, minute: 0, second: 0,
+        });
+    }
+
+    #[test]
+    fn test_unix_seconds() {
+        let at = |year, month, day, hour, minute, second| {
+            RtcTime { year, month, day, hour, minute, second }.unix_seconds()
+        };
+
+        assert_eq!(at(1970, 1, 1, 0, 0, 0), Some(0));
+        assert_eq!(at(2000, 3, 1, 0, 0, 0), Some(951_868_800));
+        assert_eq!(at(2024, 2, 29, 23, 59, 58), Some(1_709_251_198));
+        assert_eq!(at(2038, 1, 19, 3, 14, 8), Some(1 << 31));
+
+        assert_eq!(at(1969, 12, 31, 23, 59, 59), None);
+        assert_eq!(at(2024, 13, 1, 0, 0, 0), None);
+        assert_eq!(at(2024, 1, 1, 24, 0, 0), None);
+    }
+
+    #[test]
+    fn test_ticks_to_ns() {
+        assert_eq!(ticks_to_ns(3_000_000_000, 3_000_000_000), NANOS_PER_SEC);
+        assert_eq!(ticks_to_ns(1_193_182, 1_193_182), NANOS_PER_SEC);
+        // A year of a 5 GHz counter does not overflow
+        assert_eq!(ticks_to_ns(5_000_000_000 * 31_536_000, 5_000_000_000), 31_536_000 * NANOS_PER_SEC);
+    }
+}
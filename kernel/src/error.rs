@@ -2,6 +2,7 @@
 // GBSD Error Code Definitions
 
 /// System error codes (u64 format: 0xFFFFFFFF_XXXXXXXX)
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u64)]
 pub enum SystemError {
     /// Operation completed successfully
@@ -36,6 +37,15 @@ pub enum SystemError {
 
     /// Invalid system call
     InvalidSyscall = 0xFFFFFFFF_0000000A,
+
+    /// Operation would block (non-blocking call on an empty port)
+    WouldBlock = 0xFFFFFFFF_0000000B,
+
+    /// Port has been destroyed
+    PortDead = 0xFFFFFFFF_0000000C,
+
+    /// Bad user pointer (outside the process's memory or unmapped)
+    Fault = 0xFFFFFFFF_0000000D,
 }
 
 impl SystemError {
@@ -56,6 +66,9 @@ pub const E_PROCESS_NOT_FOUND: u64 = 0xFFFFFFFF_00000007;
 pub const E_NOT_OWNER: u64 = 0xFFFFFFFF_00000008;
 pub const E_ALIGN: u64 = 0xFFFFFFFF_00000009;
 pub const E_INVALID_SYSCALL: u64 = 0xFFFFFFFF_0000000A;
+pub const E_WOULD_BLOCK: u64 = 0xFFFFFFFF_0000000B;
+pub const E_PORT_DEAD: u64 = 0xFFFFFFFF_0000000C;
+pub const E_FAULT: u64 = 0xFFFFFFFF_0000000D;
 
 /// Syscall numbers
 pub const SYS_PORT_ALLOCATE: u64 = 1;
@@ -68,6 +81,53 @@ pub const SYS_SCHED_SPAWN: u64 = 7;
 pub const SYS_SCHED_YIELD: u64 = 8;
 pub const SYS_SCHED_SWITCH: u64 = 9;
 pub const SYS_TIME: u64 = 10;
+pub const SYS_PORT_CALL: u64 = 11;
+pub const SYS_PORT_REPLY: u64 = 12;
+pub const SYS_PORT_DESTROY: u64 = 13;
+pub const SYS_PORT_WATCH: u64 = 14;
+pub const SYS_PORT_SET_ALLOCATE: u64 = 15;
+pub const SYS_PORT_SET_ADD: u64 = 16;
+pub const SYS_PORT_SET_REMOVE: u64 = 17;
+pub const SYS_NOTIFY_ALLOCATE: u64 = 18;
+pub const SYS_NOTIFY_SIGNAL: u64 = 19;
+pub const SYS_MEM_STATS: u64 = 20;
+pub const SYS_MEMOBJ_ALLOCATE: u64 = 21;
+pub const SYS_MEMOBJ_MAP: u64 = 22;
+pub const SYS_CAP_REVOKE: u64 = 23;
+pub const SYS_VM_SET_PAGER: u64 = 24;
+pub const SYS_SET_EXCEPTION_PORT: u64 = 25;
+pub const SYS_TIMER_SET: u64 = 26;
+pub const SYS_TIME_WALL: u64 = 27;
+pub const SYS_SCHED_SET_PORT: u64 = 28;
+
+/// IPC flags
+pub const IPC_NONBLOCK: u64 = 1 << 0;  // Return E_WOULD_BLOCK instead of sleeping
+pub const IPC_CAP_MOVE: u64 = 1 << 1;  // Transfer the sender's capability instead of copying it
+
+/// Kernel-generated messages (delivered with sender_pid = 0)
+pub const MSG_PORT_DIED: u64 = 0xFFFF_0001;  // [MSG_PORT_DIED, cookie] to port_watch subscribers
+pub const MSG_PAGE_FAULT: u64 = 0xFFFF_0002;  // [MSG_PAGE_FAULT, pid, addr, access, rip] to pagers, as a call
+pub const MSG_EXCEPTION: u64 = 0xFFFF_0003;   // [MSG_EXCEPTION, pid, vector, code, rip, rsp, addr] to exception ports, as a call
+pub const MSG_TASK_YIELD: u64 = 0xFFFF_0004;  // [MSG_TASK_YIELD, pid] to the scheduler port when pid yields
+
+/// Page fault access bits (MSG_PAGE_FAULT)
+pub const FAULT_WRITE: u64 = 1 << 0;       // Write access (otherwise a read)
+pub const FAULT_EXEC: u64 = 1 << 1;        // Instruction fetch
+pub const FAULT_PROTECTION: u64 = 1 << 2;  // Page mapped, but the access is not allowed
+
+/// Pager replies to MSG_PAGE_FAULT
+pub const PAGER_MAP: u64 = 1;   // [PAGER_MAP, offset, flags] + memory object: map its page at offset
+pub const PAGER_KILL: u64 = 2;  // [PAGER_KILL]: kill the faulting process
+
+/// Exception port replies to MSG_EXCEPTION
+pub const EXCEPTION_RESUME: u64 = 1;  // [EXCEPTION_RESUME, rip, rsp]: continue there (0 = unchanged)
+pub const EXCEPTION_KILL: u64 = 2;    // [EXCEPTION_KILL]: kill the faulting process
+
+/// Timer ticks (SYS_TIMER_SET)
+pub const NOTIFY_TIMER_TICK: u64 = 1 << 0;  // Signalled on the registered notification every tick
+pub const TIMER_DEFAULT_HZ: u32 = 100;
+pub const TIMER_MIN_HZ: u32 = 19;  // Slowest rate the PIT can count
+pub const TIMER_MAX_HZ: u32 = 1000;
 
 /// Capability rights bits
 pub const CAP_SEND: u32 = 1 << 0;
@@ -78,3 +138,9 @@ pub const CAP_READ: u32 = 1 << 4;
 pub const CAP_WRITE: u32 = 1 << 5;
 pub const CAP_EXECUTE: u32 = 1 << 6;
 
+/// VM protection flags (SYS_VM_ALLOCATE); pages are always readable, and
+/// VM_WRITE | VM_EXEC together is refused (W^X)
+pub const VM_READ: u32 = 1 << 0;
+pub const VM_WRITE: u32 = 1 << 1;
+pub const VM_EXEC: u32 = 1 << 2;
+
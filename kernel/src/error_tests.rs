@@ -20,6 +20,9 @@ mod tests {
             E_NOT_OWNER,
             E_ALIGN,
             E_INVALID_SYSCALL,
+            E_WOULD_BLOCK,
+            E_PORT_DEAD,
+            E_FAULT,
         ];
 
         // Check no duplicates
@@ -50,6 +53,9 @@ mod tests {
             E_NOT_OWNER,
             E_ALIGN,
             E_INVALID_SYSCALL,
+            E_WOULD_BLOCK,
+            E_PORT_DEAD,
+            E_FAULT,
         ];
 
         for error in errors.iter() {
@@ -71,6 +77,9 @@ mod tests {
             E_NOT_OWNER,
             E_ALIGN,
             E_INVALID_SYSCALL,
+            E_WOULD_BLOCK,
+            E_PORT_DEAD,
+            E_FAULT,
         ];
 
         for error in errors.iter() {
@@ -96,6 +105,24 @@ mod tests {
         assert_eq!(SYS_SCHED_YIELD, 8);
         assert_eq!(SYS_SCHED_SWITCH, 9);
         assert_eq!(SYS_TIME, 10);
+        assert_eq!(SYS_PORT_CALL, 11);
+        assert_eq!(SYS_PORT_REPLY, 12);
+        assert_eq!(SYS_PORT_DESTROY, 13);
+        assert_eq!(SYS_PORT_WATCH, 14);
+        assert_eq!(SYS_PORT_SET_ALLOCATE, 15);
+        assert_eq!(SYS_PORT_SET_ADD, 16);
+        assert_eq!(SYS_PORT_SET_REMOVE, 17);
+        assert_eq!(SYS_NOTIFY_ALLOCATE, 18);
+        assert_eq!(SYS_NOTIFY_SIGNAL, 19);
+        assert_eq!(SYS_MEM_STATS, 20);
+        assert_eq!(SYS_MEMOBJ_ALLOCATE, 21);
+        assert_eq!(SYS_MEMOBJ_MAP, 22);
+        assert_eq!(SYS_CAP_REVOKE, 23);
+        assert_eq!(SYS_VM_SET_PAGER, 24);
+        assert_eq!(SYS_SET_EXCEPTION_PORT, 25);
+        assert_eq!(SYS_TIMER_SET, 26);
+        assert_eq!(SYS_TIME_WALL, 27);
+        assert_eq!(SYS_SCHED_SET_PORT, 28);
     }
 
     #[test]
@@ -0,0 +1,370 @@
+// kernel/src/fault.rs
+// User faults - page faults and exceptions forwarded to userspace
+//
+// A fault in user mode is not the kernel's to fix, and must never take the
+// machine down. The faulting process is put to sleep and the kernel sends a
+// message about the fault, as a call on the process's behalf, to a port a
+// userspace server registered. The server answers with port_reply and the
+// kernel carries out its answer.
+//
+// Page faults go to the process's pager (registered with vm_set_pager,
+// inherited across spawn) as [MSG_PAGE_FAULT, pid, addr, access, rip]:
+//
+//   [PAGER_MAP, offset, flags] + memory object capability
+//       map the object's page at `offset` over the faulting page with
+//       VM_* `flags`, then retry the faulting instruction
+//   [PAGER_KILL]
+//       kill the process
+//
+// The capability is installed in the faulting process's CSpace, so the
+// pager can take the page back by revoking its parent.
+//
+// Other exceptions, and page faults of a process without a pager (or whose
+// pager's port was destroyed), go to its exception port
+// (set_exception_port), or else to init_server's, as
+// [MSG_EXCEPTION, pid, vector, code, rip, rsp, addr]:
+//
+//   [EXCEPTION_RESUME, rip, rsp]
+//       continue at `rip` with stack `rsp` (0 keeps either), e.g. to retry
+//       the instruction or restart the process at its entry point
+//   [EXCEPTION_KILL]
+//       kill the process
+//
+// Any other answer, or no one to ask, kills the process. So does a fault
+// whose handler port the process owns, as it could never answer. Faults
+// taken while the kernel copies to or from user memory are not forwarded;
+// the syscall fails with E_FAULT instead.
+
+use crate::error::*;
+use crate::frame::FRAME_SIZE;
+use crate::globals::*;
+use crate::ipc::{kernel_call, take_kernel_reply};
+use crate::task::{self, INIT_PID};
+use crate::vm::{map_fault_page, USER_SPACE_END, USER_SPACE_START};
+
+/// Longest reply a fault handler may send, in u64 words
+const FAULT_REPLY_LEN: usize = PORT_DEFAULT_MSG_LEN as usize;
+
+/// CPU exception vector of page faults, as reported in MSG_EXCEPTION
+pub const PAGE_FAULT_VECTOR: u64 = 14;
+
+/// What becomes of a process after a fault
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultAction {
+    Retry,  // Run the faulting instruction again
+    Resume { rip: u64, rsp: u64 },
+    Kill,
+}
+
+/// Make the port in `port_slot` the current process's pager (0 = none)
+///
+/// Needs `CAP_SEND`; faults are sent with the capability's badge. A pager
+/// whose port is later destroyed counts as none.
+pub fn vm_set_pager(port_slot: u32) -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    let pager = match fault_handler(&state, current_pid, port_slot) {
+        Ok(pager) => pager,
+        Err(e) => return e,
+    };
+
+    match state.process_mut(current_pid) {
+        Some(p) => {
+            p.pager = pager;
+            E_OK
+        }
+        None => E_PROCESS_NOT_FOUND,
+    }
+}
+
+/// Make the port in `port_slot` the current process's exception port
+/// (0 = none, use init_server's)
+///
+/// Needs `CAP_SEND`; exceptions are sent with the capability's badge.
+/// init_server's exception port is the default for every process.
+pub fn set_exception_port(port_slot: u32) -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    let handler = match fault_handler(&state, current_pid, port_slot) {
+        Ok(handler) => handler,
+        Err(e) => return e,
+    };
+
+    match state.process_mut(current_pid) {
+        Some(p) => {
+            p.exception_handler = handler;
+            E_OK
+        }
+        None => E_PROCESS_NOT_FOUND,
+    }
+}
+
+/// Resolve the port a process registers to handle its faults
+fn fault_handler(state: &KernelState, pid: u32, port_slot: u32) -> Result<Option<FaultHandler>, u64> {
+    if port_slot == 0 {
+        return Ok(None);
+    }
+
+    let cap = match state.lookup_cap(pid, port_slot) {
+        Some(c) if !c.revoked && c.kind == CapabilityKind::Port => c,
+        _ => return Err(E_CAP_INVALID),
+    };
+
+    if !cap.has_right(CAP_SEND) {
+        return Err(E_NO_RIGHTS);
+    }
+
+    Ok(Some(FaultHandler { port_id: cap.target_id, badge: cap.badge }))
+}
+
+/// Handle a page fault the current process took at `addr`
+///
+/// `access` holds FAULT_* bits, `code` is the CPU's error code and `rip`
+/// and `rsp` are the faulting instruction and stack. Goes to the pager,
+/// or failing that (none, or its port destroyed) to the exception port;
+/// sleeps until answered.
+pub fn user_page_fault(addr: u64, access: u64, code: u64, rip: u64, rsp: u64) -> FaultAction {
+    let pid = current_pid();
+
+    let has_pager = kernel_state_mut().processes.iter().any(|p| p.id == pid && p.pager.is_some());
+    if !has_pager {
+        return user_exception(PAGE_FAULT_VECTOR, code, rip, rsp, addr);
+    }
+
+    match send_page_fault(&mut kernel_state_mut(), pid, addr, access, rip) {
+        E_OK => {}
+        E_PORT_DEAD => return user_exception(PAGE_FAULT_VECTOR, code, rip, rsp, addr),
+        _ => return FaultAction::Kill,
+    }
+
+    task::block_current();
+
+    if resolve_page_fault(&mut kernel_state_mut(), pid, addr) {
+        FaultAction::Retry
+    } else {
+        FaultAction::Kill
+    }
+}
+
+/// Handle CPU exception `vector` raised by the current process
+///
+/// `code` is the exception's error code (0 if it has none), `rip` and
+/// `rsp` are the faulting instruction and stack, and `addr` the faulting
+/// address of a page fault (0 otherwise). Sleeps until the exception port
+/// answers.
+pub fn user_exception(vector: u64, code: u64, rip: u64, rsp: u64, addr: u64) -> FaultAction {
+    let pid = current_pid();
+
+    if send_exception(&mut kernel_state_mut(), pid, [vector, code, rip, rsp, addr]) != E_OK {
+        return FaultAction::Kill;
+    }
+
+    task::block_current();
+
+    resolve_exception(&mut kernel_state_mut(), pid)
+}
+
+/// Send `[MSG_PAGE_FAULT, pid, addr, access, rip]` to the pager of `pid`
+///
+/// A pager whose port is gone is cleared, and `E_PORT_DEAD` returned.
+fn send_page_fault(state: &mut KernelState, pid: u32, addr: u64, access: u64, rip: u64) -> u64 {
+    let pager = state.processes.iter().find(|p| p.id == pid).and_then(|p| p.pager);
+    let result = send_fault(state, pid, pager, &[MSG_PAGE_FAULT, pid as u64, addr, access, rip]);
+    if result == E_PORT_DEAD {
+        if let Some(p) = state.process_mut(pid) {
+            p.pager = None;
+        }
+    }
+    result
+}
+
+/// Send `[MSG_EXCEPTION, pid, vector, code, rip, rsp, addr]` to the
+/// exception port of `pid`, or else of init_server
+fn send_exception(state: &mut KernelState, pid: u32, exception: [u64; 5]) -> u64 {
+    let handler_of = |pid| state.processes.iter().find(|p| p.id == pid).and_then(|p| p.exception_handler);
+    let handler = handler_of(pid).or_else(|| handler_of(INIT_PID));
+
+    let [vector, code, rip, rsp, addr] = exception;
+    send_fault(state, pid, handler, &[MSG_EXCEPTION, pid as u64, vector, code, rip, rsp, addr])
+}
+
+/// Call `handler` about a fault of `pid`, putting `pid` to sleep
+fn send_fault(state: &mut KernelState, pid: u32, handler: Option<FaultHandler>, msg: &[u64]) -> u64 {
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return E_PORT_INVALID,
+    };
+
+    // A process cannot answer for its own faults
+    match state.ports.iter().find(|p| p.id == handler.port_id) {
+        Some(port) if port.owner_pid == pid => return E_INVAL,
+        Some(_) => {}
+        None => return E_PORT_DEAD,
+    }
+
+    kernel_call(state, handler.port_id, handler.badge, pid, msg, FAULT_REPLY_LEN)
+}
+
+/// Carry out the pager's answer to a fault of `pid` at `addr`
+///
+/// Returns true if the faulting page was mapped.
+fn resolve_page_fault(state: &mut KernelState, pid: u32, addr: u64) -> bool {
+    let (reply, slot) = match take_kernel_reply(state, pid) {
+        Ok(reply) => reply,
+        Err(_) => return false,
+    };
+
+    let mapped = match reply.as_slice() {
+        [PAGER_MAP, offset, flags, ..] if slot != 0 => {
+            let page = addr & !(FRAME_SIZE - 1);
+            map_fault_page(state, pid, slot as u32, page, *offset, *flags as u32).is_ok()
+        }
+        _ => false,
+    };
+
+    // A capability that mapped nothing would only fill the CSpace
+    if !mapped && slot != 0 {
+        if let Some(p) = state.process_mut(pid) {
+            p.cspace.remove(slot as u32);
+        }
+    }
+    mapped
+}
+
+/// Turn the exception port's answer for `pid` into an action
+fn resolve_exception(state: &mut KernelState, pid: u32) -> FaultAction {
+    let reply = match take_kernel_reply(state, pid) {
+        Ok((reply, _)) => reply,
+        Err(_) => return FaultAction::Kill,
+    };
+
+    let user = USER_SPACE_START..USER_SPACE_END;
+    match reply.as_slice() {
+        [EXCEPTION_RESUME, 0, 0, ..] => FaultAction::Retry,
+        [EXCEPTION_RESUME, rip, rsp, ..]
+            if (*rip == 0 || user.contains(rip)) && (*rsp == 0 || user.contains(rsp)) =>
+        {
+            FaultAction::Resume { rip: *rip, rsp: *rsp }
+        }
+        _ => FaultAction::Kill,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// A faulting process 2 whose pager port (ID 1) belongs to process 1,
+    /// which as init_server also handles everyone's exceptions there
+    fn with_handlers() -> KernelState {
+        let mut state = KernelState::new();
+        state.processes.push(ProcessDescriptor::new(INIT_PID, 0, 0));
+        state.processes.push(ProcessDescriptor::new(2, 0, 0));
+        state.ports.push(Port::new(1, INIT_PID));
+        state.processes[0].exception_handler = Some(FaultHandler { port_id: 1, badge: 0 });
+        state.processes[1].pager = Some(FaultHandler { port_id: 1, badge: 0x42 });
+        state
+    }
+
+    #[test]
+    fn test_page_fault_is_sent_to_pager() {
+        let mut state = with_handlers();
+        let addr = 0x1_0000_2345;
+
+        assert_eq!(send_page_fault(&mut state, 2, addr, FAULT_WRITE, 0x1_0000_0100), E_OK);
+        assert_eq!(state.processes[1].state, ProcessState::Sleeping);
+
+        let (msg, meta) = state.ports[0].pop_message_with_meta().unwrap();
+        assert_eq!(msg, vec![MSG_PAGE_FAULT, 2, addr, FAULT_WRITE, 0x1_0000_0100]);
+        assert_eq!(meta.sender_pid, 0, "Sent by the kernel");
+        assert_eq!(meta.caller_pid, 2, "Answered through a reply capability");
+        assert_eq!(meta.badge, 0x42);
+    }
+
+    #[test]
+    fn test_dead_pager_counts_as_none() {
+        let mut state = with_handlers();
+        state.ports.push(Port::new(2, INIT_PID));
+        state.processes[1].pager = Some(FaultHandler { port_id: 2, badge: 0 });
+        state.ports.retain(|p| p.id != 2);
+
+        // The pager is forgotten, and the fault goes to the exception port
+        assert_eq!(send_page_fault(&mut state, 2, 0x1_0000_0000, 0, 0), E_PORT_DEAD);
+        assert!(state.processes[1].pager.is_none());
+        assert_eq!(send_exception(&mut state, 2, [PAGE_FAULT_VECTOR, 0, 0, 0, 0x1_0000_0000]), E_OK);
+        assert_eq!(state.ports[0].pop_message().unwrap()[..3], [MSG_EXCEPTION, 2, PAGE_FAULT_VECTOR]);
+    }
+
+    #[test]
+    fn test_unresolved_page_faults_kill() {
+        // No pager
+        let mut state = with_handlers();
+        state.processes[1].pager = None;
+        assert_ne!(send_page_fault(&mut state, 2, 0x1_0000_0000, 0, 0), E_OK);
+
+        // Pager says so, or maps without a memory object
+        for reply in [vec![PAGER_KILL], vec![PAGER_MAP, 0, 0]] {
+            let mut state = with_handlers();
+            state.processes[1].reply_msg = Some((reply, MessageMeta::default()));
+            assert!(!resolve_page_fault(&mut state, 2, 0x1_0000_0000));
+        }
+
+        // A capability that came with a refusal is not kept
+        let mut state = with_handlers();
+        let mut object = Capability::new(50, INIT_PID, 7, CAP_READ);
+        object.kind = CapabilityKind::MemoryObject;
+        state.in_transit.push(object);
+        let meta = MessageMeta { cap_id: 50, ..MessageMeta::default() };
+        state.processes[1].reply_msg = Some((vec![PAGER_KILL], meta));
+        assert!(!resolve_page_fault(&mut state, 2, 0x1_0000_0000));
+        assert!(state.processes[1].cspace.slots.iter().all(|s| s.is_none()));
+
+        // Call failed unanswered
+        let mut state = with_handlers();
+        state.processes[1].call_error = E_PORT_DEAD;
+        assert!(!resolve_page_fault(&mut state, 2, 0x1_0000_0000));
+    }
+
+    #[test]
+    fn test_exceptions_default_to_init() {
+        let mut state = with_handlers();
+        let exception = [6, 0, 0x1_0000_0100, 0x1_0000_8000, 0];
+
+        assert_eq!(send_exception(&mut state, 2, exception), E_OK);
+        assert_eq!(state.processes[1].state, ProcessState::Sleeping);
+        let (msg, meta) = state.ports[0].pop_message_with_meta().unwrap();
+        assert_eq!(msg, vec![MSG_EXCEPTION, 2, 6, 0, 0x1_0000_0100, 0x1_0000_8000, 0]);
+        assert_eq!((meta.sender_pid, meta.caller_pid), (0, 2));
+
+        // A process's own port takes precedence
+        state.ports.push(Port::new(2, INIT_PID));
+        state.processes[1].exception_handler = Some(FaultHandler { port_id: 2, badge: 7 });
+        assert_eq!(send_exception(&mut state, 2, exception), E_OK);
+        assert_eq!(state.ports[1].pop_message_with_meta().unwrap().1.badge, 7);
+
+        // init_server cannot handle its own exceptions
+        assert_eq!(send_exception(&mut state, INIT_PID, exception), E_INVAL);
+    }
+
+    #[test]
+    fn test_exception_replies() {
+        let resolve = |reply: Vec<u64>| {
+            let mut state = with_handlers();
+            state.processes[1].reply_msg = Some((reply, MessageMeta::default()));
+            resolve_exception(&mut state, 2)
+        };
+
+        assert_eq!(resolve(vec![EXCEPTION_RESUME, 0, 0]), FaultAction::Retry);
+        assert_eq!(
+            resolve(vec![EXCEPTION_RESUME, USER_SPACE_START, 0]),
+            FaultAction::Resume { rip: USER_SPACE_START, rsp: 0 }
+        );
+        assert_eq!(resolve(vec![EXCEPTION_RESUME, 0x1000, 0]), FaultAction::Kill, "Kernel address");
+        assert_eq!(resolve(vec![EXCEPTION_KILL]), FaultAction::Kill);
+        assert_eq!(resolve(vec![EXCEPTION_RESUME]), FaultAction::Kill, "Malformed");
+    }
+}
@@ -0,0 +1,269 @@
+// kernel/src/frame.rs
+// Physical frame allocator - bitmap over the bootloader memory map
+//
+// One bit per 4 KB frame of physical memory: set while the frame is in use
+// or not usable RAM, clear while it is free. Only frames the memory map
+// reports as available are ever cleared, so holes and device memory can
+// never be handed out. A second bitmap marks the frames allocate handed
+// out, the only ones free takes back, so freeing a reserved or unusable
+// frame cannot put it in circulation either.
+
+use spin::Mutex;
+
+/// Size of a physical frame (and of a page)
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Physical memory the allocator can track; RAM above this is ignored
+pub const MAX_PHYS_MEMORY: u64 = 0x1_0000_0000;  // 4 GB
+
+const MAX_FRAMES: usize = (MAX_PHYS_MEMORY / FRAME_SIZE) as usize;
+const BITMAP_WORDS: usize = MAX_FRAMES / 64;
+
+/// Frame usage counters, in frames
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    pub total: usize,      // Usable RAM reported by the memory map
+    pub reserved: usize,   // Usable but taken at boot (kernel, modules, boot info)
+    pub allocated: usize,  // Handed out by allocate
+    pub free: usize,
+}
+
+pub struct FrameAllocator {
+    bitmap: [u64; BITMAP_WORDS],     // 1 = in use or unusable
+    allocated: [u64; BITMAP_WORDS],  // 1 = handed out by allocate
+    stats: FrameStats,
+    next: usize,  // Frame to start the next search at
+}
+
+impl FrameAllocator {
+    /// An allocator with no usable memory; feed it with add_region
+    pub const fn new() -> Self {
+        Self {
+            bitmap: [u64::MAX; BITMAP_WORDS],
+            allocated: [0; BITMAP_WORDS],
+            stats: FrameStats { total: 0, reserved: 0, allocated: 0, free: 0 },
+            next: 0,
+        }
+    }
+
+    /// Mark the RAM in `[start, end)` usable
+    ///
+    /// Only whole frames inside the range count; partial frames at either
+    /// end are left out.
+    pub fn add_region(&mut self, start: u64, end: u64) {
+        let first = frame_up(start);
+        let last = frame_down(end).min(MAX_FRAMES);
+
+        for frame in first..last {
+            if self.is_used(frame) {
+                self.set_free(frame);
+                self.stats.total += 1;
+                self.stats.free += 1;
+            }
+        }
+    }
+
+    /// Take the frames overlapping `[start, end)` out of circulation
+    ///
+    /// Used for memory already occupied at boot. Frames that are not usable
+    /// RAM or already taken are skipped.
+    pub fn reserve(&mut self, start: u64, end: u64) {
+        let first = frame_down(start);
+        let last = frame_up(end).min(MAX_FRAMES);
+
+        for frame in first..last {
+            if !self.is_used(frame) {
+                self.set_used(frame);
+                self.stats.reserved += 1;
+                self.stats.free -= 1;
+            }
+        }
+    }
+
+    /// Allocate one frame, returning its physical address
+    pub fn allocate(&mut self) -> Option<u64> {
+        let frame = self.find_free(self.next).or_else(|| self.find_free(0))?;
+
+        self.set_used(frame);
+        set_bit(&mut self.allocated, frame, true);
+        self.stats.allocated += 1;
+        self.stats.free -= 1;
+        self.next = frame + 1;
+
+        Some(frame as u64 * FRAME_SIZE)
+    }
+
+    /// Return a frame from allocate
+    ///
+    /// Returns false (and changes nothing) for an unaligned address or a
+    /// frame allocate did not hand out: one already free, reserved or
+    /// outside usable RAM.
+    pub fn free(&mut self, addr: u64) -> bool {
+        if addr % FRAME_SIZE != 0 || addr >= MAX_PHYS_MEMORY {
+            return false;
+        }
+
+        let frame = (addr / FRAME_SIZE) as usize;
+        if !bit(&self.allocated, frame) {
+            return false;
+        }
+
+        self.set_free(frame);
+        set_bit(&mut self.allocated, frame, false);
+        self.stats.allocated -= 1;
+        self.stats.free += 1;
+        if frame < self.next {
+            self.next = frame;
+        }
+        true
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// First free frame at or after `from`
+    fn find_free(&self, from: usize) -> Option<usize> {
+        let mut word = from / 64;
+        let mut mask = !0u64 << (from % 64);
+
+        while word < BITMAP_WORDS {
+            let free = !self.bitmap[word] & mask;
+            if free != 0 {
+                return Some(word * 64 + free.trailing_zeros() as usize);
+            }
+            word += 1;
+            mask = !0;
+        }
+        None
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        bit(&self.bitmap, frame)
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        set_bit(&mut self.bitmap, frame, true);
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        set_bit(&mut self.bitmap, frame, false);
+    }
+}
+
+impl Default for FrameAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bit(bitmap: &[u64], frame: usize) -> bool {
+    bitmap[frame / 64] & (1 << (frame % 64)) != 0
+}
+
+fn set_bit(bitmap: &mut [u64], frame: usize, value: bool) {
+    if value {
+        bitmap[frame / 64] |= 1 << (frame % 64);
+    } else {
+        bitmap[frame / 64] &= !(1 << (frame % 64));
+    }
+}
+
+/// First frame starting at or after `addr`
+fn frame_up(addr: u64) -> usize {
+    addr.div_ceil(FRAME_SIZE).min(MAX_FRAMES as u64) as usize
+}
+
+/// Frame containing `addr`
+fn frame_down(addr: u64) -> usize {
+    (addr / FRAME_SIZE).min(MAX_FRAMES as u64) as usize
+}
+
+/// The system frame allocator, seeded by memory::init
+pub static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::new());
+
+/// Allocate a physical frame from the system allocator
+pub fn allocate_frame() -> Option<u64> {
+    FRAME_ALLOCATOR.lock().allocate()
+}
+
+/// Return a frame to the system allocator
+pub fn free_frame(addr: u64) -> bool {
+    FRAME_ALLOCATOR.lock().free(addr)
+}
+
+/// Current usage of physical memory
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.lock().stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    fn allocator() -> Box<FrameAllocator> {
+        Box::new(FrameAllocator::new())
+    }
+
+    #[test]
+    fn test_only_available_frames_are_allocated() {
+        let mut fa = allocator();
+        assert_eq!(fa.allocate(), None, "No memory map, no frames");
+
+        // Partial frames at the edges are not usable
+        fa.add_region(0x1800, 0x4800);
+        assert_eq!(fa.stats().total, 2);
+        assert_eq!(fa.allocate(), Some(0x2000));
+        assert_eq!(fa.allocate(), Some(0x3000));
+        assert_eq!(fa.allocate(), None);
+
+        let stats = fa.stats();
+        assert_eq!((stats.allocated, stats.free), (2, 0));
+    }
+
+    #[test]
+    fn test_reserve_and_free() {
+        let mut fa = allocator();
+        fa.add_region(0x10_0000, 0x20_0000);  // 256 frames
+
+        // A kernel image ending mid-frame keeps that whole frame
+        fa.reserve(0x10_0000, 0x10_2001);
+        let stats = fa.stats();
+        assert_eq!((stats.total, stats.reserved, stats.free), (256, 3, 253));
+        assert_eq!(fa.allocate(), Some(0x10_3000));
+
+        assert!(fa.free(0x10_3000));
+        assert!(!fa.free(0x10_3000), "Double free is refused");
+        assert!(!fa.free(0x10_3001), "Unaligned");
+        assert!(!fa.free(0x30_0000), "Never usable");
+        assert_eq!(fa.allocate(), Some(0x10_3000), "Freed frames are reused");
+    }
+
+    #[test]
+    fn test_free_refuses_frames_not_allocated() {
+        let mut fa = allocator();
+        fa.add_region(0x10_0000, 0x10_4000);
+        fa.reserve(0x10_0000, 0x10_1000);
+        assert_eq!(fa.allocate(), Some(0x10_1000));
+
+        // With a frame out, reserved and unusable frames still stay put
+        assert!(!fa.free(0x10_0000), "Reserved");
+        assert!(!fa.free(0x30_0000), "Never usable");
+        assert!(!fa.free(0x10_2000), "Free");
+
+        let stats = fa.stats();
+        assert_eq!((stats.reserved, stats.allocated, stats.free), (1, 1, 2));
+        assert_eq!(fa.allocate(), Some(0x10_2000));
+        assert_eq!(fa.allocate(), Some(0x10_3000));
+        assert_eq!(fa.allocate(), None, "The reserved frame is never handed out");
+    }
+
+    #[test]
+    fn test_memory_above_limit_is_ignored() {
+        let mut fa = allocator();
+        fa.add_region(MAX_PHYS_MEMORY - 2 * FRAME_SIZE, MAX_PHYS_MEMORY + 0x10_0000);
+        assert_eq!(fa.stats().total, 2);
+        assert_eq!(fa.allocate(), Some(MAX_PHYS_MEMORY - 2 * FRAME_SIZE));
+    }
+}
@@ -4,12 +4,21 @@
 use spin::Mutex;
 use alloc::vec::Vec;
 use core::cell::UnsafeCell;
+use crate::error::{CAP_SEND, E_NOMEM, E_OK};
+use crate::task::{CpuContext, KernelStack};
+use crate::vm::{USER_SPACE_END, USER_SPACE_START};
 
 /// Kernel state - shared between all CPU cores
 pub struct KernelState {
     pub processes: Vec<ProcessDescriptor>,
     pub ports: Vec<Port>,
-    pub capabilities: Vec<Capability>,
+    pub port_sets: Vec<PortSet>,
+    pub notifications: Vec<Notification>,
+    pub memory_objects: Vec<MemoryObject>,
+    pub in_transit: Vec<Capability>,  // Capabilities carried by queued messages
+    pub ool_in_transit: Vec<OolPayload>,  // Out-of-line data carried by queued messages
+    pub timer: Option<TimerTarget>,  // Notification signalled on every timer tick
+    pub scheduler: Option<SchedulerPort>,  // Port told about yields
     pub current_process_id: u32,
 }
 
@@ -18,14 +27,73 @@ impl KernelState {
         Self {
             processes: Vec::new(),
             ports: Vec::new(),
-            capabilities: Vec::new(),
+            port_sets: Vec::new(),
+            notifications: Vec::new(),
+            memory_objects: Vec::new(),
+            in_transit: Vec::new(),
+            ool_in_transit: Vec::new(),
+            timer: None,
+            scheduler: None,
             current_process_id: 0,
         }
     }
+
+    /// Find a process by PID
+    pub fn process_mut(&mut self, pid: u32) -> Option<&mut ProcessDescriptor> {
+        self.processes.iter_mut().find(|p| p.id == pid)
+    }
+
+    /// Look up a capability in a process's CSpace by slot handle
+    pub fn lookup_cap(&self, pid: u32, slot: u32) -> Option<Capability> {
+        self.processes
+            .iter()
+            .find(|p| p.id == pid)
+            .and_then(|p| p.cspace.get(slot))
+            .copied()
+    }
+
+    /// Revoke a capability and everything derived from it
+    ///
+    /// Walks the derivation tree through every CSpace and the in-transit
+    /// list, so copies handed to other processes (directly or still queued
+    /// in a message) lose their rights together with their ancestor.
+    /// Returns the number of capabilities revoked.
+    pub fn revoke_tree(&mut self, cap_id: u32) -> usize {
+        let mut pending = Vec::new();
+        pending.push(cap_id);
+        let mut revoked = 0;
+
+        while let Some(id) = pending.pop() {
+            let caps = self
+                .processes
+                .iter_mut()
+                .flat_map(|p| p.cspace.slots.iter_mut().flatten())
+                .chain(self.in_transit.iter_mut());
+
+            for cap in caps {
+                if (cap.id == id || cap.parent == id) && !cap.revoked {
+                    cap.revoke();
+                    revoked += 1;
+                    if cap.id != id {
+                        pending.push(cap.id);
+                    }
+                }
+            }
+        }
+
+        revoked
+    }
+
+    /// Update the scheduling state of a process (no-op if it does not exist)
+    pub fn set_process_state(&mut self, pid: u32, new_state: ProcessState) {
+        if let Some(p) = self.processes.iter_mut().find(|p| p.id == pid) {
+            p.state = new_state;
+        }
+    }
 }
 
 /// Process descriptor
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct ProcessDescriptor {
     pub id: u32,
     pub name: [u8; 32],
@@ -35,6 +103,81 @@ pub struct ProcessDescriptor {
     pub state: ProcessState,
     pub stack_pointer: u64,
     pub instruction_pointer: u64,
+    pub reply_msg: Option<(Vec<u64>, MessageMeta)>,  // Reply delivered to a pending port_call
+    pub reply_capacity: usize,  // Words the pending port_call can accept as a reply
+    pub call_error: u64,  // Set instead of reply_msg when a pending port_call fails
+    pub cspace: CSpace,  // Capabilities held by this process
+    pub vm_regions: Vec<VmRegion>,  // Memory from vm_allocate, sorted by address
+    pub pager: Option<FaultHandler>,  // Receives page faults; none sends them on as exceptions
+    pub exception_handler: Option<FaultHandler>,  // Receives exceptions; none uses init_server's
+    pub context: CpuContext,  // Registers saved while another process runs
+    pub kernel_stack: Option<KernelStack>,  // None for init_server, which keeps the boot stack
+}
+
+impl ProcessDescriptor {
+    pub fn new(id: u32, entry: u64, stack: u64) -> Self {
+        Self {
+            id,
+            name: [0u8; 32],
+            memory_start: USER_SPACE_START,
+            memory_end: USER_SPACE_END,
+            page_table_root: 0,  // Set once an address space is created
+            state: ProcessState::Ready,
+            stack_pointer: stack,
+            instruction_pointer: entry,
+            reply_msg: None,
+            reply_capacity: 0,
+            call_error: E_OK,
+            cspace: CSpace::new(),
+            vm_regions: Vec::new(),
+            pager: None,
+            exception_handler: None,
+            context: CpuContext::default(),
+            kernel_stack: None,
+        }
+    }
+}
+
+/// A port the kernel forwards a process's faults to
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct FaultHandler {
+    pub port_id: u32,
+    pub badge: u64,  // Badge of the capability it was registered with
+}
+
+/// The notification timer ticks are signalled on, set with SYS_TIMER_SET
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct TimerTarget {
+    pub pid: u32,  // The scheduler that registered it, run after each tick
+    pub notify_id: u32,
+}
+
+/// The port yields are reported to, set with SYS_SCHED_SET_PORT
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct SchedulerPort {
+    pub pid: u32,  // The scheduler that registered it, run after each yield
+    pub port_id: u32,
+    pub badge: u64,  // Badge of the capability it was registered with
+}
+
+/// A range of user memory mapped by vm_allocate or memobj_map
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct VmRegion {
+    pub start: u64,
+    pub size: u64,   // In bytes, a multiple of the page size
+    pub flags: u32,  // VM_* protection
+    pub object_id: u32,  // Memory object mapped here, 0 for private memory
+    pub cap_id: u32,     // Capability the object was mapped through, 0 for private memory
+}
+
+impl VmRegion {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+
+    pub fn overlaps(&self, start: u64, size: u64) -> bool {
+        start < self.end() && self.start < start + size
+    }
 }
 
 #[derive(Clone, Debug, Copy, PartialEq)]
@@ -45,29 +188,107 @@ pub enum ProcessState {
     Dead,
 }
 
+/// Default port queue depth, in messages
+pub const PORT_DEFAULT_QUEUE_DEPTH: u32 = 64;
+
+/// Largest queue depth port_allocate accepts
+pub const PORT_MAX_QUEUE_DEPTH: u32 = 1024;
+
+/// Default maximum message length, in u64 words
+pub const PORT_DEFAULT_MSG_LEN: u32 = 8;
+
+/// Largest message length port_allocate accepts, in u64 words
+pub const PORT_MAX_MSG_LEN: u32 = 64;
+
+/// Upper bound on one port's ring storage (depth × message length), in u64
+/// words (128 KB)
+pub const PORT_MAX_QUEUE_WORDS: u32 = 16384;
+
+/// Most out-of-line buffers one message can carry
+pub const IPC_MAX_OOL: usize = 4;
+
+/// Upper bound on the out-of-line data carried by one message, in bytes
+pub const IPC_MAX_OOL_BYTES: usize = 65536;
+
+/// Most port_watch registrations on one port, and made by one process
+pub const PORT_MAX_WATCHERS: usize = 64;
+pub const PROCESS_MAX_WATCHES: usize = 256;
+
 /// IPC Port - capability-based message queue
 #[derive(Clone, Debug)]
 pub struct Port {
     pub id: u32,
     pub owner_pid: u32,
-    pub queue: [u64; 512],  // Ring buffer: [u64; 8] × 64 messages max
+    pub queue: Vec<u64>,  // Ring buffer: max_queue_size slots of max_msg_len words
     pub queue_head: u32,
     pub queue_tail: u32,
     pub queue_size: u32,
     pub max_queue_size: u32,
+    pub max_msg_len: u32,  // In u64 words
+    pub lens: Vec<u32>,  // Length of the message in each queue slot
+    pub meta: Vec<MessageMeta>,  // Kernel-side metadata, one per queue slot
+    pub waiters: Vec<u32>,  // PIDs sleeping in port_receive, FIFO order
+    pub watchers: Vec<PortWatch>,  // Notified with MSG_PORT_DIED when the port is destroyed
+    pub set_id: u32,  // Port set this port belongs to, 0 if none
+}
+
+/// Registration for a "port died" notification
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct PortWatch {
+    pub pid: u32,          // Process that registered it
+    pub notify_port: u32,  // Port ID the notification is queued on
+    pub badge: u64,        // Badge of the capability used to register
+    pub cookie: u64,       // Echoed back in the notification
+}
+
+/// Kernel-side metadata attached to a queued message
+#[derive(Clone, Debug, Copy, Default, PartialEq)]
+pub struct MessageMeta {
+    pub sender_pid: u32,  // Stamped by the kernel, never taken from the payload
+    pub badge: u64,       // Badge of the capability the sender used
+    pub caller_pid: u32,  // Non-zero if sent by port_call and awaiting a reply
+    pub cap_id: u32,      // Capability carried by the message (in KernelState.in_transit), 0 if none
+    pub ool_id: u32,      // Out-of-line data carried by the message (in KernelState.ool_in_transit), 0 if none
+}
+
+/// Out-of-line buffers copied from a sender, held by the kernel until the
+/// message carrying them is received
+#[derive(Clone, Debug, PartialEq)]
+pub struct OolPayload {
+    pub id: u32,
+    pub buffers: Vec<Vec<u8>>,
 }
 
 impl Port {
     pub fn new(id: u32, owner_pid: u32) -> Self {
-        Self {
+        Self::with_limits(id, owner_pid, PORT_DEFAULT_QUEUE_DEPTH, PORT_DEFAULT_MSG_LEN)
+    }
+
+    /// Create a port holding up to `depth` messages of up to `msg_len` words
+    ///
+    /// The caller checks the limits against the PORT_MAX_* bounds.
+    pub fn with_limits(id: u32, owner_pid: u32, depth: u32, msg_len: u32) -> Self {
+        Self::try_with_limits(id, owner_pid, depth, msg_len).expect("kernel heap exhausted")
+    }
+
+    /// Like `with_limits`, but fails with E_NOMEM if the queue cannot be
+    /// allocated
+    pub fn try_with_limits(id: u32, owner_pid: u32, depth: u32, msg_len: u32) -> Result<Self, u64> {
+        Ok(Self {
             id,
             owner_pid,
-            queue: [0u64; 512],
+            queue: try_vec(0u64, (depth * msg_len) as usize)?,
             queue_head: 0,
             queue_tail: 0,
             queue_size: 0,
-            max_queue_size: 64,  // Max 64 messages of [u64; 8]
-        }
+            max_queue_size: depth,
+            max_msg_len: msg_len,
+            lens: try_vec(0u32, depth as usize)?,
+            meta: try_vec(MessageMeta::default(), depth as usize)?,
+            waiters: Vec::new(),
+            watchers: Vec::new(),
+            set_id: 0,
+        })
     }
 
     pub fn is_full(&self) -> bool {
@@ -78,52 +299,296 @@ impl Port {
         self.queue_size == 0
     }
 
-    pub fn push_message(&mut self, msg: &[u64; 8]) -> bool {
-        if self.is_full() {
-            return false;
+    /// Length in words of the message at the head of the queue
+    pub fn front_len(&self) -> Option<usize> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.lens[self.queue_head as usize] as usize)
         }
+    }
 
-        let base_idx = (self.queue_tail as usize) * 8;
-        for i in 0..8 {
-            self.queue[base_idx + i] = msg[i];
+    pub fn push_message(&mut self, msg: &[u64]) -> bool {
+        self.push_message_with_meta(msg, MessageMeta::default())
+    }
+
+    /// Queue a message; fails if the queue is full or the message is longer
+    /// than `max_msg_len`
+    pub fn push_message_with_meta(&mut self, msg: &[u64], meta: MessageMeta) -> bool {
+        if self.is_full() || msg.len() > self.max_msg_len as usize {
+            return false;
         }
 
-        self.queue_tail = (self.queue_tail + 1) % 64;
+        let slot = self.queue_tail as usize;
+        let base_idx = slot * self.max_msg_len as usize;
+        self.queue[base_idx..base_idx + msg.len()].copy_from_slice(msg);
+        self.lens[slot] = msg.len() as u32;
+        self.meta[slot] = meta;
+
+        self.queue_tail = (self.queue_tail + 1) % self.max_queue_size;
         self.queue_size += 1;
         true
     }
 
-    pub fn pop_message(&mut self) -> Option<[u64; 8]> {
+    pub fn pop_message(&mut self) -> Option<Vec<u64>> {
+        self.pop_message_with_meta().map(|(msg, _)| msg)
+    }
+
+    pub fn pop_message_with_meta(&mut self) -> Option<(Vec<u64>, MessageMeta)> {
         if self.is_empty() {
             return None;
         }
 
-        let base_idx = (self.queue_head as usize) * 8;
-        let msg = [
-            self.queue[base_idx],
-            self.queue[base_idx + 1],
-            self.queue[base_idx + 2],
-            self.queue[base_idx + 3],
-            self.queue[base_idx + 4],
-            self.queue[base_idx + 5],
-            self.queue[base_idx + 6],
-            self.queue[base_idx + 7],
-        ];
+        let slot = self.queue_head as usize;
+        let base_idx = slot * self.max_msg_len as usize;
+        let msg = self.queue[base_idx..base_idx + self.lens[slot] as usize].to_vec();
+        let meta = self.meta[slot];
 
-        self.queue_head = (self.queue_head + 1) % 64;
+        self.queue_head = (self.queue_head + 1) % self.max_queue_size;
         self.queue_size -= 1;
-        Some(msg)
+        Some((msg, meta))
+    }
+
+    /// Metadata of the message at the head of the queue
+    pub fn front_meta(&self) -> Option<MessageMeta> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.meta[self.queue_head as usize])
+        }
+    }
+
+    /// Park a receiver on this port until a message arrives
+    pub fn add_waiter(&mut self, pid: u32) {
+        if !self.waiters.contains(&pid) {
+            self.waiters.push(pid);
+        }
+    }
+
+    /// Take the longest-waiting receiver, if any
+    pub fn take_waiter(&mut self) -> Option<u32> {
+        if self.waiters.is_empty() {
+            None
+        } else {
+            Some(self.waiters.remove(0))
+        }
     }
 }
 
+/// Port set - lets one receiver wait on several ports at once
+///
+/// Members are ports or notifications; each belongs to at most one set.
+/// Receiving on the set takes the next message (or pending signal bits)
+/// from any member, scanning round-robin so a busy port cannot starve the
+/// others.
+#[derive(Clone, Debug)]
+pub struct PortSet {
+    pub id: u32,
+    pub owner_pid: u32,
+    pub members: Vec<PortSetMember>,
+    pub next: usize,  // Member to scan first on the next receive
+    pub waiters: Vec<u32>,  // PIDs sleeping in port_receive on the set, FIFO order
+}
+
+/// A port or notification in a set, remembered with the slot it was added under
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct PortSetMember {
+    pub kind: CapabilityKind,  // Port or Notification
+    pub object_id: u32,
+    pub slot: u32,  // Reported as MessageInfo.port for messages from this member
+}
+
+impl PortSet {
+    pub fn new(id: u32, owner_pid: u32) -> Self {
+        Self {
+            id,
+            owner_pid,
+            members: Vec::new(),
+            next: 0,
+            waiters: Vec::new(),
+        }
+    }
+
+    /// Drop a member from the set; returns false if it was not a member
+    pub fn remove_member(&mut self, kind: CapabilityKind, object_id: u32) -> bool {
+        match self.members.iter().position(|m| m.kind == kind && m.object_id == object_id) {
+            Some(i) => {
+                self.members.remove(i);
+                self.next = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Park a receiver on this set until a member gets a message
+    pub fn add_waiter(&mut self, pid: u32) {
+        if !self.waiters.contains(&pid) {
+            self.waiters.push(pid);
+        }
+    }
+
+    /// Take the longest-waiting receiver, if any
+    pub fn take_waiter(&mut self) -> Option<u32> {
+        if self.waiters.is_empty() {
+            None
+        } else {
+            Some(self.waiters.remove(0))
+        }
+    }
+}
+
+/// Memory object - physical memory that several processes can map
+///
+/// The frames belong to the object, not to any mapping: unmapping leaves
+/// them alone, and they are freed when the object is destroyed.
+#[derive(Clone, Debug)]
+pub struct MemoryObject {
+    pub id: u32,
+    pub owner_pid: u32,
+    pub frames: Vec<u64>,  // Physical address of each page, in order
+}
+
+impl MemoryObject {
+    /// Size in bytes
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * crate::frame::FRAME_SIZE
+    }
+}
+
+/// Notification - a word of signal bits, waited on like a port
+///
+/// Signalling ORs bits into the word and never fails; receiving returns all
+/// pending bits and clears them. Cheaper than a message for events such as
+/// timer ticks or IRQs.
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub id: u32,
+    pub owner_pid: u32,
+    pub bits: u64,  // Signalled since the last receive
+    pub waiters: Vec<u32>,  // PIDs sleeping in port_receive, FIFO order
+    pub set_id: u32,  // Port set this notification belongs to, 0 if none
+}
+
+impl Notification {
+    pub fn new(id: u32, owner_pid: u32) -> Self {
+        Self {
+            id,
+            owner_pid,
+            bits: 0,
+            waiters: Vec::new(),
+            set_id: 0,
+        }
+    }
+
+    pub fn signal(&mut self, bits: u64) {
+        self.bits |= bits;
+    }
+
+    /// Take all pending bits, leaving none
+    pub fn take_bits(&mut self) -> u64 {
+        core::mem::replace(&mut self.bits, 0)
+    }
+
+    /// Park a receiver until a signal arrives
+    pub fn add_waiter(&mut self, pid: u32) {
+        if !self.waiters.contains(&pid) {
+            self.waiters.push(pid);
+        }
+    }
+
+    /// Take the longest-waiting receiver, if any
+    pub fn take_waiter(&mut self) -> Option<u32> {
+        if self.waiters.is_empty() {
+            None
+        } else {
+            Some(self.waiters.remove(0))
+        }
+    }
+}
+
+/// Maximum number of capability slots per process
+pub const CSPACE_SLOTS: usize = 1024;
+
+/// Per-process capability table
+///
+/// Userspace names capabilities by slot handle. Handles start at 1 so that
+/// 0 can mean "no capability" in syscall arguments and message info.
+#[derive(Clone, Debug)]
+pub struct CSpace {
+    pub slots: Vec<Option<Capability>>,  // slots[i] is handle i + 1
+}
+
+impl CSpace {
+    pub const fn new() -> Self {
+        Self { slots: Vec::new() }
+    }
+
+    /// Store a capability in the first free slot and return its handle
+    pub fn insert(&mut self, cap: Capability) -> Option<u32> {
+        if let Some(i) = self.slots.iter().position(|s| s.is_none()) {
+            self.slots[i] = Some(cap);
+            return Some(i as u32 + 1);
+        }
+
+        if self.slots.len() >= CSPACE_SLOTS || self.slots.try_reserve(1).is_err() {
+            return None;
+        }
+
+        self.slots.push(Some(cap));
+        Some(self.slots.len() as u32)
+    }
+
+    /// Make sure the next `n` inserts succeed
+    pub fn reserve(&mut self, n: usize) -> bool {
+        let free = self.slots.iter().filter(|s| s.is_none()).count();
+        let grow = n.saturating_sub(free);
+        grow == 0 || (self.slots.len() + grow <= CSPACE_SLOTS && self.slots.try_reserve(grow).is_ok())
+    }
+
+    pub fn get(&self, handle: u32) -> Option<&Capability> {
+        let idx = (handle as usize).checked_sub(1)?;
+        self.slots.get(idx)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, handle: u32) -> Option<&mut Capability> {
+        let idx = (handle as usize).checked_sub(1)?;
+        self.slots.get_mut(idx)?.as_mut()
+    }
+
+    pub fn remove(&mut self, handle: u32) -> Option<Capability> {
+        let idx = (handle as usize).checked_sub(1)?;
+        self.slots.get_mut(idx)?.take()
+    }
+}
+
+impl Default for CSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Kind of kernel object a capability refers to
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub enum CapabilityKind {
+    Port,     // target_id is a port ID
+    Reply,    // target_id is the PID blocked in port_call (one-shot)
+    PortSet,  // target_id is a port set ID
+    Notification,  // target_id is a notification ID
+    MemoryObject,  // target_id is a memory object ID
+}
+
 /// Capability - unforgeable access token
 #[derive(Clone, Debug, Copy)]
 pub struct Capability {
-    pub id: u32,
+    pub id: u32,  // Kernel-internal identity, never exposed to userspace
     pub owner_pid: u32,
     pub target_id: u32,  // Port or object ID
     pub rights: u32,
     pub revoked: bool,
+    pub kind: CapabilityKind,
+    pub badge: u64,  // Set when minted via cap_move, delivered with every send
+    pub parent: u32,  // ID of the capability this one was derived from, 0 for roots
 }
 
 impl Capability {
@@ -134,6 +599,32 @@ impl Capability {
             target_id,
             rights,
             revoked: false,
+            kind: CapabilityKind::Port,
+            badge: 0,
+            parent: 0,
+        }
+    }
+
+    /// Mint a child of this capability with a subset of its rights
+    ///
+    /// The child keeps the kind and badge and is linked to its parent, so
+    /// revoking the parent revokes the child as well.
+    pub fn derive(&self, id: u32, owner_pid: u32, rights: u32) -> Self {
+        Self {
+            id,
+            owner_pid,
+            rights,
+            revoked: false,
+            parent: self.id,
+            ..*self
+        }
+    }
+
+    /// One-shot capability allowing `owner_pid` to answer `caller_pid`'s port_call
+    pub fn reply(id: u32, owner_pid: u32, caller_pid: u32) -> Self {
+        Self {
+            kind: CapabilityKind::Reply,
+            ..Self::new(id, owner_pid, caller_pid, CAP_SEND)
         }
     }
 
@@ -150,7 +641,13 @@ impl Capability {
 pub static KERNEL_STATE: Mutex<KernelState> = Mutex::new(KernelState {
     processes: Vec::new(),
     ports: Vec::new(),
-    capabilities: Vec::new(),
+    port_sets: Vec::new(),
+    notifications: Vec::new(),
+    memory_objects: Vec::new(),
+    in_transit: Vec::new(),
+    ool_in_transit: Vec::new(),
+    timer: None,
+    scheduler: None,
     current_process_id: 0,
 });
 
@@ -160,6 +657,18 @@ pub static NEXT_CAP_ID: Mutex<u32> = Mutex::new(1);
 /// Next port ID counter
 pub static NEXT_PORT_ID: Mutex<u32> = Mutex::new(1);
 
+/// Next port set ID counter
+pub static NEXT_PORT_SET_ID: Mutex<u32> = Mutex::new(1);
+
+/// Next notification ID counter
+pub static NEXT_NOTIFICATION_ID: Mutex<u32> = Mutex::new(1);
+
+/// Next memory object ID counter
+pub static NEXT_MEMORY_OBJECT_ID: Mutex<u32> = Mutex::new(1);
+
+/// Next out-of-line payload ID counter
+pub static NEXT_OOL_ID: Mutex<u32> = Mutex::new(1);
+
 /// Next process ID counter
 pub static NEXT_PROCESS_ID: Mutex<u32> = Mutex::new(2);  // Start from 2 (1 is init_server)
 
@@ -168,6 +677,32 @@ pub fn current_pid() -> u32 {
     KERNEL_STATE.lock().current_process_id
 }
 
+/// Get the scheduling state of a process
+pub fn process_state(pid: u32) -> Option<ProcessState> {
+    KERNEL_STATE
+        .lock()
+        .processes
+        .iter()
+        .find(|p| p.id == pid)
+        .map(|p| p.state)
+}
+
+/// Make room for one more entry in a kernel table
+///
+/// Syscalls reserve before changing any state, so running out of kernel
+/// heap fails the call with E_NOMEM instead of aborting the kernel.
+pub fn reserve_one<T>(table: &mut Vec<T>) -> Result<(), u64> {
+    table.try_reserve(1).map_err(|_| E_NOMEM)
+}
+
+/// `len` copies of `value`, or E_NOMEM if the kernel heap is exhausted
+pub fn try_vec<T: Clone>(value: T, len: usize) -> Result<Vec<T>, u64> {
+    let mut v = Vec::new();
+    v.try_reserve_exact(len).map_err(|_| E_NOMEM)?;
+    v.resize(len, value);
+    Ok(v)
+}
+
 /// Get mutable reference to kernel state
 pub fn kernel_state_mut() -> spin::MutexGuard<'static, KernelState> {
     KERNEL_STATE.lock()
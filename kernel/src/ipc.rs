@@ -1,154 +1,1437 @@
 // kernel/src/ipc.rs
 // Inter-Process Communication (IPC) - Port and Capability management
+//
+// Userspace names ports and other capabilities by slot handles in its own
+// CSpace (see globals::CSpace); global port and capability IDs stay inside
+// the kernel.
 
 use crate::error::*;
 use crate::globals::*;
+use crate::uaccess::{current_user_space, user_space, UserSpace};
+use alloc::vec;
 use alloc::vec::Vec;
 
 /// Allocate a new port for the current process
-pub fn port_allocate() -> u64 {
+///
+/// The port queues up to `depth` messages of up to `msg_len` u64 words
+/// (0 picks the default for either). Both are bounded by the kernel, as is
+/// the ring storage they add up to. Returns the CSpace slot of a capability
+/// with full rights to the port.
+pub fn port_allocate(depth: u32, msg_len: u32) -> u64 {
+    let depth = if depth == 0 { PORT_DEFAULT_QUEUE_DEPTH } else { depth };
+    let msg_len = if msg_len == 0 { PORT_DEFAULT_MSG_LEN } else { msg_len };
+
+    if depth > PORT_MAX_QUEUE_DEPTH
+        || msg_len > PORT_MAX_MSG_LEN
+        || depth * msg_len > PORT_MAX_QUEUE_WORDS
+    {
+        return E_INVAL;
+    }
+
     let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    if !state.processes.iter().any(|p| p.id == current_pid) {
+        return E_PROCESS_NOT_FOUND;
+    }
+
+    // Allocate the queue and table entry before handing out a capability
+    if let Err(e) = reserve_one(&mut state.ports) {
+        return e;
+    }
+
     let mut next_id = NEXT_PORT_ID.lock();
+    let port_id = *next_id;
+
+    let port = match Port::try_with_limits(port_id, current_pid, depth, msg_len) {
+        Ok(port) => port,
+        Err(e) => return e,
+    };
+    *next_id += 1;
+
+    // Allocate capability for this port
+    let mut cap_id = NEXT_CAP_ID.lock();
+    let capability = Capability::new(
+        *cap_id,
+        current_pid,
+        port_id,
+        CAP_SEND | CAP_RECEIVE | CAP_DESTROY | CAP_DERIVE,
+    );
+    *cap_id += 1;
+
+    let slot = match state.process_mut(current_pid).and_then(|p| p.cspace.insert(capability)) {
+        Some(slot) => slot,
+        None => return E_NOMEM,
+    };
+
+    state.ports.push(port);
+
+    slot as u64
+}
+
+/// Allocate a new, empty port set for the current process
+///
+/// Returns the CSpace slot of a capability to the set. Passing that slot to
+/// port_receive waits on every member port at once.
+pub fn port_set_allocate() -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    if !state.processes.iter().any(|p| p.id == current_pid) {
+        return E_PROCESS_NOT_FOUND;
+    }
+
+    if let Err(e) = reserve_one(&mut state.port_sets) {
+        return e;
+    }
+
+    let mut next_id = NEXT_PORT_SET_ID.lock();
+    let set_id = *next_id;
+    *next_id += 1;
+
+    let mut cap_id = NEXT_CAP_ID.lock();
+    let mut capability = Capability::new(
+        *cap_id,
+        current_pid,
+        set_id,
+        CAP_RECEIVE | CAP_DESTROY | CAP_DERIVE,
+    );
+    capability.kind = CapabilityKind::PortSet;
+    *cap_id += 1;
+
+    let slot = match state.process_mut(current_pid).and_then(|p| p.cspace.insert(capability)) {
+        Some(slot) => slot,
+        None => return E_NOMEM,
+    };
+
+    state.port_sets.push(PortSet::new(set_id, current_pid));
+
+    slot as u64
+}
+
+/// Add a port or notification to a port set
+///
+/// The caller needs `CAP_RECEIVE` on both. A member can be in one set at a
+/// time. Messages from it are reported with `MessageInfo.port = port_slot`.
+pub fn port_set_add(set_slot: u32, port_slot: u32) -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    let (set_idx, _) = match lookup_port_set(&state, current_pid, set_slot) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    let (member, set_id, pending) = match lookup_set_member(&state, current_pid, port_slot) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    if set_id != 0 {
+        return E_INVAL;
+    }
+
+    let set_id = state.port_sets[set_idx].id;
+    set_member_set_id(&mut state, &member, set_id);
+
+    let set = &mut state.port_sets[set_idx];
+    set.members.push(member);
+
+    // Messages already queued must not go unnoticed by a sleeping receiver
+    let waiter = if pending { set.take_waiter() } else { None };
+    if let Some(pid) = waiter {
+        state.set_process_state(pid, ProcessState::Ready);
+    }
+
+    E_OK
+}
+
+/// Remove a port or notification from a port set
+pub fn port_set_remove(set_slot: u32, port_slot: u32) -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    let (set_idx, _) = match lookup_port_set(&state, current_pid, set_slot) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    let (member, _, _) = match lookup_set_member(&state, current_pid, port_slot) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    if !state.port_sets[set_idx].remove_member(member.kind, member.object_id) {
+        return E_INVAL;
+    }
+    set_member_set_id(&mut state, &member, 0);
+
+    E_OK
+}
+
+/// Resolve a slot naming a port or notification the caller may receive
+/// from, for use as a port set member
+///
+/// Returns the member, the set it currently belongs to (0 if none) and
+/// whether it already has something to receive.
+fn lookup_set_member(
+    state: &KernelState,
+    pid: u32,
+    slot: u32,
+) -> Result<(PortSetMember, u32, bool), u64> {
+    let is_notification = matches!(
+        state.lookup_cap(pid, slot),
+        Some(c) if c.kind == CapabilityKind::Notification
+    );
+
+    if is_notification {
+        let (idx, _) = lookup_notification(state, pid, slot, CAP_RECEIVE)?;
+        let n = &state.notifications[idx];
+        let member = PortSetMember {
+            kind: CapabilityKind::Notification,
+            object_id: n.id,
+            slot,
+        };
+        Ok((member, n.set_id, n.bits != 0))
+    } else {
+        let (idx, _) = lookup_port(state, pid, slot, CAP_RECEIVE)?;
+        let port = &state.ports[idx];
+        let member = PortSetMember {
+            kind: CapabilityKind::Port,
+            object_id: port.id,
+            slot,
+        };
+        Ok((member, port.set_id, !port.is_empty()))
+    }
+}
+
+/// Record which set a member belongs to (0 = none)
+fn set_member_set_id(state: &mut KernelState, member: &PortSetMember, set_id: u32) {
+    if member.kind == CapabilityKind::Notification {
+        if let Some(n) = state.notifications.iter_mut().find(|n| n.id == member.object_id) {
+            n.set_id = set_id;
+        }
+    } else if let Some(port) = state.ports.iter_mut().find(|p| p.id == member.object_id) {
+        port.set_id = set_id;
+    }
+}
+
+/// Allocate a new notification for the current process
+///
+/// Returns the CSpace slot of a capability with full rights. Holders of
+/// `CAP_SEND` signal it with notify_signal; port_receive on it (or on a
+/// port set containing it) returns `[bits]` and clears the bits.
+pub fn notify_allocate() -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    if !state.processes.iter().any(|p| p.id == current_pid) {
+        return E_PROCESS_NOT_FOUND;
+    }
+
+    if let Err(e) = reserve_one(&mut state.notifications) {
+        return e;
+    }
+
+    let mut next_id = NEXT_NOTIFICATION_ID.lock();
+    let notify_id = *next_id;
+    *next_id += 1;
+
+    let mut cap_id = NEXT_CAP_ID.lock();
+    let mut capability = Capability::new(
+        *cap_id,
+        current_pid,
+        notify_id,
+        CAP_SEND | CAP_RECEIVE | CAP_DESTROY | CAP_DERIVE,
+    );
+    capability.kind = CapabilityKind::Notification;
+    *cap_id += 1;
+
+    let slot = match state.process_mut(current_pid).and_then(|p| p.cspace.insert(capability)) {
+        Some(slot) => slot,
+        None => return E_NOMEM,
+    };
+
+    state.notifications.push(Notification::new(notify_id, current_pid));
+
+    slot as u64
+}
+
+/// OR `bits` into a notification and wake a receiver (requires `CAP_SEND`)
+///
+/// Unlike port_send this never fails for lack of queue space: repeated
+/// signals before a receive simply merge.
+pub fn notify_signal(notify_slot: u32, bits: u64) -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    if bits == 0 {
+        return E_INVAL;
+    }
+
+    let (idx, _) = match lookup_notification(&state, current_pid, notify_slot, CAP_SEND) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    signal_notification(&mut state, idx, bits);
+    E_OK
+}
+
+/// OR `bits` into `state.notifications[idx]` and wake the first receiver
+/// sleeping on it, or else on its port set
+pub fn signal_notification(state: &mut KernelState, idx: usize, bits: u64) {
+    let n = &mut state.notifications[idx];
+    n.signal(bits);
+
+    let set_id = n.set_id;
+    let waiter = n.take_waiter();
+    wake_receiver(state, waiter, set_id);
+}
+
+/// Out-of-band information about a received message, filled in by port_receive
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default)]
+pub struct MessageInfo {
+    pub reply_cap: u64,   // Slot of the one-shot reply capability, 0 for plain sends
+    pub sender_pid: u64,  // PID of the sending process (kernel-stamped)
+    pub badge: u64,       // Badge of the sender's capability, 0 if unbadged
+    pub cap: u64,         // Slot of a capability transferred with the message, 0 if none
+    pub port: u64,        // Slot of the port the message arrived on (member slot for port sets)
+}
+
+/// Capability attached to an outgoing message
+#[derive(Clone, Debug, Copy, Default)]
+pub struct CapTransfer {
+    pub cap_slot: u32,  // Sender's slot of the capability to pass, 0 = none
+    pub rights: u32,    // Rights granted to the receiver (subset of the sender's)
+    pub flags: u64,     // IPC_CAP_MOVE gives up the sender's capability
+}
+
+/// An out-of-line buffer in user memory
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default)]
+pub struct OolDescriptor {
+    pub addr: u64,  // Start of the buffer
+    pub len: u64,   // Length in bytes
+}
+
+/// Out-of-line buffers sent with port_send/port_call, or offered to
+/// port_receive as space for the buffers of the next message
+///
+/// On receive the kernel writes back the number of buffers delivered in
+/// `count` and the length of each in `desc[i].len`.
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default)]
+pub struct OolBuffers {
+    pub count: u64,  // Descriptors in use, at most IPC_MAX_OOL
+    pub desc: [OolDescriptor; IPC_MAX_OOL],
+}
+
+impl OolBuffers {
+    /// The descriptors in use (`count` must already be checked)
+    pub fn used(&self) -> &[OolDescriptor] {
+        &self.desc[..self.count as usize]
+    }
+}
+
+/// A received message: its words, out-of-band information and out-of-line
+/// buffers
+type Received = (Vec<u64>, MessageInfo, Vec<Vec<u8>>);
+
+/// Send a message of `len` u64 words to a port, optionally carrying a
+/// capability and out-of-line buffers
+///
+/// `len` must not exceed the port's maximum message length. The buffers
+/// described by `ool_ptr` (may be null) are copied into the kernel here, so
+/// the sender may reuse them as soon as the send returns.
+pub fn port_send(
+    port_slot: u32,
+    msg_ptr: *const u64,
+    len: usize,
+    xfer: CapTransfer,
+    ool_ptr: *const OolBuffers,
+) -> u64 {
+    if len == 0 || len > PORT_MAX_MSG_LEN as usize {
+        return E_INVAL;
+    }
+
+    let (msg, ool) = match read_user_request(msg_ptr, len, ool_ptr) {
+        Ok(request) => request,
+        Err(e) => return e,
+    };
+
+    let mut state = kernel_state_mut();
+    enqueue_message(&mut state, port_slot, &msg, 0, xfer, ool)
+}
+
+/// Copy an outgoing message and its out-of-line buffers from the current
+/// process
+fn read_user_request(
+    msg_ptr: *const u64,
+    len: usize,
+    ool_ptr: *const OolBuffers,
+) -> Result<(Vec<u64>, Vec<Vec<u8>>), u64> {
+    let space = current_user_space()?;
+    let msg = space.read_words(msg_ptr as u64, len)?;
+    let ool = read_user_ool(&space, ool_ptr)?;
+    Ok((msg, ool))
+}
+
+/// Receive a message from a port, notification or port set into a buffer
+/// of `len` u64 words, returning the number of words received
+///
+/// A notification yields `[bits]` with its pending signal bits, which are
+/// cleared. A message longer than the buffer is left queued and `E_INVAL`
+/// is returned. A set skips such a member for the others, and only fails
+/// when nothing else is queued, reporting the member's slot as the `port`
+/// of `info_ptr` so the caller can drain it with a bigger buffer. If nothing is queued and `IPC_NONBLOCK` is not set, the
+/// caller is put to sleep on the port's (or set's) wait queue and
+/// `E_WOULD_BLOCK` is returned; the syscall layer then waits for
+/// `port_send` to wake it and retries.
+///
+/// When the message came from `port_call`, a one-shot reply capability is
+/// minted for the receiver and reported through `info_ptr` (may be null).
+/// A capability carried by the message is installed for the receiver here,
+/// and its out-of-line buffers are copied into the space described by
+/// `ool_ptr` (may be null). If they do not fit, the message is left queued
+/// and `E_INVAL` is returned, as for an over-long message. Likewise, if the
+/// receiver's CSpace has no room for the reply or carried capability, the
+/// message is left queued and `E_NOMEM` is returned.
+///
+/// Destination buffers outside the caller's memory fail with `E_FAULT`
+/// before anything is dequeued; if a copy still faults (an unmapped page),
+/// the message is lost and `E_FAULT` is returned.
+pub fn port_receive(
+    port_slot: u32,
+    buf_ptr: *mut u64,
+    len: usize,
+    flags: u64,
+    info_ptr: *mut MessageInfo,
+    ool_ptr: *mut OolBuffers,
+) -> u64 {
+    if len == 0 {
+        return E_INVAL;
+    }
+
+    let space = match current_user_space() {
+        Ok(space) => space,
+        Err(e) => return e,
+    };
+    let ool_space = match check_receive_buffers(&space, buf_ptr, len, info_ptr, ool_ptr) {
+        Ok(ool_space) => ool_space,
+        Err(e) => return e,
+    };
+    let ool_capacity: Vec<usize> = ool_space.used().iter().map(|d| d.len as usize).collect();
+
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    let kind = state.lookup_cap(current_pid, port_slot).map(|c| c.kind);
+
+    let received = match kind {
+        Some(CapabilityKind::PortSet) => {
+            let received = receive_from_set(&mut state, current_pid, port_slot, len, &ool_capacity, flags);
+            if received.as_ref().err() == Some(&E_INVAL) {
+                if let Some(slot) = oversized_member(&state, current_pid, port_slot, len, &ool_capacity) {
+                    let info = MessageInfo { port: slot as u64, ..MessageInfo::default() };
+                    if let Err(e) = write_user_info(&space, info_ptr, &info) {
+                        return e;
+                    }
+                }
+            }
+            received
+        }
+        Some(CapabilityKind::Notification) => {
+            receive_from_notification(&mut state, current_pid, port_slot, flags)
+        }
+        _ => receive_from_port(&mut state, current_pid, port_slot, len, &ool_capacity, flags),
+    };
+
+    match received {
+        Ok((msg, info, ool)) => {
+            let written = space
+                .write_words(buf_ptr as u64, &msg)
+                .and_then(|_| write_user_info(&space, info_ptr, &info))
+                .and_then(|_| write_user_ool(&space, ool_ptr, ool_space, &ool));
+
+            match written {
+                Ok(()) => msg.len() as u64,  // u64 words received
+                Err(e) => e,
+            }
+        }
+        Err(e) => e,
+    }
+}
+
+/// Check that a receiver's buffers lie in memory it may write, returning the
+/// out-of-line space it offers
+fn check_receive_buffers(
+    space: &UserSpace,
+    buf_ptr: *mut u64,
+    len: usize,
+    info_ptr: *mut MessageInfo,
+    ool_ptr: *mut OolBuffers,
+) -> Result<OolBuffers, u64> {
+    let size = len.checked_mul(core::mem::size_of::<u64>()).ok_or(E_FAULT)?;
+    space.check_writable(buf_ptr as u64, size)?;
+    if !info_ptr.is_null() {
+        space.check_writable(info_ptr as u64, core::mem::size_of::<MessageInfo>())?;
+    }
+
+    let ool_space = read_user_ool_buffers(space, ool_ptr)?;
+    for d in ool_space.used() {
+        space.check_writable(d.addr, d.len as usize)?;
+    }
+    Ok(ool_space)
+}
+
+fn receive_from_port(
+    state: &mut KernelState,
+    pid: u32,
+    port_slot: u32,
+    capacity: usize,
+    ool_capacity: &[usize],
+    flags: u64,
+) -> Result<Received, u64> {
+    // Check if receiver has capability with RECEIVE right
+    let (idx, _) = lookup_port(state, pid, port_slot, CAP_RECEIVE)?;
+
+    if !front_fits(state, idx, capacity, ool_capacity) {
+        return Err(E_INVAL);
+    }
+
+    if let Some(received) = pop_received(state, idx, pid, port_slot)? {
+        return Ok(received);
+    }
+
+    if (flags & IPC_NONBLOCK) == 0 {
+        // Sleep on the port until port_send wakes us
+        state.ports[idx].add_waiter(pid);
+        state.set_process_state(pid, ProcessState::Sleeping);
+    }
+    Err(E_WOULD_BLOCK)
+}
+
+fn receive_from_notification(
+    state: &mut KernelState,
+    pid: u32,
+    notify_slot: u32,
+    flags: u64,
+) -> Result<Received, u64> {
+    let (idx, _) = lookup_notification(state, pid, notify_slot, CAP_RECEIVE)?;
+
+    let bits = state.notifications[idx].take_bits();
+    if bits != 0 {
+        return Ok(notification_message(bits, notify_slot));
+    }
+
+    if (flags & IPC_NONBLOCK) == 0 {
+        // Sleep until notify_signal wakes us
+        state.notifications[idx].add_waiter(pid);
+        state.set_process_state(pid, ProcessState::Sleeping);
+    }
+    Err(E_WOULD_BLOCK)
+}
+
+/// Message returned for pending notification bits (sent by the kernel)
+fn notification_message(bits: u64, notify_slot: u32) -> Received {
+    let info = MessageInfo {
+        port: notify_slot as u64,
+        ..MessageInfo::default()
+    };
+    (vec![bits], info, Vec::new())
+}
+
+fn receive_from_set(
+    state: &mut KernelState,
+    pid: u32,
+    set_slot: u32,
+    capacity: usize,
+    ool_capacity: &[usize],
+    flags: u64,
+) -> Result<Received, u64> {
+    let (set_idx, _) = lookup_port_set(state, pid, set_slot)?;
+
+    // Round-robin over the members, starting after the last one served. A
+    // member whose next message does not fit is passed over, so that it
+    // cannot hold up the rest
+    let count = state.port_sets[set_idx].members.len();
+    let start = state.port_sets[set_idx].next;
+    let mut oversized = false;
+    for n in 0..count {
+        let k = (start + n) % count;
+        let member = state.port_sets[set_idx].members[k];
+
+        if member.kind == CapabilityKind::Notification {
+            let idx = match state.notifications.iter().position(|n| n.id == member.object_id) {
+                Some(i) if state.notifications[i].bits != 0 => i,
+                _ => continue,
+            };
+
+            state.port_sets[set_idx].next = (k + 1) % count;
+            let bits = state.notifications[idx].take_bits();
+            return Ok(notification_message(bits, member.slot));
+        }
+
+        let idx = match state.ports.iter().position(|p| p.id == member.object_id) {
+            Some(i) if !state.ports[i].is_empty() => i,
+            _ => continue,
+        };
+
+        if !front_fits(state, idx, capacity, ool_capacity) {
+            oversized = true;
+            continue;
+        }
+
+        state.port_sets[set_idx].next = (k + 1) % count;
+        if let Some(received) = pop_received(state, idx, pid, member.slot)? {
+            return Ok(received);
+        }
+    }
+
+    if oversized {
+        return Err(E_INVAL);
+    }
+
+    if (flags & IPC_NONBLOCK) == 0 {
+        // Sleep on the set until any member has something to receive
+        state.port_sets[set_idx].add_waiter(pid);
+        state.set_process_state(pid, ProcessState::Sleeping);
+    }
+    Err(E_WOULD_BLOCK)
+}
+
+/// Slot of the first member of `pid`'s set in `set_slot` whose next message
+/// does not fit the given buffers
+fn oversized_member(
+    state: &KernelState,
+    pid: u32,
+    set_slot: u32,
+    capacity: usize,
+    ool_capacity: &[usize],
+) -> Option<u32> {
+    let (set_idx, _) = lookup_port_set(state, pid, set_slot).ok()?;
+    state.port_sets[set_idx]
+        .members
+        .iter()
+        .filter(|m| m.kind == CapabilityKind::Port)
+        .find(|m| match state.ports.iter().position(|p| p.id == m.object_id) {
+            Some(idx) => !state.ports[idx].is_empty() && !front_fits(state, idx, capacity, ool_capacity),
+            None => false,
+        })
+        .map(|m| m.slot)
+}
+
+/// Whether the message at the head of `state.ports[idx]` fits a buffer of
+/// `capacity` words and out-of-line buffers of `ool_capacity` bytes each
+fn front_fits(state: &KernelState, idx: usize, capacity: usize, ool_capacity: &[usize]) -> bool {
+    let port = &state.ports[idx];
+    if port.front_len().is_some_and(|l| l > capacity) {
+        return false;
+    }
+
+    let ool_id = port.front_meta().map_or(0, |m| m.ool_id);
+    match state.ool_in_transit.iter().find(|p| ool_id != 0 && p.id == ool_id) {
+        Some(payload) => {
+            payload.buffers.len() <= ool_capacity.len()
+                && payload.buffers.iter().zip(ool_capacity).all(|(b, &space)| b.len() <= space)
+        }
+        None => true,
+    }
+}
+
+/// Dequeue the next message of `state.ports[idx]` for `receiver_pid`,
+/// delivering its capability and out-of-line buffers and minting a reply
+/// capability for calls
+///
+/// Fails with `E_NOMEM`, leaving the message queued, if the receiver's
+/// CSpace has no room for those capabilities: a call whose reply capability
+/// was lost could never be answered.
+fn pop_received(
+    state: &mut KernelState,
+    idx: usize,
+    receiver_pid: u32,
+    port_slot: u32,
+) -> Result<Option<Received>, u64> {
+    let needed = state.ports[idx]
+        .front_meta()
+        .map_or(0, |m| (m.caller_pid != 0) as usize + (m.cap_id != 0) as usize);
+    if let Some(p) = state.process_mut(receiver_pid) {
+        if !p.cspace.reserve(needed) {
+            return Err(E_NOMEM);
+        }
+    }
+
+    let (msg, meta) = match state.ports[idx].pop_message_with_meta() {
+        Some(popped) => popped,
+        None => return Ok(None),
+    };
+    let ool = take_ool(state, meta.ool_id);
+
+    let mut info = MessageInfo {
+        reply_cap: 0,
+        sender_pid: meta.sender_pid as u64,
+        badge: meta.badge,
+        cap: deliver_capability(state, meta.cap_id, receiver_pid),
+        port: port_slot as u64,
+    };
+
+    if meta.caller_pid != 0 {
+        let mut cap_id = NEXT_CAP_ID.lock();
+        let reply_cap = Capability::reply(*cap_id, receiver_pid, meta.caller_pid);
+        *cap_id += 1;
+
+        if let Some(slot) = state.process_mut(receiver_pid).and_then(|p| p.cspace.insert(reply_cap)) {
+            info.reply_cap = slot as u64;
+        }
+    }
+
+    Ok(Some((msg, info, ool)))
+}
+
+/// Send a request to a port and put the caller to sleep awaiting the reply
+///
+/// `reply_len` is the size of the caller's reply buffer in u64 words; a
+/// longer reply is refused. The request may carry out-of-line buffers as
+/// with port_send. Returns `E_OK` once the request is queued; the syscall
+/// layer then blocks and collects the answer with `take_reply`.
+pub fn port_call(
+    port_slot: u32,
+    msg_ptr: *const u64,
+    len: usize,
+    reply_len: usize,
+    ool_ptr: *const OolBuffers,
+) -> u64 {
+    if len == 0 || len > PORT_MAX_MSG_LEN as usize || reply_len == 0 {
+        return E_INVAL;
+    }
+
+    let (msg, ool) = match read_user_request(msg_ptr, len, ool_ptr) {
+        Ok(request) => request,
+        Err(e) => return e,
+    };
+
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    let result = enqueue_message(&mut state, port_slot, &msg, current_pid, CapTransfer::default(), ool);
+    if result != E_OK {
+        return result;
+    }
+
+    // Sleep until port_reply delivers the answer
+    if let Some(p) = state.process_mut(current_pid) {
+        p.reply_msg = None;
+        p.reply_capacity = reply_len;
+        p.call_error = E_OK;
+        p.state = ProcessState::Sleeping;
+    }
+
+    E_OK
+}
+
+/// Answer a port_call through a one-shot reply capability, optionally
+/// handing the caller a capability
+pub fn port_reply(reply_slot: u32, msg_ptr: *const u64, len: usize, xfer: CapTransfer) -> u64 {
+    if len == 0 || len > PORT_MAX_MSG_LEN as usize {
+        return E_INVAL;
+    }
+
+    let msg = match current_user_space().and_then(|space| space.read_words(msg_ptr as u64, len)) {
+        Ok(msg) => msg,
+        Err(e) => return e,
+    };
+
+    deliver_reply(&mut kernel_state_mut(), reply_slot, msg, xfer)
+}
+
+/// Hand `msg` to the caller behind the current process's reply capability
+/// in `reply_slot`
+///
+/// A caller that died or otherwise stopped waiting is not woken: the reply
+/// is dropped and the capability spent, as if the caller were gone.
+fn deliver_reply(state: &mut KernelState, reply_slot: u32, msg: Vec<u64>, xfer: CapTransfer) -> u64 {
+    let current_pid = state.current_process_id;
+
+    // Find reply capability
+    let cap = match state.lookup_cap(current_pid, reply_slot) {
+        Some(c) if c.kind == CapabilityKind::Reply => c,
+        _ => return E_CAP_INVALID,
+    };
+
+    let reply_capacity = match pending_caller(state, cap.target_id) {
+        Some(caller) if !cap.revoked => caller.reply_capacity,
+        _ => {
+            // Caller is gone: the one-shot capability is spent
+            if let Some(p) = state.process_mut(current_pid) {
+                p.cspace.remove(reply_slot);
+            }
+            return if cap.revoked { E_CAP_INVALID } else { E_PROCESS_NOT_FOUND };
+        }
+    };
+
+    // The reply must fit the caller's buffer; the capability stays usable
+    if msg.len() > reply_capacity {
+        return E_INVAL;
+    }
+
+    let cap_id = match attach_capability(state, &xfer) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    // One-shot: consume the reply capability
+    if let Some(p) = state.process_mut(current_pid) {
+        p.cspace.remove(reply_slot);
+    }
+
+    let meta = MessageMeta {
+        sender_pid: current_pid,
+        cap_id,
+        ..MessageMeta::default()
+    };
+
+    if let Some(caller) = pending_caller(state, cap.target_id) {
+        caller.reply_msg = Some((msg, meta));
+        caller.reply_capacity = 0;
+        caller.state = ProcessState::Ready;
+    }
+
+    E_OK
+}
+
+/// `pid`, if it is still asleep in a call waiting for the answer
+///
+/// Woken callers have their `reply_capacity` cleared, so a process that
+/// sleeps again for something else is not mistaken for one.
+fn pending_caller(state: &mut KernelState, pid: u32) -> Option<&mut ProcessDescriptor> {
+    state
+        .process_mut(pid)
+        .filter(|p| p.state == ProcessState::Sleeping && p.reply_capacity != 0)
+}
+
+/// Copy the reply delivered to the current process into `buf_ptr`, and its
+/// out-of-band information into `info_ptr` (may be null)
+///
+/// If the call failed before being answered (e.g. the port was destroyed
+/// with the request still queued), the recorded error is returned instead.
+/// Buffers outside the caller's memory fail with `E_FAULT` and the reply is
+/// lost.
+pub fn take_reply(buf_ptr: *mut u64, info_ptr: *mut MessageInfo) -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    let (reply, error) = match state.process_mut(current_pid) {
+        Some(p) => (p.reply_msg.take(), core::mem::replace(&mut p.call_error, E_OK)),
+        None => return E_PROCESS_NOT_FOUND,
+    };
+
+    match reply {
+        Some((msg, meta)) => {
+            let info = MessageInfo {
+                reply_cap: 0,
+                sender_pid: meta.sender_pid as u64,
+                badge: meta.badge,
+                cap: deliver_capability(&mut state, meta.cap_id, current_pid),
+                port: 0,
+            };
+
+            let written = user_space(&state, current_pid).and_then(|space| {
+                space.write_words(buf_ptr as u64, &msg)?;
+                write_user_info(&space, info_ptr, &info)
+            });
+
+            match written {
+                Ok(()) => msg.len() as u64,  // u64 words received
+                Err(e) => e,
+            }
+        }
+        None if error != E_OK => error,
+        None => E_PORT_INVALID,
+    }
+}
+
+/// Queue `msg` on port `port_id` as a plain message from the kernel
+///
+/// The message is stamped with sender_pid 0 and `badge`. Fails like
+/// kernel_call, without putting anyone to sleep.
+pub fn kernel_send(state: &mut KernelState, port_id: u32, badge: u64, msg: &[u64]) -> u64 {
+    let idx = match state.ports.iter().position(|p| p.id == port_id) {
+        Some(i) => i,
+        None => return E_PORT_DEAD,
+    };
+
+    if msg.len() > state.ports[idx].max_msg_len as usize {
+        return E_INVAL;
+    }
+
+    let meta = MessageMeta {
+        badge,
+        ..MessageMeta::default()
+    };
+
+    if !post_message(state, idx, msg, meta) {
+        return E_PORT_FULL;
+    }
+    E_OK
+}
+
+/// Queue `msg` on port `port_id` as a call made by the kernel on behalf
+/// of process `pid`, and put `pid` to sleep awaiting the reply
+///
+/// Used to hand events such as page faults to userspace servers. The
+/// message is stamped with sender_pid 0 and `badge`; the receiver gets a
+/// reply capability as for port_call, and may answer with up to
+/// `reply_len` words. Collect the answer with take_kernel_reply. Fails
+/// with `E_PORT_DEAD` if the port is gone, `E_INVAL` if the message is too
+/// long for it and `E_PORT_FULL` if its queue is full.
+pub fn kernel_call(
+    state: &mut KernelState,
+    port_id: u32,
+    badge: u64,
+    pid: u32,
+    msg: &[u64],
+    reply_len: usize,
+) -> u64 {
+    let idx = match state.ports.iter().position(|p| p.id == port_id) {
+        Some(i) => i,
+        None => return E_PORT_DEAD,
+    };
+
+    if msg.len() > state.ports[idx].max_msg_len as usize {
+        return E_INVAL;
+    }
+
+    let meta = MessageMeta {
+        badge,
+        caller_pid: pid,
+        ..MessageMeta::default()
+    };
+
+    if !post_message(state, idx, msg, meta) {
+        return E_PORT_FULL;
+    }
+
+    if let Some(p) = state.process_mut(pid) {
+        p.reply_msg = None;
+        p.reply_capacity = reply_len;
+        p.call_error = E_OK;
+        p.state = ProcessState::Sleeping;
+    }
+
+    E_OK
+}
+
+/// Take the reply to a kernel_call made for `pid`
+///
+/// A capability carried by the reply is installed in `pid`'s CSpace.
+/// Returns the reply and the capability's slot (0 if none), or the error
+/// recorded if the call failed unanswered.
+pub fn take_kernel_reply(state: &mut KernelState, pid: u32) -> Result<(Vec<u64>, u64), u64> {
+    let (reply, error) = match state.process_mut(pid) {
+        Some(p) => (p.reply_msg.take(), core::mem::replace(&mut p.call_error, E_OK)),
+        None => return Err(E_PROCESS_NOT_FOUND),
+    };
+
+    match reply {
+        Some((msg, meta)) => {
+            let slot = deliver_capability(state, meta.cap_id, pid);
+            Ok((msg, slot))
+        }
+        None if error != E_OK => Err(error),
+        None => Err(E_PORT_INVALID),
+    }
+}
+
+/// Destroy a port, port set, notification or memory object (requires
+/// `CAP_DESTROY`)
+///
+/// A memory object is unmapped from every process and its memory freed
+/// (see vm::destroy_memory_object). Destroying a set or notification
+/// releases it from (or its members from) any set and wakes its sleeping
+/// receivers, whose retry fails with `E_PORT_DEAD`. For a port, queued
+/// messages are discarded along with any capabilities and out-of-line data
+/// they carry, callers still waiting in port_call get `E_PORT_DEAD`, and
+/// sleeping receivers are woken so their retry fails the same way.
+/// Capabilities for the port stay in their CSpaces but every later use
+/// reports `E_PORT_DEAD`. Finally each registered watcher is sent
+/// `[MSG_PORT_DIED, cookie]`.
+pub fn port_destroy(port_slot: u32) -> u64 {
+    let mut state = kernel_state_mut();
+    destroy_port(&mut state, port_slot)
+}
+
+fn destroy_port(state: &mut KernelState, port_slot: u32) -> u64 {
+    let current_pid = state.current_process_id;
+
+    match state.lookup_cap(current_pid, port_slot).map(|c| c.kind) {
+        Some(CapabilityKind::PortSet) => return destroy_port_set(state, port_slot),
+        Some(CapabilityKind::Notification) => return destroy_notification(state, port_slot),
+        Some(CapabilityKind::MemoryObject) => return crate::vm::destroy_memory_object(state, port_slot),
+        _ => {}
+    }
+
+    let (idx, _) = match lookup_port(state, current_pid, port_slot, CAP_DESTROY) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    let mut port = state.ports.remove(idx);
+
+    if port.set_id != 0 {
+        if let Some(set) = state.port_sets.iter_mut().find(|s| s.id == port.set_id) {
+            set.remove_member(CapabilityKind::Port, port.id);
+        }
+    }
+
+    while let Some((_, meta)) = port.pop_message_with_meta() {
+        if meta.cap_id != 0 {
+            state.in_transit.retain(|c| c.id != meta.cap_id);
+        }
+        if meta.ool_id != 0 {
+            state.ool_in_transit.retain(|p| p.id != meta.ool_id);
+        }
+
+        if meta.caller_pid != 0 {
+            if let Some(caller) = pending_caller(state, meta.caller_pid) {
+                caller.call_error = E_PORT_DEAD;
+                caller.reply_capacity = 0;
+                caller.state = ProcessState::Ready;
+            }
+        }
+    }
+
+    for pid in port.waiters.drain(..) {
+        state.set_process_state(pid, ProcessState::Ready);
+    }
+
+    for watch in port.watchers.iter() {
+        let msg = [MSG_PORT_DIED, watch.cookie];
+        let meta = MessageMeta {
+            badge: watch.badge,
+            ..MessageMeta::default()
+        };
+
+        // Notifications are best effort: dropped if the watcher's port is
+        // gone, full or takes shorter messages
+        if let Some(i) = state.ports.iter().position(|p| p.id == watch.notify_port) {
+            post_message(state, i, &msg, meta);
+        }
+    }
+
+    E_OK
+}
+
+fn destroy_port_set(state: &mut KernelState, set_slot: u32) -> u64 {
+    let current_pid = state.current_process_id;
+
+    let (set_idx, cap) = match lookup_port_set(state, current_pid, set_slot) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    if !cap.has_right(CAP_DESTROY) {
+        return E_NO_RIGHTS;
+    }
+
+    let mut set = state.port_sets.remove(set_idx);
+
+    for member in set.members.iter() {
+        set_member_set_id(state, member, 0);
+    }
+
+    for pid in set.waiters.drain(..) {
+        state.set_process_state(pid, ProcessState::Ready);
+    }
+
+    E_OK
+}
+
+fn destroy_notification(state: &mut KernelState, notify_slot: u32) -> u64 {
+    let current_pid = state.current_process_id;
+
+    let (idx, _) = match lookup_notification(state, current_pid, notify_slot, CAP_DESTROY) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    let mut n = state.notifications.remove(idx);
+
+    if n.set_id != 0 {
+        if let Some(set) = state.port_sets.iter_mut().find(|s| s.id == n.set_id) {
+            set.remove_member(CapabilityKind::Notification, n.id);
+        }
+    }
+
+    for pid in n.waiters.drain(..) {
+        state.set_process_state(pid, ProcessState::Ready);
+    }
+
+    E_OK
+}
+
+/// Ask for `[MSG_PORT_DIED, cookie]` on `notify_slot` when the port in
+/// `port_slot` is destroyed
+///
+/// Both capabilities need `CAP_SEND`. The notification carries the badge of
+/// the capability used for `notify_slot`. Fails with `E_NOMEM` once the
+/// port has PORT_MAX_WATCHERS watchers or the caller has registered
+/// PROCESS_MAX_WATCHES watches.
+pub fn port_watch(port_slot: u32, notify_slot: u32, cookie: u64) -> u64 {
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+    watch_port(&mut state, current_pid, port_slot, notify_slot, cookie)
+}
+
+fn watch_port(state: &mut KernelState, current_pid: u32, port_slot: u32, notify_slot: u32, cookie: u64) -> u64 {
+    let (idx, _) = match lookup_port(state, current_pid, port_slot, CAP_SEND) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    let (notify_idx, notify_cap) = match lookup_port(state, current_pid, notify_slot, CAP_SEND) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    // Watches live as long as the port, so each one is kernel heap the
+    // caller could otherwise grow without end
+    let watches = state.ports.iter().flat_map(|p| &p.watchers).filter(|w| w.pid == current_pid).count();
+    if state.ports[idx].watchers.len() >= PORT_MAX_WATCHERS || watches >= PROCESS_MAX_WATCHES {
+        return E_NOMEM;
+    }
+    if let Err(e) = reserve_one(&mut state.ports[idx].watchers) {
+        return e;
+    }
+
+    let watch = PortWatch {
+        pid: current_pid,
+        notify_port: state.ports[notify_idx].id,
+        badge: notify_cap.badge,
+        cookie,
+    };
+    state.ports[idx].watchers.push(watch);
+
+    E_OK
+}
+
+/// Queue a message on a port on behalf of the current process
+///
+/// The sender's PID and the badge of the capability used are stamped into
+/// the message metadata; `caller_pid` is non-zero for port_call requests.
+/// `ool` holds the out-of-line buffers already copied from the sender.
+fn enqueue_message(
+    state: &mut KernelState,
+    port_slot: u32,
+    msg: &[u64],
+    caller_pid: u32,
+    xfer: CapTransfer,
+    ool: Vec<Vec<u8>>,
+) -> u64 {
+    let current_pid = state.current_process_id;
+
+    // Check if sender has capability with SEND right
+    let (idx, cap) = match lookup_port(state, current_pid, port_slot, CAP_SEND) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    if msg.len() > state.ports[idx].max_msg_len as usize {
+        return E_INVAL;
+    }
+
+    // Check if port queue is full
+    if state.ports[idx].is_full() {
+        return E_PORT_FULL;
+    }
+
+    // Make room in the transit tables, so nothing below fails for memory
+    if let Err(e) = reserve_one(&mut state.in_transit).and_then(|_| reserve_one(&mut state.ool_in_transit)) {
+        return e;
+    }
+
+    // Detach the carried capability only once the send is certain to succeed
+    let cap_id = match attach_capability(state, &xfer) {
+        Ok(id) => id,
+        Err(e) => return e,
+    };
+
+    let meta = MessageMeta {
+        sender_pid: current_pid,
+        badge: cap.badge,
+        caller_pid,
+        cap_id,
+        ool_id: attach_ool(state, ool),
+    };
+
+    if !post_message(state, idx, msg, meta) {
+        return E_PORT_FULL;
+    }
+
+    E_OK
+}
+
+/// Queue a message on `state.ports[idx]` and wake the first receiver
+/// sleeping on it, or else on its port set. Returns false if the queue is
+/// full or the message too long for the port.
+fn post_message(state: &mut KernelState, idx: usize, msg: &[u64], meta: MessageMeta) -> bool {
+    let port = &mut state.ports[idx];
 
-    let port_id = *next_id;
-    *next_id += 1;
+    // Push message
+    if !port.push_message_with_meta(msg, meta) {
+        return false;
+    }
+
+    // Wake the first receiver sleeping on this port
+    let set_id = port.set_id;
+    let waiter = port.take_waiter();
+    wake_receiver(state, waiter, set_id);
+
+    true
+}
+
+/// Wake `waiter`, or failing that the first receiver sleeping on port set
+/// `set_id` (0 = not in a set)
+fn wake_receiver(state: &mut KernelState, waiter: Option<u32>, set_id: u32) {
+    let waiter = match waiter {
+        Some(pid) => Some(pid),
+        None if set_id != 0 => state
+            .port_sets
+            .iter_mut()
+            .find(|s| s.id == set_id)
+            .and_then(|s| s.take_waiter()),
+        None => None,
+    };
+
+    if let Some(pid) = waiter {
+        state.set_process_state(pid, ProcessState::Ready);
+    }
+}
+
+/// Put the capability described by `xfer` in transit
+///
+/// The rights passed on must be a subset of the sender's, the same rule
+/// cap_move applies. With `IPC_CAP_MOVE` the sender's capability itself is
+/// handed over; otherwise a child carrying the same badge is minted, which
+/// requires `CAP_DERIVE`. Returns
+/// the kernel ID of the in-transit capability, or 0 if nothing is attached.
+fn attach_capability(state: &mut KernelState, xfer: &CapTransfer) -> Result<u32, u64> {
+    if xfer.cap_slot == 0 {
+        return Ok(0);
+    }
 
     let current_pid = state.current_process_id;
-    let port = Port::new(port_id, current_pid);
 
-    state.ports.push(port);
+    let src_cap = match state.lookup_cap(current_pid, xfer.cap_slot) {
+        Some(c) if !c.revoked && c.kind != CapabilityKind::Reply => c,
+        _ => return Err(E_CAP_INVALID),
+    };
 
-    // Allocate capability for this port
-    let mut cap_id = NEXT_CAP_ID.lock();
-    let capability = Capability::new(
-        *cap_id,
-        current_pid,
-        port_id,
-        CAP_SEND | CAP_RECEIVE | CAP_DESTROY,
-    );
-    *cap_id += 1;
+    // Verify requested rights are subset of current rights
+    if (xfer.rights & src_cap.rights) != xfer.rights {
+        return Err(E_NO_RIGHTS);
+    }
 
-    state.capabilities.push(capability);
+    let cap = if (xfer.flags & IPC_CAP_MOVE) != 0 {
+        if let Some(p) = state.process_mut(current_pid) {
+            p.cspace.remove(xfer.cap_slot);
+        }
+        leave_port_sets(state, current_pid, xfer.cap_slot, &src_cap);
+        Capability { rights: xfer.rights, ..src_cap }
+    } else {
+        if !src_cap.has_right(CAP_DERIVE) {
+            return Err(E_NO_RIGHTS);
+        }
+        let mut cap_id = NEXT_CAP_ID.lock();
+        let child = src_cap.derive(*cap_id, current_pid, xfer.rights);
+        *cap_id += 1;
+        child
+    };
 
-    port_id as u64
+    state.in_transit.push(cap);
+    Ok(cap.id)
 }
 
-/// Send a message to a port
-pub fn port_send(port_id: u32, msg_ptr: *const u64, len: usize) -> u64 {
-    if len != 8 {
-        return E_INVAL;
+/// Drop the port or notification `cap` from `pid`'s sets if it was added
+/// under `slot`, which `pid` no longer holds
+fn leave_port_sets(state: &mut KernelState, pid: u32, slot: u32, cap: &Capability) {
+    if !matches!(cap.kind, CapabilityKind::Port | CapabilityKind::Notification) {
+        return;
     }
+    let member = PortSetMember { kind: cap.kind, object_id: cap.target_id, slot };
 
-    // Read message from user space (assume valid for now)
-    let msg = unsafe {
-        [
-            *msg_ptr,
-            *msg_ptr.add(1),
-            *msg_ptr.add(2),
-            *msg_ptr.add(3),
-            *msg_ptr.add(4),
-            *msg_ptr.add(5),
-            *msg_ptr.add(6),
-            *msg_ptr.add(7),
-        ]
-    };
+    let mut left = false;
+    for set in state.port_sets.iter_mut().filter(|s| s.owner_pid == pid && s.members.contains(&member)) {
+        left |= set.remove_member(member.kind, member.object_id);
+    }
+    if left {
+        set_member_set_id(state, &member, 0);
+    }
+}
 
-    let mut state = kernel_state_mut();
-    let current_pid = state.current_process_id;
+/// Hand an in-transit capability to its receiver
+///
+/// Returns the receiver's slot for the capability, or 0 if nothing was
+/// carried, the capability was revoked in transit or the CSpace is full.
+fn deliver_capability(state: &mut KernelState, cap_id: u32, receiver_pid: u32) -> u64 {
+    if cap_id == 0 {
+        return 0;
+    }
 
-    // Find port
-    let port = match state.ports.iter_mut().find(|p| p.id == port_id) {
-        Some(p) => p,
-        None => return E_PORT_INVALID,
+    let idx = match state.in_transit.iter().position(|c| c.id == cap_id) {
+        Some(i) => i,
+        None => return 0,
     };
 
-    // Check if sender has capability with SEND right
-    if !has_capability(current_pid, port_id, CAP_SEND, &state) {
-        return E_NO_RIGHTS;
+    let mut cap = state.in_transit.remove(idx);
+    if cap.revoked {
+        return 0;
     }
 
-    // Check if port queue is full
-    if port.is_full() {
-        return E_PORT_FULL;
+    cap.owner_pid = receiver_pid;
+    match state.process_mut(receiver_pid).and_then(|p| p.cspace.insert(cap)) {
+        Some(slot) => slot as u64,
+        None => 0,
     }
+}
 
-    // Push message
-    if port.push_message(&msg) {
-        E_OK
-    } else {
-        E_PORT_FULL
+/// Put out-of-line buffers copied from a sender in transit
+///
+/// Returns the payload ID to record in the message metadata, or 0 if there
+/// are no buffers.
+fn attach_ool(state: &mut KernelState, buffers: Vec<Vec<u8>>) -> u32 {
+    if buffers.is_empty() {
+        return 0;
+    }
+
+    let mut next_id = NEXT_OOL_ID.lock();
+    let id = *next_id;
+    *next_id += 1;
+
+    state.ool_in_transit.push(OolPayload { id, buffers });
+    id
+}
+
+/// Take the out-of-line buffers of a received message out of transit
+fn take_ool(state: &mut KernelState, ool_id: u32) -> Vec<Vec<u8>> {
+    if ool_id == 0 {
+        return Vec::new();
+    }
+
+    match state.ool_in_transit.iter().position(|p| p.id == ool_id) {
+        Some(i) => state.ool_in_transit.remove(i).buffers,
+        None => Vec::new(),
     }
 }
 
-/// Receive a message from a port
-pub fn port_receive(port_id: u32, buf_ptr: *mut u64, len: usize) -> u64 {
-    if len != 8 {
-        return E_INVAL;
+/// Read an OolBuffers from user memory; a null pointer means no buffers
+fn read_user_ool_buffers(space: &UserSpace, ool_ptr: *const OolBuffers) -> Result<OolBuffers, u64> {
+    if ool_ptr.is_null() {
+        return Ok(OolBuffers::default());
     }
 
-    let mut state = kernel_state_mut();
-    let current_pid = state.current_process_id;
+    let ool: OolBuffers = space.read(ool_ptr as u64)?;
+    if ool.count > IPC_MAX_OOL as u64 {
+        return Err(E_INVAL);
+    }
+    Ok(ool)
+}
 
-    // Find port
-    let port = match state.ports.iter_mut().find(|p| p.id == port_id) {
-        Some(p) => p,
-        None => return E_PORT_INVALID,
-    };
+/// Copy the out-of-line buffers described by `ool_ptr` (may be null) from
+/// user memory
+///
+/// Together they may hold at most `IPC_MAX_OOL_BYTES`.
+fn read_user_ool(space: &UserSpace, ool_ptr: *const OolBuffers) -> Result<Vec<Vec<u8>>, u64> {
+    let ool = read_user_ool_buffers(space, ool_ptr)?;
 
-    // Check if receiver has capability with RECEIVE right
-    if !has_capability(current_pid, port_id, CAP_RECEIVE, &state) {
-        return E_NO_RIGHTS;
+    let total = ool.used().iter().try_fold(0u64, |sum, d| sum.checked_add(d.len));
+    if total.is_none_or(|t| t > IPC_MAX_OOL_BYTES as u64) {
+        return Err(E_INVAL);
     }
 
-    // Pop message from queue
-    match port.pop_message() {
-        Some(msg) => {
-            // Write message to user space (assume valid for now)
-            unsafe {
-                *buf_ptr = msg[0];
-                *buf_ptr.add(1) = msg[1];
-                *buf_ptr.add(2) = msg[2];
-                *buf_ptr.add(3) = msg[3];
-                *buf_ptr.add(4) = msg[4];
-                *buf_ptr.add(5) = msg[5];
-                *buf_ptr.add(6) = msg[6];
-                *buf_ptr.add(7) = msg[7];
-            }
-            8  // 8 u64s received
-        }
-        None => {
-            // No message available - for now return error (future: block)
-            E_PORT_INVALID  // TODO: Block process
-        }
+    ool.used()
+        .iter()
+        .map(|d| {
+            let mut buf = try_vec(0u8, d.len as usize)?;
+            space.copy_from_user(&mut buf, d.addr)?;
+            Ok(buf)
+        })
+        .collect()
+}
+
+/// Copy received out-of-line buffers into the receiver's `ool_space` and
+/// report how many arrived and their lengths
+///
+/// front_fits already checked that every buffer fits.
+fn write_user_ool(
+    space: &UserSpace,
+    ool_ptr: *mut OolBuffers,
+    mut ool_space: OolBuffers,
+    buffers: &[Vec<u8>],
+) -> Result<(), u64> {
+    if ool_ptr.is_null() {
+        return Ok(());
+    }
+
+    for (d, buf) in ool_space.desc.iter_mut().zip(buffers) {
+        space.copy_to_user(d.addr, buf)?;
+        d.len = buf.len() as u64;
+    }
+    ool_space.count = buffers.len() as u64;
+    space.write(ool_ptr as u64, &ool_space)
+}
+
+/// Write message info to user memory if requested
+fn write_user_info(space: &UserSpace, info_ptr: *mut MessageInfo, info: &MessageInfo) -> Result<(), u64> {
+    if info_ptr.is_null() {
+        return Ok(());
     }
+    space.write(info_ptr as u64, info)
 }
 
-/// Move (transfer) a capability from one process to another
-pub fn cap_move(src_cap_id: u32, dst_pid: u32, rights: u32) -> u64 {
+/// Copy a capability into another process's CSpace
+///
+/// The copy is a child of the source capability in the derivation tree and
+/// needs `CAP_DERIVE` on the source. A non-zero `badge` is stamped into the
+/// new capability and delivered with every message sent through it.
+/// Badged capabilities cannot be re-badged; copies of them inherit the
+/// original badge. Returns the destination slot.
+pub fn cap_move(src_slot: u32, dst_pid: u32, rights: u32, badge: u64) -> u64 {
     let mut state = kernel_state_mut();
     let current_pid = state.current_process_id;
 
-    // Find source capability
-    let src_cap = match state.capabilities.iter().find(|c| c.id == src_cap_id) {
-        Some(c) => *c,
+    // Find source capability in the caller's CSpace
+    let src_cap = match state.lookup_cap(current_pid, src_slot) {
+        Some(c) => c,
         None => return E_CAP_INVALID,
     };
 
-    // Verify current process owns the capability
-    if src_cap.owner_pid != current_pid {
-        return E_NO_RIGHTS;
-    }
-
     // Verify capability is not revoked
     if src_cap.revoked {
         return E_CAP_INVALID;
     }
 
+    // Reply capabilities are one-shot and cannot be duplicated
+    if src_cap.kind == CapabilityKind::Reply {
+        return E_NO_RIGHTS;
+    }
+
+    // Only holders of CAP_DERIVE may mint children
+    if !src_cap.has_right(CAP_DERIVE) {
+        return E_NO_RIGHTS;
+    }
+
     // Verify requested rights are subset of current rights
     if (rights & src_cap.rights) != rights {
         return E_NO_RIGHTS;
     }
 
+    // A badge can only be set once
+    if badge != 0 && src_cap.badge != 0 {
+        return E_NO_RIGHTS;
+    }
+
     // Verify destination process exists
     if !state.processes.iter().any(|p| p.id == dst_pid) {
         return E_PROCESS_NOT_FOUND;
@@ -156,50 +1439,101 @@ pub fn cap_move(src_cap_id: u32, dst_pid: u32, rights: u32) -> u64 {
 
     // Create new capability for destination process
     let mut cap_id = NEXT_CAP_ID.lock();
-    let new_cap = Capability::new(*cap_id, dst_pid, src_cap.target_id, rights);
+    let mut new_cap = src_cap.derive(*cap_id, dst_pid, rights);
+    if badge != 0 {
+        new_cap.badge = badge;
+    }
     *cap_id += 1;
 
-    state.capabilities.push(new_cap);
-
-    E_OK
+    match state.process_mut(dst_pid).and_then(|p| p.cspace.insert(new_cap)) {
+        Some(slot) => slot as u64,
+        None => E_NOMEM,
+    }
 }
 
-/// Revoke a capability
-pub fn cap_revoke(cap_id: u32) -> u64 {
+/// Revoke a capability together with every capability derived from it
+///
+/// Memory objects mapped through any of the revoked capabilities are
+/// unmapped from the processes that mapped them.
+pub fn cap_revoke(slot: u32) -> u64 {
     let mut state = kernel_state_mut();
     let current_pid = state.current_process_id;
 
-    // Find capability
-    let cap = match state.capabilities.iter_mut().find(|c| c.id == cap_id) {
-        Some(c) => c,
+    // Only capabilities in the caller's own CSpace can be named
+    let cap_id = match state.lookup_cap(current_pid, slot) {
+        Some(c) => c.id,
         None => return E_CAP_INVALID,
     };
 
-    // Verify current process owns the capability
-    if cap.owner_pid != current_pid {
-        return E_NO_RIGHTS;
-    }
-
-    cap.revoke();
+    state.revoke_tree(cap_id);
+    crate::vm::unmap_revoked(&mut state);
     E_OK
 }
 
-/// Check if a process has a specific capability with required rights
-fn has_capability(
+/// Resolve a port capability slot of `pid` that carries `required_right`
+///
+/// Returns the index of the port in `state.ports` and the capability used.
+fn lookup_port(
+    state: &KernelState,
     pid: u32,
-    target_id: u32,
+    slot: u32,
     required_right: u32,
+) -> Result<(usize, Capability), u64> {
+    let cap = match state.lookup_cap(pid, slot) {
+        Some(c) if !c.revoked && c.kind == CapabilityKind::Port => c,
+        _ => return Err(E_CAP_INVALID),
+    };
+
+    if !cap.has_right(required_right) {
+        return Err(E_NO_RIGHTS);
+    }
+
+    // Port IDs are never reused, so a missing port was destroyed
+    match state.ports.iter().position(|p| p.id == cap.target_id) {
+        Some(idx) => Ok((idx, cap)),
+        None => Err(E_PORT_DEAD),
+    }
+}
+
+/// Find a port set by slot in `pid`'s CSpace, checking `CAP_RECEIVE`
+fn lookup_port_set(state: &KernelState, pid: u32, slot: u32) -> Result<(usize, Capability), u64> {
+    let cap = match state.lookup_cap(pid, slot) {
+        Some(c) if !c.revoked && c.kind == CapabilityKind::PortSet => c,
+        _ => return Err(E_CAP_INVALID),
+    };
+
+    if !cap.has_right(CAP_RECEIVE) {
+        return Err(E_NO_RIGHTS);
+    }
+
+    // Set IDs are never reused, so a missing set was destroyed
+    match state.port_sets.iter().position(|s| s.id == cap.target_id) {
+        Some(idx) => Ok((idx, cap)),
+        None => Err(E_PORT_DEAD),
+    }
+}
+
+/// Find a notification by slot in `pid`'s CSpace, checking `required_right`
+pub fn lookup_notification(
     state: &KernelState,
-) -> bool {
-    state
-        .capabilities
-        .iter()
-        .any(|c| {
-            c.owner_pid == pid
-                && c.target_id == target_id
-                && !c.revoked
-                && (c.rights & required_right) != 0
-        })
+    pid: u32,
+    slot: u32,
+    required_right: u32,
+) -> Result<(usize, Capability), u64> {
+    let cap = match state.lookup_cap(pid, slot) {
+        Some(c) if !c.revoked && c.kind == CapabilityKind::Notification => c,
+        _ => return Err(E_CAP_INVALID),
+    };
+
+    if !cap.has_right(required_right) {
+        return Err(E_NO_RIGHTS);
+    }
+
+    // Notification IDs are never reused, so a missing one was destroyed
+    match state.notifications.iter().position(|n| n.id == cap.target_id) {
+        Some(idx) => Ok((idx, cap)),
+        None => Err(E_PORT_DEAD),
+    }
 }
 
 #[cfg(test)]
@@ -211,5 +1545,465 @@ mod tests {
         // This would require mocking the global state
         // Actual tests would be in integration tests
     }
-}
 
+    fn state_with_cap(pid: u32, port_id: u32, rights: u32) -> (KernelState, u32) {
+        let mut state = KernelState::new();
+        state.current_process_id = pid;
+        state.processes.push(ProcessDescriptor::new(pid, 0x1000, 0x2000));
+        state.processes.push(ProcessDescriptor::new(2, 0x1000, 0x2000));
+        let slot = state
+            .process_mut(pid)
+            .unwrap()
+            .cspace
+            .insert(Capability::new(100, pid, port_id, rights))
+            .unwrap();
+        (state, slot)
+    }
+
+    #[test]
+    fn test_cap_transfer_copy() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_RECEIVE | CAP_DERIVE);
+        let xfer = CapTransfer { cap_slot: slot, rights: CAP_SEND, flags: 0 };
+
+        let cap_id = attach_capability(&mut state, &xfer).unwrap();
+        assert_ne!(cap_id, 100, "Copy must mint a new capability");
+        assert!(state.lookup_cap(1, slot).is_some(), "Sender keeps its capability");
+
+        let received = deliver_capability(&mut state, cap_id, 2) as u32;
+        assert_ne!(received, 0);
+        let cap = state.lookup_cap(2, received).unwrap();
+        assert_eq!(cap.owner_pid, 2);
+        assert_eq!(cap.target_id, 7);
+        assert_eq!(cap.rights, CAP_SEND);
+        assert_eq!(cap.parent, 100, "Copy is a child of the sender's capability");
+        assert!(state.in_transit.is_empty());
+    }
+
+    #[test]
+    fn test_cap_transfer_copy_needs_derive() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND);
+        let xfer = CapTransfer { cap_slot: slot, rights: CAP_SEND, flags: 0 };
+        assert_eq!(attach_capability(&mut state, &xfer), Err(E_NO_RIGHTS));
+
+        // Moving does not mint anything, so it needs no CAP_DERIVE
+        let xfer = CapTransfer { cap_slot: slot, rights: CAP_SEND, flags: IPC_CAP_MOVE };
+        assert_eq!(attach_capability(&mut state, &xfer), Ok(100));
+    }
+
+    #[test]
+    fn test_cap_transfer_move() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_RECEIVE);
+        let xfer = CapTransfer { cap_slot: slot, rights: CAP_SEND, flags: IPC_CAP_MOVE };
+
+        let cap_id = attach_capability(&mut state, &xfer).unwrap();
+        assert_eq!(cap_id, 100, "Move hands over the sender's capability");
+        assert!(state.lookup_cap(1, slot).is_none(), "Sender loses the capability");
+
+        let received = deliver_capability(&mut state, cap_id, 2) as u32;
+        assert!(lookup_port(&state, 2, received, CAP_SEND).is_err(), "Port 7 does not exist");
+        let cap = state.lookup_cap(2, received).unwrap();
+        assert!(cap.has_right(CAP_SEND));
+        assert!(!cap.has_right(CAP_RECEIVE), "Rights were reduced");
+    }
+
+    #[test]
+    fn test_cap_transfer_rights_must_be_subset() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND);
+        let xfer = CapTransfer { cap_slot: slot, rights: CAP_SEND | CAP_RECEIVE, flags: 0 };
+        assert_eq!(attach_capability(&mut state, &xfer), Err(E_NO_RIGHTS));
+
+        let xfer = CapTransfer { cap_slot: slot + 1, rights: CAP_SEND, flags: 0 };
+        assert_eq!(attach_capability(&mut state, &xfer), Err(E_CAP_INVALID));
+        assert!(state.in_transit.is_empty(), "Failed transfers leave no trace");
+    }
+
+    #[test]
+    fn test_cap_transfer_revoked_in_transit() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_DERIVE);
+        let xfer = CapTransfer { cap_slot: slot, rights: CAP_SEND, flags: 0 };
+
+        // Revoking the sender's capability reaches the copy in the queue
+        let cap_id = attach_capability(&mut state, &xfer).unwrap();
+        assert_eq!(state.revoke_tree(100), 2);
+
+        assert_eq!(deliver_capability(&mut state, cap_id, 2), 0);
+        assert!(state.in_transit.is_empty());
+    }
+
+    #[test]
+    fn test_port_destroy_fails_pending_and_future_sends() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_RECEIVE | CAP_DESTROY | CAP_DERIVE);
+        state.ports.push(Port::new(7, 1));
+
+        // A queued port_call from PID 2 carrying a capability, and a sleeping receiver
+        let xfer = CapTransfer { cap_slot: slot, rights: CAP_SEND, flags: 0 };
+        let cap_id = attach_capability(&mut state, &xfer).unwrap();
+        let meta = MessageMeta { sender_pid: 2, caller_pid: 2, cap_id, ..MessageMeta::default() };
+        assert!(post_message(&mut state, 0, &[1; 8], meta));
+        state.process_mut(2).unwrap().reply_capacity = 8;
+        state.set_process_state(2, ProcessState::Sleeping);
+        state.ports[0].add_waiter(3);
+
+        // A caller killed while waiting stays dead
+        state.processes.push(ProcessDescriptor::new(4, 0x1000, 0x2000));
+        state.set_process_state(4, ProcessState::Dead);
+        let meta = MessageMeta { sender_pid: 4, caller_pid: 4, ..MessageMeta::default() };
+        assert!(post_message(&mut state, 0, &[1; 8], meta));
+
+        assert_eq!(destroy_port(&mut state, slot), E_OK);
+        assert!(state.ports.is_empty());
+        assert!(state.in_transit.is_empty(), "Carried capabilities are dropped");
+
+        let caller = state.process_mut(2).unwrap();
+        assert_eq!(caller.call_error, E_PORT_DEAD);
+        assert_eq!(caller.state, ProcessState::Ready);
+        let dead = state.process_mut(4).unwrap();
+        assert_eq!((dead.state, dead.call_error), (ProcessState::Dead, E_OK));
+
+        // Later uses of the surviving capability report the dead port
+        let msg = [0u64; 8];
+        assert_eq!(enqueue_message(&mut state, slot, &msg, 0, CapTransfer::default(), Vec::new()), E_PORT_DEAD);
+        assert_eq!(destroy_port(&mut state, slot), E_PORT_DEAD);
+    }
+
+    #[test]
+    fn test_port_destroy_needs_destroy_right() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_RECEIVE);
+        state.ports.push(Port::new(7, 1));
+
+        assert_eq!(destroy_port(&mut state, slot), E_NO_RIGHTS);
+        assert_eq!(state.ports.len(), 1);
+    }
+
+    #[test]
+    fn test_port_destroy_notifies_watchers() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_DESTROY);
+        state.ports.push(Port::new(7, 1));
+        state.ports.push(Port::new(8, 2));
+        state.ports[0].watchers.push(PortWatch { pid: 2, notify_port: 8, badge: 0x5, cookie: 42 });
+        state.ports[0].watchers.push(PortWatch { pid: 2, notify_port: 99, badge: 0, cookie: 1 });
+
+        assert_eq!(destroy_port(&mut state, slot), E_OK);
+
+        let (msg, meta) = state.ports[0].pop_message_with_meta().unwrap();
+        assert_eq!(msg[0], MSG_PORT_DIED);
+        assert_eq!(msg[1], 42);
+        assert_eq!(meta.sender_pid, 0, "Sent by the kernel");
+        assert_eq!(meta.badge, 0x5);
+        assert!(state.ports[0].is_empty(), "Watcher on a missing port is skipped");
+    }
+
+    #[test]
+    fn test_port_watch_limits() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND);
+        state.ports.push(Port::new(7, 1));
+
+        for cookie in 0..PORT_MAX_WATCHERS as u64 {
+            assert_eq!(watch_port(&mut state, 1, slot, slot, cookie), E_OK);
+        }
+        assert_eq!(watch_port(&mut state, 1, slot, slot, 0), E_NOMEM, "Port at its limit");
+
+        // Watches on other ports count against the process too
+        let watch = PortWatch { pid: 1, notify_port: 7, badge: 0, cookie: 0 };
+        let mut other = Port::new(8, 2);
+        other.watchers.resize(PROCESS_MAX_WATCHES - PORT_MAX_WATCHERS + 1, watch);
+        state.ports.push(other);
+        state.ports[0].watchers.pop();
+        assert_eq!(watch_port(&mut state, 1, slot, slot, 0), E_NOMEM, "Process at its limit");
+
+        state.ports[1].watchers[0].pid = 2;
+        assert_eq!(watch_port(&mut state, 1, slot, slot, 0), E_OK);
+    }
+
+    /// PID 1 receives on a set (slot 2) holding ports 7 (slot 1) and 8 (slot 3)
+    fn state_with_port_set() -> KernelState {
+        let (mut state, _) = state_with_cap(1, 7, CAP_SEND | CAP_RECEIVE);
+        let cspace = &mut state.process_mut(1).unwrap().cspace;
+        let mut set_cap = Capability::new(101, 1, 1, CAP_RECEIVE | CAP_DESTROY);
+        set_cap.kind = CapabilityKind::PortSet;
+        cspace.insert(set_cap);
+        cspace.insert(Capability::new(102, 1, 8, CAP_SEND | CAP_RECEIVE));
+
+        let mut set = PortSet::new(1, 1);
+        for (port_id, slot) in [(7, 1), (8, 3)] {
+            let mut port = Port::new(port_id, 1);
+            port.set_id = 1;
+            state.ports.push(port);
+            set.members.push(PortSetMember { kind: CapabilityKind::Port, object_id: port_id, slot });
+        }
+        state.port_sets.push(set);
+        state
+    }
+
+    #[test]
+    fn test_port_set_receive_reports_source() {
+        let mut state = state_with_port_set();
+        let meta = MessageMeta::default();
+        assert!(post_message(&mut state, 1, &[8; 8], meta));
+        assert!(post_message(&mut state, 0, &[7; 8], meta));
+        assert!(post_message(&mut state, 1, &[8; 8], meta));
+
+        // Round-robin: port 7 first, then 8, then 8 again
+        let mut sources = [0u64; 3];
+        for s in sources.iter_mut() {
+            let (msg, info, _) = receive_from_set(&mut state, 1, 2, 8, &[], 0).unwrap();
+            assert_eq!(msg[0], if info.port == 1 { 7 } else { 8 });
+            *s = info.port;
+        }
+        assert_eq!(sources, [1, 3, 3]);
+
+        assert_eq!(receive_from_set(&mut state, 1, 2, 8, &[], IPC_NONBLOCK).err(), Some(E_WOULD_BLOCK));
+        assert!(state.port_sets[0].waiters.is_empty(), "Non-blocking receive does not sleep");
+    }
+
+    #[test]
+    fn test_port_set_skips_oversized_member() {
+        let mut state = state_with_port_set();
+        let meta = MessageMeta::default();
+        assert!(post_message(&mut state, 0, &[7; 8], meta));
+        assert!(post_message(&mut state, 1, &[8], meta));
+
+        // Port 7's message does not fit, but does not hold up port 8
+        let (msg, info, _) = receive_from_set(&mut state, 1, 2, 4, &[], 0).unwrap();
+        assert_eq!((msg, info.port), (vec![8], 3));
+
+        // Then it is reported, and left for a bigger buffer
+        assert_eq!(receive_from_set(&mut state, 1, 2, 4, &[], 0).err(), Some(E_INVAL));
+        assert_eq!(oversized_member(&state, 1, 2, 4, &[]), Some(1));
+        assert!(state.port_sets[0].waiters.is_empty());
+        assert_eq!(receive_from_set(&mut state, 1, 2, 8, &[], 0).unwrap().1.port, 1);
+    }
+
+    #[test]
+    fn test_port_set_member_moved_away() {
+        let mut state = state_with_port_set();
+        let xfer = CapTransfer { cap_slot: 3, rights: CAP_SEND, flags: IPC_CAP_MOVE };
+        assert!(attach_capability(&mut state, &xfer).is_ok());
+
+        assert_eq!(state.port_sets[0].members.len(), 1, "Slot 3 no longer names port 8");
+        assert_eq!(state.ports[1].set_id, 0);
+    }
+
+    #[test]
+    fn test_port_set_wakes_receiver() {
+        let mut state = state_with_port_set();
+
+        assert_eq!(receive_from_set(&mut state, 1, 2, 8, &[], 0).err(), Some(E_WOULD_BLOCK));
+        assert_eq!(state.port_sets[0].waiters, [1]);
+        assert_eq!(state.process_mut(1).unwrap().state, ProcessState::Sleeping);
+
+        assert!(post_message(&mut state, 1, &[8; 8], MessageMeta::default()));
+        assert_eq!(state.process_mut(1).unwrap().state, ProcessState::Ready);
+        assert_eq!(receive_from_set(&mut state, 1, 2, 8, &[], 0).unwrap().1.port, 3);
+    }
+
+    #[test]
+    fn test_port_set_destroy() {
+        let mut state = state_with_port_set();
+
+        // Destroying a member port drops it from the set
+        state.process_mut(1).unwrap().cspace.get_mut(3).unwrap().rights |= CAP_DESTROY;
+        assert_eq!(destroy_port(&mut state, 3), E_OK);
+        assert_eq!(state.port_sets[0].members.len(), 1);
+
+        // Destroying the set releases the remaining ports
+        assert_eq!(destroy_port(&mut state, 2), E_OK);
+        assert!(state.port_sets.is_empty());
+        assert_eq!(state.ports[0].set_id, 0);
+        assert_eq!(receive_from_set(&mut state, 1, 2, 8, &[], 0).err(), Some(E_PORT_DEAD));
+    }
+
+    /// Add a notification (slot 4, ID 1) to the set from state_with_port_set
+    fn add_notification(state: &mut KernelState) {
+        let mut cap = Capability::new(103, 1, 1, CAP_SEND | CAP_RECEIVE | CAP_DESTROY);
+        cap.kind = CapabilityKind::Notification;
+        assert_eq!(state.process_mut(1).unwrap().cspace.insert(cap), Some(4));
+
+        let mut n = Notification::new(1, 1);
+        n.set_id = 1;
+        state.notifications.push(n);
+        let member = PortSetMember { kind: CapabilityKind::Notification, object_id: 1, slot: 4 };
+        state.port_sets[0].members.push(member);
+    }
+
+    #[test]
+    fn test_notification_signals_merge() {
+        let mut state = state_with_port_set();
+        add_notification(&mut state);
+
+        assert_eq!(receive_from_notification(&mut state, 1, 4, 0).err(), Some(E_WOULD_BLOCK));
+        assert_eq!(state.process_mut(1).unwrap().state, ProcessState::Sleeping);
+
+        // Any number of signals fit; they OR together
+        for _ in 0..1000 {
+            signal_notification(&mut state, 0, 1 << 0);
+        }
+        signal_notification(&mut state, 0, 1 << 5);
+        assert_eq!(state.process_mut(1).unwrap().state, ProcessState::Ready);
+
+        let (msg, info, _) = receive_from_notification(&mut state, 1, 4, 0).unwrap();
+        assert_eq!(msg, [0b100001]);
+        assert_eq!(info.port, 4);
+        assert_eq!(info.sender_pid, 0, "Signals come from the kernel");
+        assert_eq!(state.notifications[0].bits, 0, "Receiving clears the bits");
+    }
+
+    #[test]
+    fn test_notification_in_port_set() {
+        let mut state = state_with_port_set();
+        add_notification(&mut state);
+
+        assert_eq!(receive_from_set(&mut state, 1, 2, 8, &[], 0).err(), Some(E_WOULD_BLOCK));
+        signal_notification(&mut state, 0, 0x8);
+        assert_eq!(state.process_mut(1).unwrap().state, ProcessState::Ready);
+
+        let (msg, info, _) = receive_from_set(&mut state, 1, 2, 8, &[], 0).unwrap();
+        assert_eq!((msg[0], info.port), (0x8, 4));
+
+        // Destroying the notification drops it from the set
+        assert_eq!(destroy_port(&mut state, 4), E_OK);
+        assert_eq!(state.port_sets[0].members.len(), 2);
+        assert_eq!(receive_from_notification(&mut state, 1, 4, 0).err(), Some(E_PORT_DEAD));
+    }
+
+    #[test]
+    fn test_port_allocate_bounds() {
+        // Limits are checked before any state is touched
+        assert_eq!(port_allocate(PORT_MAX_QUEUE_DEPTH + 1, 0), E_INVAL);
+        assert_eq!(port_allocate(0, PORT_MAX_MSG_LEN + 1), E_INVAL);
+        assert_eq!(port_allocate(PORT_MAX_QUEUE_DEPTH, PORT_MAX_MSG_LEN), E_INVAL);
+    }
+
+    #[test]
+    fn test_message_length_limits() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_RECEIVE);
+        state.ports.push(Port::with_limits(7, 1, 4, 2));
+
+        let xfer = CapTransfer::default();
+        assert_eq!(enqueue_message(&mut state, slot, &[1, 2, 3], 0, xfer, Vec::new()), E_INVAL);
+        assert_eq!(enqueue_message(&mut state, slot, &[1, 2], 0, xfer, Vec::new()), E_OK);
+
+        // Too small a buffer leaves the message queued
+        assert_eq!(receive_from_port(&mut state, 1, slot, 1, &[], 0).err(), Some(E_INVAL));
+        let (msg, _, _) = receive_from_port(&mut state, 1, slot, 8, &[], 0).unwrap();
+        assert_eq!(msg, [1, 2]);
+    }
+
+    #[test]
+    fn test_receive_needs_room_for_reply_capability() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_RECEIVE);
+        state.ports.push(Port::new(7, 1));
+        assert_eq!(enqueue_message(&mut state, slot, &[9], 2, CapTransfer::default(), Vec::new()), E_OK);
+
+        let cspace = &mut state.process_mut(1).unwrap().cspace;
+        while cspace.insert(Capability::new(0, 1, 99, CAP_SEND)).is_some() {}
+
+        // The call stays queued rather than losing its way back
+        assert_eq!(receive_from_port(&mut state, 1, slot, 8, &[], 0).err(), Some(E_NOMEM));
+        assert!(!state.ports[0].is_empty());
+
+        let last = CSPACE_SLOTS as u32;
+        state.process_mut(1).unwrap().cspace.remove(last);
+        let (msg, info, _) = receive_from_port(&mut state, 1, slot, 8, &[], 0).unwrap();
+        assert_eq!(msg, [9]);
+        assert_eq!(info.reply_cap, last as u64);
+    }
+
+    #[test]
+    fn test_receive_length_cannot_overflow() {
+        // Runs against the global state, under a PID no other test uses
+        let pid = 0xFFFF;
+        {
+            let mut state = kernel_state_mut();
+            state.processes.push(ProcessDescriptor::new(pid, 0, 0));
+            state.current_process_id = pid;
+        }
+
+        let buf = crate::vm::USER_SPACE_START as *mut u64;
+        let (info, ool) = (core::ptr::null_mut(), core::ptr::null_mut());
+        assert_eq!(port_receive(1, buf, usize::MAX, 0, info, ool), E_FAULT);
+    }
+
+    #[test]
+    fn test_reply_only_wakes_a_waiting_caller() {
+        let (mut state, _) = state_with_cap(1, 7, CAP_SEND);
+        let cspace = &mut state.process_mut(1).unwrap().cspace;
+        let late = cspace.insert(Capability::reply(200, 1, 2)).unwrap();
+        let answered = cspace.insert(Capability::reply(201, 1, 2)).unwrap();
+
+        // Killed while waiting: the reply is dropped and the capability spent
+        let caller = state.process_mut(2).unwrap();
+        caller.reply_capacity = 8;
+        caller.state = ProcessState::Dead;
+        assert_eq!(deliver_reply(&mut state, late, vec![1], CapTransfer::default()), E_PROCESS_NOT_FOUND);
+        assert!(state.lookup_cap(1, late).is_none());
+        let caller = state.process_mut(2).unwrap();
+        assert_eq!(caller.state, ProcessState::Dead);
+        assert!(caller.reply_msg.is_none());
+
+        caller.state = ProcessState::Sleeping;
+        assert_eq!(deliver_reply(&mut state, answered, vec![1], CapTransfer::default()), E_OK);
+        let caller = state.process_mut(2).unwrap();
+        assert_eq!((caller.state, caller.reply_capacity), (ProcessState::Ready, 0));
+        assert!(caller.reply_msg.is_some());
+    }
+
+    #[test]
+    fn test_ool_buffers_follow_the_message() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND | CAP_RECEIVE | CAP_DESTROY);
+        state.ports.push(Port::new(7, 1));
+
+        let ool = vec![b"hello".to_vec(), vec![7; 300]];
+        assert_eq!(enqueue_message(&mut state, slot, &[4, 1, 5], 0, CapTransfer::default(), ool), E_OK);
+        assert_eq!(state.ool_in_transit.len(), 1);
+
+        // Missing or too small buffers leave the message queued
+        assert_eq!(receive_from_port(&mut state, 1, slot, 8, &[], 0).err(), Some(E_INVAL));
+        assert_eq!(receive_from_port(&mut state, 1, slot, 8, &[64, 64], 0).err(), Some(E_INVAL));
+
+        let (msg, _, ool) = receive_from_port(&mut state, 1, slot, 8, &[64, 512], 0).unwrap();
+        assert_eq!(msg, [4, 1, 5]);
+        assert_eq!(ool, [b"hello".to_vec(), vec![7; 300]]);
+        assert!(state.ool_in_transit.is_empty());
+
+        // Destroying the port drops data still queued
+        let ool = vec![vec![1; 16]];
+        assert_eq!(enqueue_message(&mut state, slot, &[1], 0, CapTransfer::default(), ool), E_OK);
+        assert_eq!(destroy_port(&mut state, slot), E_OK);
+        assert!(state.ool_in_transit.is_empty());
+    }
+
+    #[test]
+    fn test_read_user_ool_limits() {
+        let space = UserSpace::of(&ProcessDescriptor::new(1, 0x1000, 0x2000));
+        let data = [0xABu8; 32];
+        let mut ool = OolBuffers::default();
+        assert_eq!(read_user_ool(&space, core::ptr::null()), Ok(Vec::new()));
+
+        ool.count = 1;
+        ool.desc[0] = OolDescriptor { addr: data.as_ptr() as u64, len: 32 };
+        assert_eq!(read_user_ool(&space, &ool), Ok(vec![vec![0xAB; 32]]));
+
+        ool.count = IPC_MAX_OOL as u64 + 1;
+        assert_eq!(read_user_ool(&space, &ool), Err(E_INVAL));
+
+        ool.count = 2;
+        ool.desc[1] = OolDescriptor { addr: data.as_ptr() as u64, len: IPC_MAX_OOL_BYTES as u64 };
+        assert_eq!(read_user_ool(&space, &ool), Err(E_INVAL), "Total size is bounded");
+
+        // Buffers outside the sender's memory are refused, not dereferenced
+        ool.desc[1] = OolDescriptor { addr: 0, len: 8 };
+        assert_eq!(read_user_ool(&space, &ool), Err(E_FAULT));
+    }
+
+    #[test]
+    fn test_lookup_port_uses_local_slots() {
+        let (mut state, slot) = state_with_cap(1, 7, CAP_SEND);
+        state.ports.push(Port::new(7, 1));
+
+        assert!(lookup_port(&state, 1, slot, CAP_SEND).is_ok());
+        assert_eq!(lookup_port(&state, 1, slot, CAP_RECEIVE).err(), Some(E_NO_RIGHTS));
+        // The same slot number means nothing in another process's CSpace
+        assert_eq!(lookup_port(&state, 2, slot, CAP_SEND).err(), Some(E_CAP_INVALID));
+    }
+}
@@ -3,7 +3,10 @@
 
 #[cfg(test)]
 mod tests {
+    use crate::error::*;
     use crate::globals::*;
+    use crate::task::CpuContext;
+    use alloc::vec::Vec;
 
     #[test]
     fn test_port_creation() {
@@ -65,6 +68,124 @@ mod tests {
         assert!(port.push_message(&msg), "Should push after making space");
     }
 
+    #[test]
+    fn test_port_custom_limits() {
+        let mut port = Port::with_limits(1, 1, 4, 16);
+        assert_eq!(port.queue.len(), 64, "Ring storage sized to depth × length");
+
+        let long = [7u64; 16];
+        assert!(port.push_message(&long));
+        assert!(!port.push_message(&[0u64; 17]), "Longer than max_msg_len");
+        assert!(port.push_message(&[1, 2]));
+
+        assert_eq!(port.front_len(), Some(16));
+        assert_eq!(port.pop_message().unwrap(), long);
+        assert_eq!(port.front_len(), Some(2));
+        assert_eq!(port.pop_message().unwrap(), [1, 2], "Short messages keep their length");
+        assert_eq!(port.front_len(), None);
+    }
+
+    #[test]
+    fn test_port_shallow_queue_wraps() {
+        let mut port = Port::with_limits(1, 1, 3, 1);
+
+        for round in 0..5u64 {
+            assert!(port.push_message(&[round]));
+            assert!(port.push_message(&[round + 100]));
+            assert_eq!(port.pop_message().unwrap(), [round]);
+            assert_eq!(port.pop_message().unwrap(), [round + 100]);
+        }
+
+        for i in 0..3 {
+            assert!(port.push_message(&[i]));
+        }
+        assert!(port.is_full(), "Depth 3 holds three messages");
+    }
+
+    #[test]
+    fn test_port_message_meta_roundtrip() {
+        let mut port = Port::new(1, 1);
+        let msg = [1u64, 2, 3, 4, 5, 6, 7, 8];
+
+        assert!(port.push_message(&msg));
+        let meta = MessageMeta {
+            sender_pid: 9,
+            badge: 0xB00,
+            caller_pid: 9,
+            cap_id: 0,
+            ool_id: 0,
+        };
+        assert!(port.push_message_with_meta(&msg, meta));
+
+        let (_, plain) = port.pop_message_with_meta().unwrap();
+        assert_eq!(plain.caller_pid, 0, "Plain sends carry no caller");
+
+        let (received, call) = port.pop_message_with_meta().unwrap();
+        assert_eq!(received, msg);
+        assert_eq!(call.sender_pid, 9, "Sender identity travels with the message");
+        assert_eq!(call.badge, 0xB00, "Badge travels with the message");
+        assert_eq!(call.caller_pid, 9, "Calls record the blocked caller");
+    }
+
+    #[test]
+    fn test_port_wait_queue_fifo() {
+        let mut port = Port::new(1, 1);
+        assert!(port.take_waiter().is_none(), "New port has no waiters");
+
+        port.add_waiter(5);
+        port.add_waiter(7);
+        port.add_waiter(5); // Already waiting, must not be queued twice
+
+        assert_eq!(port.waiters.len(), 2);
+        assert_eq!(port.take_waiter(), Some(5));
+        assert_eq!(port.take_waiter(), Some(7));
+        assert!(port.take_waiter().is_none());
+    }
+
+    #[test]
+    fn test_port_set_membership() {
+        let mut set = PortSet::new(1, 1);
+        let port = PortSetMember { kind: CapabilityKind::Port, object_id: 7, slot: 1 };
+        let notification = PortSetMember { kind: CapabilityKind::Notification, object_id: 7, slot: 3 };
+        set.members.push(port);
+        set.members.push(notification);
+        set.next = 1;
+
+        assert!(set.remove_member(CapabilityKind::Port, 7));
+        assert!(!set.remove_member(CapabilityKind::Port, 7), "Already removed");
+        assert_eq!(set.members, [notification], "Same ID of another kind is kept");
+        assert_eq!(set.next, 0, "Scan restarts after membership changes");
+    }
+
+    #[test]
+    fn test_port_set_wait_queue_fifo() {
+        let mut set = PortSet::new(1, 1);
+        set.add_waiter(5);
+        set.add_waiter(7);
+        set.add_waiter(5);
+
+        assert_eq!(set.take_waiter(), Some(5));
+        assert_eq!(set.take_waiter(), Some(7));
+        assert!(set.take_waiter().is_none());
+    }
+
+    #[test]
+    fn test_notification_bits() {
+        let mut n = Notification::new(1, 1);
+        assert_eq!(n.take_bits(), 0);
+
+        n.signal(0b01);
+        n.signal(0b10);
+        n.signal(0b01);
+        assert_eq!(n.take_bits(), 0b11, "Signals merge by OR");
+        assert_eq!(n.bits, 0);
+
+        n.add_waiter(4);
+        n.add_waiter(4);
+        assert_eq!(n.take_waiter(), Some(4));
+        assert!(n.take_waiter().is_none());
+    }
+
     #[test]
     fn test_capability_creation() {
         let cap = Capability::new(1, 10, 100, 0x03);
@@ -73,6 +194,7 @@ mod tests {
         assert_eq!(cap.target_id, 100);
         assert_eq!(cap.rights, 0x03);
         assert!(!cap.revoked);
+        assert_eq!(cap.badge, 0, "Freshly allocated capabilities are unbadged");
     }
 
     #[test]
@@ -96,6 +218,119 @@ mod tests {
         assert!(!cap.has_right(0x01), "Should not have rights after revocation");
     }
 
+    #[test]
+    fn test_capability_derive() {
+        let mut parent = Capability::new(1, 1, 100, CAP_SEND | CAP_DERIVE);
+        parent.badge = 0xB00;
+
+        let child = parent.derive(2, 3, CAP_SEND);
+        assert_eq!(child.id, 2);
+        assert_eq!(child.owner_pid, 3);
+        assert_eq!(child.target_id, 100);
+        assert_eq!(child.rights, CAP_SEND);
+        assert_eq!(child.parent, 1);
+        assert_eq!(child.badge, 0xB00, "Children inherit the badge");
+        assert_eq!(parent.parent, 0, "Roots have no parent");
+    }
+
+    /// Build a derivation chain root (PID 1) -> child (PID 2) -> grandchild
+    /// (PID 3), plus an unrelated capability for the same port in PID 1
+    fn state_with_derivation_chain() -> KernelState {
+        let mut state = KernelState::new();
+        for pid in 1..=3 {
+            state.processes.push(ProcessDescriptor::new(pid, 0, 0));
+        }
+
+        let root = Capability::new(1, 1, 100, CAP_SEND | CAP_DERIVE);
+        let child = root.derive(2, 2, CAP_SEND | CAP_DERIVE);
+        let grandchild = child.derive(3, 3, CAP_SEND);
+        let unrelated = Capability::new(4, 1, 100, CAP_SEND);
+
+        state.process_mut(1).unwrap().cspace.insert(root);
+        state.process_mut(1).unwrap().cspace.insert(unrelated);
+        state.process_mut(2).unwrap().cspace.insert(child);
+        state.process_mut(3).unwrap().cspace.insert(grandchild);
+        state
+    }
+
+    #[test]
+    fn test_revoke_tree_multi_level() {
+        let mut state = state_with_derivation_chain();
+
+        assert_eq!(state.revoke_tree(1), 3);
+        assert!(state.lookup_cap(1, 1).unwrap().revoked);
+        assert!(state.lookup_cap(2, 1).unwrap().revoked);
+        assert!(state.lookup_cap(3, 1).unwrap().revoked, "Grandchild must be revoked");
+        assert!(!state.lookup_cap(1, 2).unwrap().revoked, "Unrelated capability survives");
+    }
+
+    #[test]
+    fn test_revoke_tree_subtree_only() {
+        let mut state = state_with_derivation_chain();
+
+        assert_eq!(state.revoke_tree(2), 2);
+        assert!(!state.lookup_cap(1, 1).unwrap().revoked, "Ancestors are untouched");
+        assert!(state.lookup_cap(2, 1).unwrap().revoked);
+        assert!(state.lookup_cap(3, 1).unwrap().revoked);
+
+        // Revoking again is harmless
+        assert_eq!(state.revoke_tree(2), 0);
+    }
+
+    #[test]
+    fn test_revoke_tree_reaches_in_transit() {
+        let mut state = state_with_derivation_chain();
+
+        // A great-grandchild still queued in a message
+        let grandchild = state.lookup_cap(3, 1).unwrap();
+        state.in_transit.push(grandchild.derive(5, 3, CAP_SEND));
+
+        assert_eq!(state.revoke_tree(1), 4);
+        assert!(state.in_transit[0].revoked);
+    }
+
+    #[test]
+    fn test_reply_capability() {
+        let cap = Capability::reply(4, 10, 20);
+        assert_eq!(cap.kind, CapabilityKind::Reply);
+        assert_eq!(cap.owner_pid, 10);
+        assert_eq!(cap.target_id, 20, "Reply capability targets the caller PID");
+        assert!(cap.has_right(CAP_SEND));
+        assert!(!cap.has_right(CAP_RECEIVE));
+
+        let port_cap = Capability::new(5, 10, 20, CAP_SEND);
+        assert_eq!(port_cap.kind, CapabilityKind::Port);
+    }
+
+    #[test]
+    fn test_cspace_slots() {
+        let mut cspace = CSpace::new();
+        assert!(cspace.get(0).is_none(), "Slot 0 is never valid");
+
+        let a = cspace.insert(Capability::new(10, 1, 100, CAP_SEND)).unwrap();
+        let b = cspace.insert(Capability::new(11, 1, 101, CAP_RECEIVE)).unwrap();
+        assert_eq!((a, b), (1, 2), "Handles are small local indices");
+        assert_eq!(cspace.get(b).unwrap().target_id, 101);
+
+        // Freed slots are reused
+        assert_eq!(cspace.remove(a).unwrap().id, 10);
+        assert!(cspace.get(a).is_none());
+        assert_eq!(cspace.insert(Capability::new(12, 1, 102, CAP_SEND)), Some(a));
+
+        cspace.get_mut(b).unwrap().revoke();
+        assert!(cspace.get(b).unwrap().revoked);
+        assert!(cspace.get(99).is_none());
+    }
+
+    #[test]
+    fn test_cspace_capacity() {
+        let mut cspace = CSpace::new();
+        for i in 0..CSPACE_SLOTS {
+            assert!(cspace.insert(Capability::new(i as u32, 1, 1, CAP_SEND)).is_some());
+        }
+        assert!(cspace.insert(Capability::new(0, 1, 1, CAP_SEND)).is_none());
+    }
+
     #[test]
     fn test_process_descriptor_creation() {
         let proc = ProcessDescriptor {
@@ -107,6 +342,15 @@ mod tests {
             state: ProcessState::Ready,
             stack_pointer: 0x2000,
             instruction_pointer: 0x1000,
+            reply_msg: None,
+            reply_capacity: 0,
+            call_error: E_OK,
+            cspace: CSpace::new(),
+            vm_regions: Vec::new(),
+            pager: None,
+            exception_handler: None,
+            context: CpuContext::default(),
+            kernel_stack: None,
         };
 
         assert_eq!(proc.id, 1);
@@ -114,6 +358,37 @@ mod tests {
         assert_eq!(proc.memory_end, 0x2000);
     }
 
+    #[test]
+    fn test_set_process_state() {
+        let mut state = KernelState::new();
+        state.processes.push(ProcessDescriptor {
+            id: 3,
+            name: [0; 32],
+            memory_start: 0x1000,
+            memory_end: 0x2000,
+            page_table_root: 0,
+            state: ProcessState::Ready,
+            stack_pointer: 0x2000,
+            instruction_pointer: 0x1000,
+            reply_msg: None,
+            reply_capacity: 0,
+            call_error: E_OK,
+            cspace: CSpace::new(),
+            vm_regions: Vec::new(),
+            pager: None,
+            exception_handler: None,
+            context: CpuContext::default(),
+            kernel_stack: None,
+        });
+
+        state.set_process_state(3, ProcessState::Sleeping);
+        assert_eq!(state.processes[0].state, ProcessState::Sleeping);
+
+        // Unknown PIDs are ignored
+        state.set_process_state(99, ProcessState::Dead);
+        assert_eq!(state.processes[0].state, ProcessState::Sleeping);
+    }
+
     #[test]
     fn test_process_state_values() {
         // Verify process states
@@ -14,12 +14,21 @@ mod serial;
 mod panic;
 mod arch;
 mod memory;
+mod allocator;
 
 // Kernel infrastructure
+pub mod clock;
 pub mod error;
+pub mod fault;
+pub mod frame;
 pub mod globals;
 pub mod ipc;
+pub mod sched;
 pub mod syscall;
+pub mod task;
+pub mod timer;
+pub mod uaccess;
+pub mod vm;
 
 // Unit tests
 #[cfg(test)]
@@ -29,8 +38,11 @@ mod error_tests;
 mod ipc_tests;
 
 /// Kernel entry point called by the bootloader.
+///
+/// `mbi_addr` is the physical address of the multiboot2 boot information,
+/// passed in rdi by the boot stub.
 #[no_mangle]
-pub extern "C" fn _start() -> ! {
+pub extern "C" fn _start(mbi_addr: usize) -> ! {
     // Initialize subsystems
     vga::clear_screen();
     vga::print_str("GBSD kernel starting...\n");
@@ -38,7 +50,9 @@ pub extern "C" fn _start() -> ! {
     serial::init();
     serial::write_str("Serial initialized.\n");
 
-    memory::init();
+    memory::init(mbi_addr);
+    arch::init_fpu();  // Before any task's FPU save area is sized
+    task::init_tasks();
 
     vga::print_str("Initialization complete.\n");
 
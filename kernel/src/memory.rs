@@ -1,31 +1,88 @@
 // kernel/src/memory.rs
 // Memory management - paging, allocation
 
-use spin::Mutex;
+use multiboot2::MemoryAreaType;
+use crate::frame::{FRAME_ALLOCATOR, FRAME_SIZE};
 
-pub struct MemoryManager {
-    total_memory: usize,
-    allocated: usize,
+// Kernel image bounds, from linker.ld
+extern "C" {
+    static __kernel_start: u8;
+    static __kernel_end: u8;
 }
 
-impl MemoryManager {
-    pub fn new() -> Self {
-        Self {
-            total_memory: 0x100000000,  // 4 GB
-            allocated: 0,
+/// Low memory (real-mode IVT, BIOS data, EBDA, video memory and ROMs)
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Initialize memory management system
+///
+/// `mbi_addr` is the physical address of the multiboot2 boot information.
+/// Seeds the frame allocator with the RAM the memory map reports as
+/// available, then takes back everything still in use: low memory, the
+/// kernel image, boot modules and the boot information itself. Finally
+/// hands the boot page tables over to vm as the kernel address space.
+pub fn init(mbi_addr: usize) {
+    let boot_info = match unsafe { multiboot2::load(mbi_addr) } {
+        Ok(info) => info,
+        Err(_) => {
+            crate::serial::write_str("[memory] No multiboot2 boot information\n");
+            return;
+        }
+    };
+
+    let mut frames = FRAME_ALLOCATOR.lock();
+
+    match boot_info.memory_map_tag() {
+        Some(map) => {
+            for area in map.memory_areas() {
+                if area.typ() == MemoryAreaType::Available {
+                    frames.add_region(area.start_address(), area.end_address());
+                }
+            }
         }
+        None => crate::serial::write_str("[memory] No memory map from bootloader\n"),
     }
-}
 
-pub static MEMORY_MANAGER: Mutex<MemoryManager> = Mutex::new(MemoryManager {
-    total_memory: 0x100000000,
-    allocated: 0,
-});
+    frames.reserve(0, LOW_MEMORY_END);
 
-/// Initialize memory management system
-pub fn init() {
-    // TODO: Detect actual memory from bootloader
-    // Initialize paging
-    // Set up allocator
+    let (kernel_start, kernel_end) = unsafe {
+        (&__kernel_start as *const u8 as u64, &__kernel_end as *const u8 as u64)
+    };
+    frames.reserve(kernel_start, kernel_end);
+
+    for module in boot_info.module_tags() {
+        frames.reserve(module.start_address() as u64, module.end_address() as u64);
+    }
+
+    frames.reserve(boot_info.start_address() as u64, boot_info.end_address() as u64);
+
+    let stats = frames.stats();
+    drop(frames);
+
+    crate::serial::write_str("[memory] ");
+    write_kib(stats.total);
+    crate::serial::write_str(" KB usable, ");
+    write_kib(stats.reserved);
+    crate::serial::write_str(" KB reserved, ");
+    write_kib(stats.free);
+    crate::serial::write_str(" KB free\n");
+
+    crate::vm::init();
 }
 
+/// Print a frame count in KB to serial
+fn write_kib(frames: usize) {
+    let mut n = frames as u64 * (FRAME_SIZE / 1024);
+    let mut buf = [0u8; 20];
+    let mut i = buf.len();
+
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+
+    crate::serial::write_str(core::str::from_utf8(&buf[i..]).unwrap_or("?"));
+}
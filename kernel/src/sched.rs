@@ -0,0 +1,174 @@
+// kernel/src/sched.rs
+// Yielding - handing the CPU back to scheduler_server
+//
+// As with timer ticks, the kernel does not pick the next task. scheduler_server
+// registers a port with sched_set_port, and a task that calls sched_yield is
+// reported there as [MSG_TASK_YIELD, pid]. The message comes from the kernel
+// (sender_pid 0) with the PID of the process that really yielded, so no task
+// can yield on another's behalf. The CPU then goes straight to the
+// scheduler; the yielding task stays Ready but does not run again until the
+// scheduler switches back to it.
+//
+// Only scheduler_server, at the fixed SCHEDULER_PID, may register. Until it
+// does, or once the port is destroyed, yields return at once.
+
+use crate::error::*;
+use crate::globals::*;
+use crate::ipc::kernel_send;
+use crate::task;
+
+/// Report yields to the port in `port_slot`
+///
+/// Needs `CAP_SEND`; yields are sent with the capability's badge. The
+/// caller is switched to after each yield. Fails with E_NO_RIGHTS unless
+/// the caller is scheduler_server.
+pub fn sched_set_port(port_slot: u32) -> u64 {
+    let mut state = kernel_state_mut();
+    let pid = state.current_process_id;
+    match register(&mut state, pid, port_slot) {
+        Ok(()) => E_OK,
+        Err(e) => e,
+    }
+}
+
+/// Make `pid`'s port in `slot` the one yields are reported to
+fn register(state: &mut KernelState, pid: u32, slot: u32) -> Result<(), u64> {
+    if pid != task::SCHEDULER_PID {
+        return Err(E_NO_RIGHTS);
+    }
+
+    let cap = match state.lookup_cap(pid, slot) {
+        Some(c) if !c.revoked && c.kind == CapabilityKind::Port => c,
+        _ => return Err(E_CAP_INVALID),
+    };
+    if !cap.has_right(CAP_SEND) {
+        return Err(E_NO_RIGHTS);
+    }
+
+    state.scheduler = Some(SchedulerPort { pid, port_id: cap.target_id, badge: cap.badge });
+    Ok(())
+}
+
+/// Give up the CPU to the scheduler
+///
+/// Returns E_OK once the scheduler switches back, or at once if there is
+/// no scheduler or the caller is the scheduler. A scheduler blocked in
+/// something other than its receive sees the yield once that finishes, and
+/// the caller carries on until then. Fails with E_PORT_FULL, without
+/// yielding, if the scheduler's queue is full.
+pub fn sched_yield() -> u64 {
+    let next = match report_yield(&mut kernel_state_mut()) {
+        Ok(next) => next,
+        Err(e) => return e,
+    };
+
+    match next {
+        Some(pid) => match task::switch_task(pid) {
+            E_INVAL => E_OK,  // The scheduler stopped being Ready; it still has the message
+            result => result,
+        },
+        None => E_OK,
+    }
+}
+
+/// Tell the scheduler the current process yielded, returning the scheduler
+/// if it should run now
+fn report_yield(state: &mut KernelState) -> Result<Option<u32>, u64> {
+    let pid = state.current_process_id;
+    let target = match state.scheduler {
+        Some(target) if target.pid != pid => target,
+        _ => return Ok(None),
+    };
+
+    // Port IDs are never reused, so a missing port was destroyed
+    match kernel_send(state, target.port_id, target.badge, &[MSG_TASK_YIELD, pid as u64]) {
+        E_OK => {}
+        E_PORT_DEAD => {
+            state.scheduler = None;
+            return Ok(None);
+        }
+        e => return Err(e),
+    }
+
+    let ready = state.processes.iter().any(|p| p.id == target.pid && p.state == ProcessState::Ready);
+    Ok(if ready { Some(target.pid) } else { None })
+}
+
+/// Test fixture: the scheduler with port 5 (badge 0x5C) and notification 5,
+/// while process 2 runs
+///
+/// Returns the state and the scheduler's slots for the port and the
+/// notification.
+#[cfg(test)]
+pub(crate) fn with_scheduler() -> (KernelState, u32, u32) {
+    let pid = task::SCHEDULER_PID;
+    let mut state = KernelState::new();
+    state.processes.push(ProcessDescriptor::new(2, 0, 0));
+    state.processes.push(ProcessDescriptor::new(pid, 0, 0));
+    state.ports.push(Port::new(5, pid));
+    state.notifications.push(Notification::new(5, pid));
+
+    let mut port = Capability::new(1, pid, 5, CAP_SEND | CAP_RECEIVE);
+    port.badge = 0x5C;
+    let mut notification = Capability::new(2, pid, 5, CAP_SEND | CAP_RECEIVE);
+    notification.kind = CapabilityKind::Notification;
+    let cspace = &mut state.process_mut(pid).unwrap().cspace;
+    let port_slot = cspace.insert(port).unwrap();
+    let notify_slot = cspace.insert(notification).unwrap();
+
+    state.current_process_id = 2;
+    (state, port_slot, notify_slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_yield_reports_pid_and_runs_scheduler() {
+        let (mut state, slot, _) = with_scheduler();
+        assert_eq!(report_yield(&mut state), Ok(None), "No scheduler to yield to");
+        assert!(state.ports[0].pop_message().is_none());
+
+        assert_eq!(register(&mut state, 3, slot), Ok(()));
+        state.processes[1].state = ProcessState::Sleeping;
+        state.ports[0].add_waiter(3);
+
+        assert_eq!(report_yield(&mut state), Ok(Some(3)));
+        assert_eq!(state.processes[1].state, ProcessState::Ready, "Woken from its receive");
+        let (msg, meta) = state.ports[0].pop_message_with_meta().unwrap();
+        assert_eq!(msg, vec![MSG_TASK_YIELD, 2]);
+        assert_eq!((meta.sender_pid, meta.badge), (0, 0x5C), "Sent by the kernel");
+
+        // The scheduler yielding has no one to hand to
+        state.current_process_id = 3;
+        assert_eq!(report_yield(&mut state), Ok(None));
+        assert!(state.ports[0].pop_message().is_none());
+    }
+
+    #[test]
+    fn test_only_scheduler_takes_yields() {
+        let (mut state, slot, _) = with_scheduler();
+        assert_eq!(register(&mut state, 2, slot), Err(E_NO_RIGHTS), "Even with no one registered");
+        assert_eq!(register(&mut state, 3, slot), Ok(()));
+        state.processes[1].state = ProcessState::Dead;
+        assert_eq!(register(&mut state, 2, slot), Err(E_NO_RIGHTS), "Even once the scheduler is dead");
+
+        // A full queue fails the yield rather than losing it
+        while state.ports[0].push_message_with_meta(&[0], MessageMeta::default()) {}
+        assert_eq!(report_yield(&mut state), Err(E_PORT_FULL));
+
+        // A destroyed port stops the reports
+        state.ports.clear();
+        assert_eq!(report_yield(&mut state), Ok(None));
+        assert_eq!(state.scheduler, None);
+    }
+
+    #[test]
+    fn test_only_scheduler_switches_tasks() {
+        assert_ne!(current_pid(), task::SCHEDULER_PID);
+        let result = crate::syscall::handle_syscall(SYS_SCHED_SWITCH, [2, 0, 0, 0, 0, 0]);
+        assert_eq!(result, E_NO_RIGHTS);
+    }
+}
@@ -1,139 +1,337 @@
 use crate::error::*;
-use crate::ipc::{port_allocate, port_send, port_receive, cap_move, cap_revoke};
+use crate::ipc::{
+    port_allocate, port_send, port_receive, port_call, port_reply, take_reply, port_destroy,
+    port_watch, port_set_allocate, port_set_add, port_set_remove, notify_allocate, notify_signal,
+    cap_move, cap_revoke, CapTransfer, MessageInfo, OolBuffers,
+};
 use crate::globals::*;
+use crate::fault::{set_exception_port, vm_set_pager};
+use crate::allocator::MemoryStats;
+use crate::clock::{clock_monotonic, clock_wall};
+use crate::sched::{sched_set_port, sched_yield};
+use crate::task;
+use crate::timer::timer_set;
+use crate::uaccess::current_user_space;
+use crate::vm::{
+    copy_spawn_regions, destroy_address_space, memobj_allocate, memobj_map, vm_allocate, vm_deallocate,
+    AddressSpace, PageTableFrames, USER_SPACE_END, USER_SPACE_START,
+};
 
 /// Main syscall handler - dispatches to appropriate syscall
 pub fn handle_syscall(num: u64, args: [u64; 6]) -> u64 {
     match num {
-        SYS_PORT_ALLOCATE => sys_port_allocate(),
-        SYS_PORT_SEND => sys_port_send(args[0] as u32, args[1] as *const u64, args[2] as usize),
-        SYS_PORT_RECEIVE => sys_port_receive(args[0] as u32, args[1] as *mut u64, args[2] as usize),
+        SYS_PORT_ALLOCATE => sys_port_allocate(args[0] as u32, args[1] as u32),
+        SYS_PORT_SEND => sys_port_send(
+            args[0] as u32,
+            args[1] as *const u64,
+            args[2] as usize,
+            cap_transfer(args[3], args[5]),
+            args[4] as *const OolBuffers,
+        ),
+        SYS_PORT_RECEIVE => sys_port_receive(
+            args[0] as u32,
+            args[1] as *mut u64,
+            args[2] as usize,
+            args[3],
+            args[4] as *mut MessageInfo,
+            args[5] as *mut OolBuffers,
+        ),
         SYS_VM_ALLOCATE => sys_vm_allocate(args[0], args[1], args[2] as u32),
         SYS_VM_DEALLOCATE => sys_vm_deallocate(args[0], args[1]),
-        SYS_CAP_MOVE => sys_cap_move(args[0] as u32, args[1] as u32, args[2] as u32),
+        SYS_CAP_MOVE => sys_cap_move(args[0] as u32, args[1] as u32, args[2] as u32, args[3]),
         SYS_SCHED_SPAWN => sys_sched_spawn(args[0], args[1], args[2] as *const u8),
         SYS_SCHED_YIELD => sys_sched_yield(),
         SYS_SCHED_SWITCH => sys_sched_switch(args[0] as u32),
         SYS_TIME => sys_time(),
+        SYS_PORT_CALL => sys_port_call(
+            args[0] as u32,
+            args[1] as *const u64,
+            (args[2] & 0xFFFF_FFFF) as usize,  // Request length in the low half
+            args[3] as *mut u64,
+            (args[2] >> 32) as usize,          // Reply buffer size in the high half
+            args[4] as *const OolBuffers,
+            args[5] as *mut MessageInfo,
+        ),
+        SYS_PORT_REPLY => sys_port_reply(
+            args[0] as u32,
+            args[1] as *const u64,
+            args[2] as usize,
+            cap_transfer(args[3], args[5]),
+        ),
+        SYS_PORT_DESTROY => sys_port_destroy(args[0] as u32),
+        SYS_PORT_WATCH => sys_port_watch(args[0] as u32, args[1] as u32, args[2]),
+        SYS_PORT_SET_ALLOCATE => sys_port_set_allocate(),
+        SYS_PORT_SET_ADD => sys_port_set_add(args[0] as u32, args[1] as u32),
+        SYS_PORT_SET_REMOVE => sys_port_set_remove(args[0] as u32, args[1] as u32),
+        SYS_NOTIFY_ALLOCATE => sys_notify_allocate(),
+        SYS_NOTIFY_SIGNAL => sys_notify_signal(args[0] as u32, args[1]),
+        SYS_MEM_STATS => sys_mem_stats(args[0] as *mut MemoryStats),
+        SYS_MEMOBJ_ALLOCATE => sys_memobj_allocate(args[0]),
+        SYS_MEMOBJ_MAP => sys_memobj_map(args[0] as u32, args[1], args[2] as u32),
+        SYS_CAP_REVOKE => sys_cap_revoke(args[0] as u32),
+        SYS_VM_SET_PAGER => sys_vm_set_pager(args[0] as u32),
+        SYS_SET_EXCEPTION_PORT => sys_set_exception_port(args[0] as u32),
+        SYS_TIMER_SET => sys_timer_set(args[0] as u32, args[1] as u32),
+        SYS_TIME_WALL => sys_time_wall(),
+        SYS_SCHED_SET_PORT => sys_sched_set_port(args[0] as u32),
         _ => E_INVALID_SYSCALL,
     }
 }
 
-/// 1. Allocate a new port for IPC
-fn sys_port_allocate() -> u64 {
-    port_allocate()
+/// Decode the optional capability-transfer arguments of send/reply
+///
+/// The slot is passed in the low 32 bits of `cap` and the rights granted
+/// in the high 32 bits.
+fn cap_transfer(cap: u64, flags: u64) -> CapTransfer {
+    CapTransfer {
+        cap_slot: cap as u32,
+        rights: (cap >> 32) as u32,
+        flags,
+    }
 }
 
-/// 2. Send a message to a port
-fn sys_port_send(port_id: u32, msg_ptr: *const u64, len: usize) -> u64 {
-    port_send(port_id, msg_ptr, len)
+/// 1. Allocate a new port for IPC (queue depth and message length in u64s, 0 = default)
+fn sys_port_allocate(depth: u32, msg_len: u32) -> u64 {
+    port_allocate(depth, msg_len)
 }
 
-/// 3. Receive a message from a port
-fn sys_port_receive(port_id: u32, buf_ptr: *mut u64, len: usize) -> u64 {
-    port_receive(port_id, buf_ptr, len)
+/// 2. Send a message to a port, optionally carrying a capability and out-of-line buffers
+fn sys_port_send(
+    port_slot: u32,
+    msg_ptr: *const u64,
+    len: usize,
+    xfer: CapTransfer,
+    ool_ptr: *const OolBuffers,
+) -> u64 {
+    port_send(port_slot, msg_ptr, len, xfer, ool_ptr)
 }
 
-/// 4. Allocate user virtual memory
-fn sys_vm_allocate(hint: u64, size: u64, flags: u32) -> u64 {
-    if size == 0 || size > 0x40000000 {  // Max 1 GB
-        return E_INVAL;
-    }
+/// 3. Receive from a port, notification or port set (blocks unless IPC_NONBLOCK is set)
+fn sys_port_receive(
+    port_slot: u32,
+    buf_ptr: *mut u64,
+    len: usize,
+    flags: u64,
+    info_ptr: *mut MessageInfo,
+    ool_ptr: *mut OolBuffers,
+) -> u64 {
+    loop {
+        let result = port_receive(port_slot, buf_ptr, len, flags, info_ptr, ool_ptr);
+        if result != E_WOULD_BLOCK || (flags & IPC_NONBLOCK) != 0 {
+            return result;
+        }
 
-    if (hint & 0xFFF) != 0 {
-        return E_ALIGN;  // Must be 4 KB aligned
+        // Caller is parked on the port's (or set's) wait queue
+        task::block_current();
     }
+}
 
-    // For now, just return a valid address in user space
-    // Real implementation would allocate physical pages and map them
-    let addr = 0x1000 + hint;
-    if addr < 0x800000000000 {
-        // Assuming user space goes up to this
-        addr
-    } else {
-        E_NOMEM
-    }
+/// 4. Allocate and map zeroed user memory with VM_* protection (hint 0 = anywhere)
+fn sys_vm_allocate(hint: u64, size: u64, flags: u32) -> u64 {
+    vm_allocate(hint, size, flags)
 }
 
-/// 5. Deallocate user virtual memory
+/// 5. Unmap a region from vm_allocate and free its memory
 fn sys_vm_deallocate(addr: u64, size: u64) -> u64 {
-    if size == 0 {
-        return E_INVAL;
-    }
-
-    if (addr & 0xFFF) != 0 {
-        return E_ALIGN;
-    }
-
-    // For now, just acknowledge
-    E_OK
+    vm_deallocate(addr, size)
 }
 
-/// 6. Move (transfer) a capability
-fn sys_cap_move(src_cap: u32, dst_pid: u32, rights: u32) -> u64 {
-    cap_move(src_cap, dst_pid, rights)
+/// 6. Move (transfer) a capability, optionally stamping a badge
+fn sys_cap_move(src_slot: u32, dst_pid: u32, rights: u32, badge: u64) -> u64 {
+    cap_move(src_slot, dst_pid, rights, badge)
 }
 
-/// 7. Spawn a new task (process)
+/// 7. Spawn a task from copies of the caller's code and stack, named by a NUL-terminated string (may be null)
 fn sys_sched_spawn(entry: u64, stack: u64, name_ptr: *const u8) -> u64 {
     if entry == 0 || stack == 0 {
         return E_INVAL;
     }
 
     // Verify entry and stack are in user space
-    if entry >= 0x800000000000 || stack >= 0x800000000000 {
+    let user = USER_SPACE_START..USER_SPACE_END;
+    if !user.contains(&entry) || !user.contains(&stack) {
         return E_INVAL;
     }
 
+    // Names are truncated to 31 bytes so they stay NUL-terminated
+    let mut name = [0u8; 32];
+    if !name_ptr.is_null() {
+        let read = current_user_space().and_then(|space| space.read_str(name_ptr as u64, &mut name[..31]));
+        if let Err(e) = read {
+            return e;
+        }
+    }
+
     let mut state = kernel_state_mut();
-    let mut pid_counter = NEXT_PROCESS_ID.lock();
+    if let Err(e) = reserve_one(&mut state.processes) {
+        return e;
+    }
 
-    let new_pid = *pid_counter;
-    *pid_counter += 1;
+    let parent_pid = state.current_process_id;
+    let parent = match state.processes.iter().find(|p| p.id == parent_pid) {
+        Some(p) => p,
+        None => return E_PROCESS_NOT_FOUND,
+    };
 
-    let proc = ProcessDescriptor {
-        id: new_pid,
-        name: [0u8; 32],
-        memory_start: 0x1000,
-        memory_end: 0x800000000000,
-        page_table_root: 0,  // Would be set by MMU
-        state: ProcessState::Ready,
-        stack_pointer: stack,
-        instruction_pointer: entry,
+    // Each process gets its own page tables, sharing only the kernel half,
+    // with its code and stack copied in from the parent
+    let mut space = match AddressSpace::new() {
+        Ok(space) => space,
+        Err(e) => return e,
     };
+    let regions = match copy_spawn_regions(parent, &mut space, entry, stack) {
+        Ok(regions) => regions,
+        Err(e) => {
+            space.destroy(&mut PageTableFrames);
+            return e;
+        }
+    };
+
+    // Children share their parent's pager
+    let mut process = ProcessDescriptor::new(0, entry, stack);
+    process.name = name;
+    process.page_table_root = space.root();
+    process.pager = parent.pager;
+    process.vm_regions = regions;
+    if let Err(e) = task::init_user_context(&mut process) {
+        destroy_address_space(space, &process.vm_regions);
+        return e;
+    }
+
+    // The PID is only taken once nothing can fail
+    let mut pid_counter = NEXT_PROCESS_ID.lock();
+    let new_pid = *pid_counter;
+    *pid_counter += 1;
 
-    state.processes.push(proc);
+    process.id = new_pid;
+    state.processes.push(process);
 
     new_pid as u64
 }
 
 /// 8. Yield CPU to scheduler
 fn sys_sched_yield() -> u64 {
-    // Signal scheduler that this task is yielding
-    // The scheduler will decide what to do next
-    E_OK  // TODO: Implement proper yield
+    sched_yield()
 }
 
 /// 9. Switch to a different task (scheduler-only)
 fn sys_sched_switch(target_pid: u32) -> u64 {
-    let mut state = kernel_state_mut();
+    if current_pid() != task::SCHEDULER_PID {
+        return E_NO_RIGHTS;
+    }
+    task::switch_task(target_pid)
+}
 
-    // Only scheduler_server should call this
-    // For now, just verify the PID exists
-    if !state.processes.iter().any(|p| p.id == target_pid) {
-        return E_PROCESS_NOT_FOUND;
+/// 10. Get monotonic time, in nanoseconds since boot
+fn sys_time() -> u64 {
+    clock_monotonic()
+}
+
+/// 11. Send a request and block until the receiver replies
+fn sys_port_call(
+    port_slot: u32,
+    msg_ptr: *const u64,
+    len: usize,
+    reply_ptr: *mut u64,
+    reply_len: usize,
+    ool_ptr: *const OolBuffers,
+    info_ptr: *mut MessageInfo,
+) -> u64 {
+    let result = port_call(port_slot, msg_ptr, len, reply_len, ool_ptr);
+    if result != E_OK {
+        return result;
     }
 
-    state.current_process_id = target_pid;
+    // Caller sleeps until port_reply hands back the answer
+    task::block_current();
+    take_reply(reply_ptr, info_ptr)
+}
 
-    // TODO: Actual context switch
-    E_OK
+/// 12. Answer a port_call through its one-shot reply capability
+fn sys_port_reply(reply_cap: u32, msg_ptr: *const u64, len: usize, xfer: CapTransfer) -> u64 {
+    port_reply(reply_cap, msg_ptr, len, xfer)
 }
 
-/// 10. Get monotonic time
-fn sys_time() -> u64 {
-    // Read TSC (Time Stamp Counter) on x86_64
-    // For now, return a dummy value
-    // Real implementation: read rdmsr(0x10)
-    0x1000
+/// 13. Destroy a port, port set, notification or memory object; later uses fail with E_PORT_DEAD
+fn sys_port_destroy(port_slot: u32) -> u64 {
+    port_destroy(port_slot)
+}
+
+/// 14. Register for a MSG_PORT_DIED notification when a port is destroyed
+fn sys_port_watch(port_slot: u32, notify_slot: u32, cookie: u64) -> u64 {
+    port_watch(port_slot, notify_slot, cookie)
+}
+
+/// 15. Allocate an empty port set
+fn sys_port_set_allocate() -> u64 {
+    port_set_allocate()
+}
+
+/// 16. Add a port to a port set
+fn sys_port_set_add(set_slot: u32, port_slot: u32) -> u64 {
+    port_set_add(set_slot, port_slot)
+}
+
+/// 17. Remove a port from a port set
+fn sys_port_set_remove(set_slot: u32, port_slot: u32) -> u64 {
+    port_set_remove(set_slot, port_slot)
+}
+
+/// 18. Allocate a notification (signal bits, received like a port)
+fn sys_notify_allocate() -> u64 {
+    notify_allocate()
+}
+
+/// 19. OR bits into a notification, waking its receiver
+fn sys_notify_signal(notify_slot: u32, bits: u64) -> u64 {
+    notify_signal(notify_slot, bits)
+}
+
+/// 20. Report physical memory and kernel heap usage
+fn sys_mem_stats(stats_ptr: *mut MemoryStats) -> u64 {
+    let stats = crate::allocator::memory_stats();
+    match current_user_space().and_then(|space| space.write(stats_ptr as u64, &stats)) {
+        Ok(()) => E_OK,
+        Err(e) => e,
+    }
+}
+
+/// 21. Create a shared memory object
+fn sys_memobj_allocate(size: u64) -> u64 {
+    memobj_allocate(size)
+}
+
+/// 22. Map a memory object into the caller's address space
+fn sys_memobj_map(slot: u32, hint: u64, flags: u32) -> u64 {
+    memobj_map(slot, hint, flags)
+}
+
+/// 23. Revoke a capability and all capabilities derived from it
+fn sys_cap_revoke(slot: u32) -> u64 {
+    cap_revoke(slot)
+}
+
+/// 24. Register the port that receives the caller's page faults
+fn sys_vm_set_pager(port_slot: u32) -> u64 {
+    vm_set_pager(port_slot)
+}
+
+/// 25. Register the port that receives the caller's exceptions
+fn sys_set_exception_port(port_slot: u32) -> u64 {
+    set_exception_port(port_slot)
+}
+
+/// 26. Signal a notification on every timer tick at `hz` (0 = default); scheduler-only
+fn sys_timer_set(notify_slot: u32, hz: u32) -> u64 {
+    timer_set(notify_slot, hz)
+}
+
+/// 27. Get wall-clock time, in nanoseconds since the Unix epoch (UTC)
+fn sys_time_wall() -> u64 {
+    clock_wall()
+}
+
+/// 28. Report yields to a port and run the caller after each; scheduler-only
+fn sys_sched_set_port(port_slot: u32) -> u64 {
+    sched_set_port(port_slot)
 }
@@ -1,13 +1,110 @@
-pub struct CpuContext {
-    pub rsp: u64,
-    pub rip: u64,
-    // регистры
-}
+use crate::error::{E_INVAL, E_OK, E_PROCESS_NOT_FOUND};
+use crate::globals::{current_pid, kernel_state_mut, process_state, ProcessDescriptor, ProcessState};
+use crate::vm::AddressSpace;
+
+pub use crate::arch::{CpuContext, KernelStack};
+
+/// PID of init_server, the first user task
+pub const INIT_PID: u32 = 1;
+
+/// PID of scheduler_server, the second task init_server starts; only it
+/// may switch tasks or take the timer and yield reports
+pub const SCHEDULER_PID: u32 = 3;
 
 pub fn init_tasks() {
     // создаем первый task (init)
+    let mut state = kernel_state_mut();
+
+    let mut init = ProcessDescriptor::new(INIT_PID, 0, 0);
+    init.name[..11].copy_from_slice(b"init_server");
+    init.state = ProcessState::Running;
+    init.page_table_root = AddressSpace::new().expect("no memory for init_server's page tables").root();
+    init.context = crate::arch::context::boot_context().expect("no memory for init_server's FPU state");
+
+    state.processes.push(init);
+    state.current_process_id = INIT_PID;
+}
+
+/// Give a new process a kernel stack and a context that enters ring 3 at
+/// its entry point the first time it is switched to
+pub fn init_user_context(process: &mut ProcessDescriptor) -> Result<(), u64> {
+    let mut stack = KernelStack::new()?;
+    process.context = crate::arch::context::user_context(
+        &mut stack,
+        process.instruction_pointer,
+        process.stack_pointer,
+    )?;
+    process.kernel_stack = Some(stack);
+    Ok(())
+}
+
+/// Stop running the current process and run `target_pid` instead
+///
+/// The current process becomes Ready and returns from here, with E_OK,
+/// once something switches back to it. `target_pid` must be Ready; a
+/// blocked, dead or never started process is refused with E_INVAL.
+///
+/// Must be called with interrupts disabled, as on the syscall path.
+pub fn switch_task(target_pid: u32) -> u64 {
+    let mut state = kernel_state_mut();
+    let current = state.current_process_id;
+    if target_pid == current {
+        return E_OK;
+    }
+
+    let (from, to) = match (
+        state.processes.iter().position(|p| p.id == current),
+        state.processes.iter().position(|p| p.id == target_pid),
+    ) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return E_PROCESS_NOT_FOUND,
+    };
+
+    let target = &state.processes[to];
+    if target.state != ProcessState::Ready || !target.context.is_set_up() {
+        return E_INVAL;
+    }
+    if !state.processes[from].context.is_set_up() {
+        return E_INVAL;  // Nowhere to save the current process
+    }
+
+    let root = target.page_table_root;
+    if state.processes[from].state == ProcessState::Running {
+        state.processes[from].state = ProcessState::Ready;
+    }
+    state.processes[to].state = ProcessState::Running;
+    state.current_process_id = target_pid;
+
+    // The contexts stay where they are until the switch is done: nothing
+    // else runs to grow the process table in between
+    let from_context = &mut state.processes[from].context as *mut CpuContext;
+    let to_context = &mut state.processes[to].context as *mut CpuContext;
+    drop(state);
+
+    if root != 0 {
+        crate::arch::switch_address_space(root);
+    }
+    unsafe { crate::arch::context::switch_to(from_context, to_context) }
+}
+
+/// Kill the current process after a fault it cannot recover from
+///
+/// The process is marked Dead and never scheduled again; this CPU idles
+/// until the scheduler switches away.
+pub fn kill_current() -> ! {
+    let pid = current_pid();
+    kernel_state_mut().set_process_state(pid, ProcessState::Dead);
+    crate::serial::write_str("[task] Killed process after unhandled fault\n");
+
+    loop {
+        crate::arch::wait_for_interrupt();
+    }
 }
 
-pub fn switch_task(_from: &mut CpuContext, _to: &CpuContext) {
-    // контекст-свитч
+/// Block the current process until it is woken (state leaves Sleeping)
+pub fn block_current() {
+    let pid = current_pid();
+    while process_state(pid) == Some(ProcessState::Sleeping) {
+        crate::arch::wait_for_interrupt();
+    }
 }
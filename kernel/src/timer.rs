@@ -0,0 +1,133 @@
+// kernel/src/timer.rs
+// Timer ticks - the interrupt that lets scheduler_server preempt tasks
+//
+// The kernel does not pick tasks itself. scheduler_server registers a
+// notification with timer_set, and on every tick the kernel signals
+// NOTIFY_TIMER_TICK on it and switches to the scheduler, which puts the
+// interrupted task back in its queue and runs the next with
+// SYS_SCHED_SWITCH. Ticks merge like any notification bits, so a scheduler
+// that falls behind sees one tick, not a backlog.
+//
+// Only scheduler_server, at the fixed SCHEDULER_PID, may register. Until it
+// does, ticks are taken and dropped.
+
+use crate::error::*;
+use crate::globals::*;
+use crate::ipc::{lookup_notification, signal_notification};
+use crate::task;
+
+/// Signal the notification in `notify_slot` on every timer tick, `hz`
+/// times a second (0 = TIMER_DEFAULT_HZ)
+///
+/// Needs `CAP_SEND` on the notification. The caller is switched to after
+/// each tick. Fails with E_INVAL for a rate outside TIMER_MIN_HZ..=
+/// TIMER_MAX_HZ and E_NO_RIGHTS unless the caller is scheduler_server.
+pub fn timer_set(notify_slot: u32, hz: u32) -> u64 {
+    let hz = if hz == 0 { TIMER_DEFAULT_HZ } else { hz };
+    if !(TIMER_MIN_HZ..=TIMER_MAX_HZ).contains(&hz) {
+        return E_INVAL;
+    }
+
+    let mut state = kernel_state_mut();
+    let pid = state.current_process_id;
+    if let Err(e) = register(&mut state, pid, notify_slot) {
+        return e;
+    }
+    drop(state);
+
+    crate::arch::timer::set_rate(hz);
+    E_OK
+}
+
+/// Make `pid`'s notification in `slot` the tick target
+fn register(state: &mut KernelState, pid: u32, slot: u32) -> Result<(), u64> {
+    if pid != task::SCHEDULER_PID {
+        return Err(E_NO_RIGHTS);
+    }
+
+    let (idx, _) = lookup_notification(state, pid, slot, CAP_SEND)?;
+    state.timer = Some(TimerTarget { pid, notify_id: state.notifications[idx].id });
+    Ok(())
+}
+
+/// Handle a timer tick; called by the timer interrupt after acknowledging it
+///
+/// Interrupts are only enabled in user mode and for the hlt in
+/// wait_for_interrupt, whose callers hold no lock. Should the state be
+/// locked anyway, the tick is dropped rather than spinning on a lock its
+/// holder can never release.
+pub fn timer_tick() {
+    let next = match KERNEL_STATE.try_lock() {
+        Some(mut state) => tick(&mut state),
+        None => return,
+    };
+    if let Some(pid) = next {
+        task::switch_task(pid);
+    }
+}
+
+/// Signal the tick, returning the scheduler if it should run now
+fn tick(state: &mut KernelState) -> Option<u32> {
+    let target = state.timer?;
+
+    // Notification IDs are never reused, so a missing one was destroyed
+    let idx = match state.notifications.iter().position(|n| n.id == target.notify_id) {
+        Some(idx) => idx,
+        None => {
+            state.timer = None;
+            return None;
+        }
+    };
+    signal_notification(state, idx, NOTIFY_TIMER_TICK);
+
+    // A scheduler blocked in something other than its receive runs once
+    // that finishes, and sees the tick then
+    let ready = state.processes.iter().any(|p| p.id == target.pid && p.state == ProcessState::Ready);
+    if ready && state.current_process_id != target.pid {
+        Some(target.pid)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sched::with_scheduler;
+
+    #[test]
+    fn test_tick_signals_and_runs_scheduler() {
+        let (mut state, _, slot) = with_scheduler();
+        assert_eq!(tick(&mut state), None, "No ticks before a scheduler registers");
+
+        assert_eq!(register(&mut state, 3, slot), Ok(()));
+        state.processes[1].state = ProcessState::Sleeping;
+        state.notifications[0].add_waiter(3);
+
+        assert_eq!(tick(&mut state), Some(3));
+        assert_eq!(state.notifications[0].bits, NOTIFY_TIMER_TICK);
+        assert_eq!(state.processes[1].state, ProcessState::Ready, "Woken from its receive");
+
+        // Ticks merge while the scheduler is busy
+        state.current_process_id = 3;
+        state.processes[1].state = ProcessState::Running;
+        assert_eq!(tick(&mut state), None);
+        assert_eq!(state.notifications[0].take_bits(), NOTIFY_TIMER_TICK);
+    }
+
+    #[test]
+    fn test_only_scheduler_takes_ticks() {
+        let (mut state, _, slot) = with_scheduler();
+        assert_eq!(register(&mut state, 2, slot), Err(E_NO_RIGHTS), "Even with no one registered");
+        assert_eq!(register(&mut state, 3, slot), Ok(()));
+
+        // Not even once the scheduler is dead
+        state.processes[1].state = ProcessState::Dead;
+        assert_eq!(register(&mut state, 2, slot), Err(E_NO_RIGHTS));
+
+        // A destroyed notification stops the ticks
+        state.notifications.clear();
+        assert_eq!(tick(&mut state), None);
+        assert_eq!(state.timer, None);
+    }
+}
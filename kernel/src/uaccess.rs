@@ -0,0 +1,210 @@
+// kernel/src/uaccess.rs
+// User memory access - checked copies between kernel and user space
+//
+// Syscalls never dereference user pointers directly. Every access goes
+// through a UserSpace, which checks the range against the calling
+// process's memory bounds and page tables and copies with the arch fault
+// fixup, so a bad pointer fails the syscall with E_FAULT instead of
+// crashing the kernel. Each page must be mapped for user access, and
+// writable by the user for a write: the kernel must not write where the
+// process itself could not, such as its code or a memory object it may
+// only read. Pages unmapped after the check are caught by the fixup.
+
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use crate::error::*;
+use crate::frame::FRAME_SIZE;
+use crate::globals::{try_vec, KernelState, ProcessDescriptor};
+use crate::vm::AddressSpace;
+
+/// The user address range of one process
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UserSpace {
+    pub start: u64,
+    pub end: u64,   // Exclusive
+    pub root: u64,  // Page tables to check against; 0 checks the range only
+}
+
+impl UserSpace {
+    pub fn of(process: &ProcessDescriptor) -> Self {
+        Self {
+            start: process.memory_start,
+            end: process.memory_end,
+            root: process.page_table_root,
+        }
+    }
+
+    /// Check that user code may read `[addr, addr + len)`
+    ///
+    /// Empty ranges are always accepted, whatever the address.
+    pub fn check(&self, addr: u64, len: usize) -> Result<(), u64> {
+        self.check_access(addr, len, false)
+    }
+
+    /// Check that user code may write `[addr, addr + len)`
+    pub fn check_writable(&self, addr: u64, len: usize) -> Result<(), u64> {
+        self.check_access(addr, len, true)
+    }
+
+    fn check_access(&self, addr: u64, len: usize, write: bool) -> Result<(), u64> {
+        if len == 0 {
+            return Ok(());
+        }
+
+        let end = match addr.checked_add(len as u64) {
+            Some(end) if addr >= self.start && end <= self.end => end,
+            _ => return Err(E_FAULT),
+        };
+
+        if self.root != 0 {
+            let space = AddressSpace::from_root(self.root);
+            let first = addr & !(FRAME_SIZE - 1);
+            for page in (first..end).step_by(FRAME_SIZE as usize) {
+                if !space.is_user_accessible(page, write) {
+                    return Err(E_FAULT);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy `dst.len()` bytes from user address `src`
+    pub fn copy_from_user(&self, dst: &mut [u8], src: u64) -> Result<(), u64> {
+        self.check(src, dst.len())?;
+        copy(dst.as_mut_ptr(), src as *const u8, dst.len())
+    }
+
+    /// Copy `src` to user address `dst`
+    pub fn copy_to_user(&self, dst: u64, src: &[u8]) -> Result<(), u64> {
+        self.check_writable(dst, src.len())?;
+        copy(dst as *mut u8, src.as_ptr(), src.len())
+    }
+
+    /// Read a plain-data value (no pointers or invariants) from user memory
+    pub fn read<T: Copy>(&self, src: u64) -> Result<T, u64> {
+        let mut value = MaybeUninit::<T>::zeroed();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        self.copy_from_user(bytes, src)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Write a plain-data value to user memory
+    pub fn write<T: Copy>(&self, dst: u64, value: &T) -> Result<(), u64> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>())
+        };
+        self.copy_to_user(dst, bytes)
+    }
+
+    /// Read `len` u64 words from user memory
+    ///
+    /// The range is checked before anything is allocated for it.
+    pub fn read_words(&self, src: u64, len: usize) -> Result<Vec<u64>, u64> {
+        let size = len.checked_mul(size_of::<u64>()).ok_or(E_FAULT)?;
+        self.check(src, size)?;
+
+        let mut words = try_vec(0u64, len)?;
+        let bytes = unsafe { core::slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, size) };
+        self.copy_from_user(bytes, src)?;
+        Ok(words)
+    }
+
+    /// Write u64 words to user memory
+    pub fn write_words(&self, dst: u64, words: &[u64]) -> Result<(), u64> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(words.as_ptr() as *const u8, core::mem::size_of_val(words))
+        };
+        self.copy_to_user(dst, bytes)
+    }
+
+    /// Read a NUL-terminated string of at most `dst.len()` bytes into `dst`,
+    /// returning its length
+    ///
+    /// Bytes are fetched one at a time so a short string at the end of the
+    /// address space does not fault. A string with no NUL in range is
+    /// truncated.
+    pub fn read_str(&self, src: u64, dst: &mut [u8]) -> Result<usize, u64> {
+        for (i, out) in dst.iter_mut().enumerate() {
+            let byte: u8 = self.read(src.checked_add(i as u64).ok_or(E_FAULT)?)?;
+            if byte == 0 {
+                return Ok(i);
+            }
+            *out = byte;
+        }
+        Ok(dst.len())
+    }
+}
+
+/// Address space of process `pid`
+pub fn user_space(state: &KernelState, pid: u32) -> Result<UserSpace, u64> {
+    state
+        .processes
+        .iter()
+        .find(|p| p.id == pid)
+        .map(UserSpace::of)
+        .ok_or(E_PROCESS_NOT_FOUND)
+}
+
+/// Address space of the current process
+pub fn current_user_space() -> Result<UserSpace, u64> {
+    let state = crate::globals::kernel_state_mut();
+    user_space(&state, state.current_process_id)
+}
+
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), u64> {
+    if unsafe { crate::arch::copy_user_bytes(dst, src, len) } {
+        Ok(())
+    } else {
+        Err(E_FAULT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const SPACE: UserSpace = UserSpace { start: 0x1000, end: 0x8000_0000_0000, root: 0 };
+
+    #[test]
+    fn test_check_user_range() {
+        assert_eq!(SPACE.check(0x1000, 8), Ok(()));
+        assert_eq!(SPACE.check(0x8000_0000_0000 - 8, 8), Ok(()));
+        assert_eq!(SPACE.check(0, 0), Ok(()), "Empty ranges are never touched");
+
+        assert_eq!(SPACE.check(0, 8), Err(E_FAULT), "Null is below the user range");
+        assert_eq!(SPACE.check(0x8000_0000_0000 - 8, 9), Err(E_FAULT));
+        assert_eq!(SPACE.check(0xFFFF_8000_0000_0000, 8), Err(E_FAULT), "Kernel half");
+        assert_eq!(SPACE.check(u64::MAX - 4, 8), Err(E_FAULT), "Wrapping range");
+    }
+
+    #[test]
+    fn test_copy_round_trip() {
+        let mut user = [0u64; 4];
+        let addr = user.as_mut_ptr() as u64;
+
+        assert_eq!(SPACE.write_words(addr, &[1, 2, 3]), Ok(()));
+        assert_eq!(SPACE.read_words(addr, 4), Ok(vec![1, 2, 3, 0]));
+        assert_eq!(SPACE.read::<u64>(addr + 8), Ok(2));
+        assert_eq!(SPACE.read_words(0x10, 1), Err(E_FAULT));
+
+        // Huge lengths fail before anything is allocated
+        assert_eq!(SPACE.read_words(addr, usize::MAX), Err(E_FAULT));
+        assert_eq!(SPACE.read_words(addr, usize::MAX / 8), Err(E_FAULT));
+    }
+
+    #[test]
+    fn test_read_str() {
+        let user = *b"init\0garbage";
+        let mut name = [0u8; 8];
+        assert_eq!(SPACE.read_str(user.as_ptr() as u64, &mut name), Ok(4));
+        assert_eq!(&name[..5], b"init\0");
+
+        // No NUL within the limit: truncated
+        let mut short = [0u8; 2];
+        assert_eq!(SPACE.read_str(user.as_ptr() as u64, &mut short), Ok(2));
+        assert_eq!(&short, b"in");
+    }
+}
@@ -0,0 +1,1149 @@
+// kernel/src/vm.rs
+// Virtual memory - page tables and per-process address spaces
+//
+// Every process gets its own PML4. The kernel half (PML4 entries 256..512)
+// points at the same tables in every address space, so kernel mappings
+// such as the heap appear everywhere at once. The boot stub identity-maps
+// physical memory below MAX_PHYS_MEMORY and the kernel image lives there,
+// so that window is shared as well; user space starts above it. Kernel
+// mappings never carry USER_ACCESSIBLE, so a process can only touch pages
+// mapped into its own half.
+//
+// User memory comes from vm_allocate, which backs a region with fresh
+// zeroed frames and records it in the process's vm_regions. Memory objects
+// are the shared alternative: their frames belong to the object, and any
+// process holding a capability to it can map them with memobj_map. Such
+// mappings last only as long as the capability they were made through.
+
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::error::*;
+use crate::frame::{allocate_frame, free_frame, FRAME_SIZE, MAX_PHYS_MEMORY};
+use crate::globals::*;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+/// Virtual address physical memory is mapped at; the boot stub
+/// identity-maps it, so page tables are reached at their physical address
+pub const PHYS_OFFSET: u64 = 0;
+
+/// First user address, just above the shared physical memory window
+pub const USER_SPACE_START: u64 = MAX_PHYS_MEMORY;
+
+/// End of user space (exclusive), where the canonical lower half ends
+pub const USER_SPACE_END: u64 = 0x8000_0000_0000;
+
+/// Largest region one vm_allocate can map
+pub const VM_MAX_ALLOCATION: u64 = 0x4000_0000;  // 1 GB
+
+/// First PML4 entry of the kernel half
+const KERNEL_PML4_START: usize = 256;
+
+/// PDPT entries (1 GB each) covering the shared physical memory window
+const PHYS_WINDOW_PDPT_ENTRIES: usize = (MAX_PHYS_MEMORY >> 30) as usize;
+
+/// Physical address of the kernel's own PML4, the template for new address spaces
+static KERNEL_PML4: Mutex<u64> = Mutex::new(0);
+
+/// Take over the boot page tables as the kernel address space
+///
+/// Fills every kernel-half PML4 entry with a (possibly empty) table so
+/// that later kernel mappings land in tables all address spaces share, and
+/// turns on no-execute support for W^X. Write protection is turned on too,
+/// so the kernel faults like user code on a read-only user page.
+pub fn init() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    let (level_4, _) = Cr3::read();
+    let root = level_4.start_address().as_u64();
+    let pml4 = unsafe { table_mut(root) };
+
+    for entry in pml4.iter_mut().skip(KERNEL_PML4_START) {
+        if entry.is_unused() {
+            match new_table(&mut PageTableFrames) {
+                Some(frame) => entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE),
+                None => panic!("vm: out of frames for kernel page tables"),
+            }
+        }
+    }
+
+    *KERNEL_PML4.lock() = root;
+}
+
+/// A process address space, named by the physical address of its PML4
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AddressSpace {
+    root: u64,
+}
+
+impl AddressSpace {
+    /// Create an address space sharing the kernel's mappings and with an
+    /// empty user half
+    pub fn new() -> Result<Self, u64> {
+        Self::with_kernel(*KERNEL_PML4.lock(), &mut PageTableFrames)
+    }
+
+    /// Create an address space sharing the mappings of `kernel_root`
+    pub fn with_kernel(kernel_root: u64, frames: &mut impl TableFrames) -> Result<Self, u64> {
+        let kernel = unsafe { table_mut(kernel_root) };
+        let root = new_table(frames).ok_or(E_NOMEM)?;
+        let pml4 = unsafe { table_mut(root.start_address().as_u64()) };
+
+        for i in KERNEL_PML4_START..512 {
+            pml4[i] = kernel[i].clone();
+        }
+
+        // The physical memory window shares PML4 entry 0 with the bottom of
+        // user space, so it gets a private PDPT pointing at the kernel's
+        // tables for the window
+        if !kernel[0].is_unused() {
+            let pdpt = match new_table(frames) {
+                Some(frame) => frame,
+                None => {
+                    unsafe { frames.deallocate_frame(root) };
+                    return Err(E_NOMEM);
+                }
+            };
+
+            let kernel_pdpt = unsafe { table_mut(kernel[0].addr().as_u64()) };
+            let user_pdpt = unsafe { table_mut(pdpt.start_address().as_u64()) };
+            for i in 0..PHYS_WINDOW_PDPT_ENTRIES {
+                user_pdpt[i] = kernel_pdpt[i].clone();
+            }
+            pml4[0].set_frame(pdpt, kernel[0].flags());
+        }
+
+        Ok(Self { root: root.start_address().as_u64() })
+    }
+
+    /// The address space loaded from a saved CR3 value
+    pub fn from_root(root: u64) -> Self {
+        Self { root }
+    }
+
+    /// Physical address of the PML4, the value loaded into CR3
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Map the user page containing `virt` to frame `phys`
+    ///
+    /// USER_ACCESSIBLE is added to `flags`. Fails with E_FAULT outside
+    /// user space, E_INVAL if the page is already mapped and E_NOMEM if a
+    /// page table cannot be allocated.
+    pub fn map_user_page(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        flags: PageTableFlags,
+        frames: &mut impl TableFrames,
+    ) -> Result<(), u64> {
+        if !(USER_SPACE_START..USER_SPACE_END).contains(&virt) {
+            return Err(E_FAULT);
+        }
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt));
+        let frame = phys_frame(phys);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        let mapped = unsafe {
+            self.mapper().map_to_with_table_flags(page, frame, flags, table_flags, frames)
+        };
+
+        match mapped {
+            // Only the active address space has the mapping cached; the
+            // caller flushes it if that matters
+            Ok(flush) => {
+                flush.ignore();
+                Ok(())
+            }
+            Err(MapToError::PageAlreadyMapped(_)) => Err(E_INVAL),
+            Err(_) => Err(E_NOMEM),
+        }
+    }
+
+    /// Remove the user page containing `virt`, returning the frame it mapped
+    ///
+    /// The TLB is not flushed; the caller does that if this address space
+    /// is loaded.
+    pub fn unmap_user_page(&mut self, virt: u64) -> Option<u64> {
+        if !(USER_SPACE_START..USER_SPACE_END).contains(&virt) {
+            return None;
+        }
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt));
+        match unsafe { self.mapper() }.unmap(page) {
+            Ok((frame, flush)) => {
+                flush.ignore();
+                Some(frame.start_address().as_u64())
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Physical address `virt` maps to, if any
+    pub fn translate(&self, virt: u64) -> Option<u64> {
+        let mapper = unsafe { self.mapper() };
+        mapper.translate_addr(VirtAddr::try_new(virt).ok()?).map(|p| p.as_u64())
+    }
+
+    /// Whether user code may access `virt`, and write to it if `write`
+    pub fn is_user_accessible(&self, virt: u64, write: bool) -> bool {
+        let addr = match VirtAddr::try_new(virt) {
+            Ok(addr) => addr,
+            Err(_) => return false,
+        };
+
+        // Every level on the way down must allow the access
+        let mut user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if write {
+            user |= PageTableFlags::WRITABLE;
+        }
+        let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+        let mut table = unsafe { table_mut(self.root) };
+
+        for (level, &index) in indices.iter().enumerate() {
+            let entry = &table[index];
+            if !entry.flags().contains(user) {
+                return false;
+            }
+            if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return true;
+            }
+            table = unsafe { table_mut(entry.addr().as_u64()) };
+        }
+        false
+    }
+
+    /// Free the PML4 and the page tables of the user half
+    ///
+    /// User pages must be unmapped first; their frames are not freed here.
+    /// The kernel's tables, shared with every address space, are left alone.
+    pub fn destroy(self, frames: &mut impl TableFrames) {
+        let pml4 = unsafe { table_mut(self.root) };
+        for (i, entry) in pml4.iter().enumerate().take(KERNEL_PML4_START) {
+            if entry.is_unused() {
+                continue;
+            }
+            // Entry 0's PDPT begins with the kernel's physical memory window
+            let shared = if i == 0 { PHYS_WINDOW_PDPT_ENTRIES } else { 0 };
+            free_tables(entry.addr().as_u64(), 3, shared, frames);
+        }
+        unsafe { frames.deallocate_frame(phys_frame(self.root)) };
+    }
+
+    /// # Safety
+    ///
+    /// No other mapper over the same tables may be alive.
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(table_mut(self.root), VirtAddr::new(PHYS_OFFSET))
+    }
+}
+
+/// Map `size` bytes of fresh zeroed memory into the current process
+///
+/// With `hint` 0 the lowest free range of user space is used; otherwise
+/// the region goes exactly at `hint`, which must be page aligned and must
+/// not overlap an existing region. `flags` are VM_* protection bits;
+/// writable and executable together is refused. Returns the start of the
+/// region.
+pub fn vm_allocate(hint: u64, size: u64, flags: u32) -> u64 {
+    if size == 0 || size > VM_MAX_ALLOCATION {
+        return E_INVAL;
+    }
+
+    if (hint & (FRAME_SIZE - 1)) != 0 {
+        return E_ALIGN;  // Must be 4 KB aligned
+    }
+
+    let size = size.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+    let page_flags = match protection_flags(flags) {
+        Ok(f) => f,
+        Err(e) => return e,
+    };
+
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+    let process = match state.process_mut(current_pid) {
+        Some(p) => p,
+        None => return E_PROCESS_NOT_FOUND,
+    };
+
+    if process.page_table_root == 0 {
+        return E_INVAL;  // No address space to map into
+    }
+
+    let start = match place_region(&process.vm_regions, hint, size) {
+        Ok(start) => start,
+        Err(e) => return e,
+    };
+
+    if let Err(e) = reserve_one(&mut process.vm_regions) {
+        return e;
+    }
+
+    let mut space = AddressSpace::from_root(process.page_table_root);
+    if let Err(e) = map_region(&mut space, start, size, page_flags) {
+        return e;
+    }
+
+    let region = VmRegion { start, size, flags, object_id: 0, cap_id: 0 };
+    let idx = process.vm_regions.partition_point(|r| r.start < start);
+    process.vm_regions.insert(idx, region);
+
+    start
+}
+
+/// Unmap a region of the current process and free its frames
+///
+/// `addr` and `size` must describe a whole region from vm_allocate (the
+/// size rounded up to pages like vm_allocate does).
+pub fn vm_deallocate(addr: u64, size: u64) -> u64 {
+    if size == 0 {
+        return E_INVAL;
+    }
+
+    if (addr & (FRAME_SIZE - 1)) != 0 {
+        return E_ALIGN;
+    }
+
+    let size = size.div_ceil(FRAME_SIZE) * FRAME_SIZE;
+
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+    let process = match state.process_mut(current_pid) {
+        Some(p) => p,
+        None => return E_PROCESS_NOT_FOUND,
+    };
+
+    let idx = match process.vm_regions.iter().position(|r| r.start == addr && r.size == size) {
+        Some(i) => i,
+        None => return E_INVAL,
+    };
+    let region = process.vm_regions.remove(idx);
+
+    // Memory object frames stay with the object
+    let mut space = AddressSpace::from_root(process.page_table_root);
+    unmap_region(&mut space, addr, size, region.object_id == 0);
+
+    E_OK
+}
+
+/// Create a memory object of `size` bytes of zeroed memory
+///
+/// Returns the CSpace slot of a capability with full rights to it. Copies
+/// with fewer rights (from cap_move, or attached to a message) let other
+/// processes map it. port_destroy on the capability frees it.
+pub fn memobj_allocate(size: u64) -> u64 {
+    if size == 0 || size > VM_MAX_ALLOCATION {
+        return E_INVAL;
+    }
+
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    if !state.processes.iter().any(|p| p.id == current_pid) {
+        return E_PROCESS_NOT_FOUND;
+    }
+
+    if let Err(e) = reserve_one(&mut state.memory_objects) {
+        return e;
+    }
+
+    let mut frames = match try_vec(0u64, size.div_ceil(FRAME_SIZE) as usize) {
+        Ok(frames) => frames,
+        Err(e) => return e,
+    };
+
+    for i in 0..frames.len() {
+        match allocate_frame() {
+            Some(frame) => {
+                unsafe { core::ptr::write_bytes((PHYS_OFFSET + frame) as *mut u8, 0, FRAME_SIZE as usize) };
+                frames[i] = frame;
+            }
+            None => {
+                frames[..i].iter().for_each(|&f| { free_frame(f); });
+                return E_NOMEM;
+            }
+        }
+    }
+
+    let mut next_id = NEXT_MEMORY_OBJECT_ID.lock();
+    let object_id = *next_id;
+    *next_id += 1;
+
+    let mut cap_id = NEXT_CAP_ID.lock();
+    let mut capability = Capability::new(
+        *cap_id,
+        current_pid,
+        object_id,
+        CAP_READ | CAP_WRITE | CAP_EXECUTE | CAP_DESTROY | CAP_DERIVE,
+    );
+    capability.kind = CapabilityKind::MemoryObject;
+    *cap_id += 1;
+
+    let slot = match state.process_mut(current_pid).and_then(|p| p.cspace.insert(capability)) {
+        Some(slot) => slot,
+        None => {
+            frames.iter().for_each(|&f| { free_frame(f); });
+            return E_NOMEM;
+        }
+    };
+
+    state.memory_objects.push(MemoryObject { id: object_id, owner_pid: current_pid, frames });
+
+    slot as u64
+}
+
+/// Map the memory object in `slot` into the current process
+///
+/// `hint` and `flags` work as for vm_allocate. Mapping needs `CAP_READ`,
+/// plus `CAP_WRITE` for VM_WRITE and `CAP_EXECUTE` for VM_EXEC. The
+/// mapping is removed with vm_deallocate, or by the kernel once the
+/// capability is revoked. Returns the start of the mapping.
+pub fn memobj_map(slot: u32, hint: u64, flags: u32) -> u64 {
+    if (hint & (FRAME_SIZE - 1)) != 0 {
+        return E_ALIGN;
+    }
+
+    if let Err(e) = protection_flags(flags) {
+        return e;
+    }
+
+    let mut state = kernel_state_mut();
+    let current_pid = state.current_process_id;
+
+    let (idx, cap_id) = match lookup_object(&state, current_pid, slot, flags) {
+        Ok(found) => found,
+        Err(e) => return e,
+    };
+
+    let pages = 0..state.memory_objects[idx].frames.len();
+    match map_object(&mut state, current_pid, idx, pages, hint, flags, cap_id) {
+        Ok(start) => start,
+        Err(e) => e,
+    }
+}
+
+/// Map one page of the memory object in `slot` of process `pid` at `page`,
+/// for a pager resolving a fault there
+///
+/// `offset` is the byte offset of the page within the object. A page an
+/// earlier fault mapped at the same address is replaced, which lets a
+/// pager swap a shared read-only page for a private copy; the capability
+/// it was mapped through is dropped with it.
+pub fn map_fault_page(
+    state: &mut KernelState,
+    pid: u32,
+    slot: u32,
+    page: u64,
+    offset: u64,
+    flags: u32,
+) -> Result<(), u64> {
+    if (page & (FRAME_SIZE - 1)) != 0 || (offset & (FRAME_SIZE - 1)) != 0 {
+        return Err(E_ALIGN);
+    }
+
+    protection_flags(flags)?;
+    let (idx, cap_id) = lookup_object(state, pid, slot, flags)?;
+
+    let first = (offset / FRAME_SIZE) as usize;
+    if first >= state.memory_objects[idx].frames.len() {
+        return Err(E_INVAL);
+    }
+
+    if let Some(process) = state.process_mut(pid) {
+        drop_fault_page(process, page, cap_id);
+    }
+
+    map_object(state, pid, idx, first..first + 1, page, flags, cap_id).map(|_| ())
+}
+
+/// Unmap the page an earlier fault mapped at `page`, if any, and remove the
+/// capability it was mapped through unless that is `keep_cap` or still
+/// maps something else
+///
+/// Each pager reply hands the faulting process a new capability, so
+/// without this a long-running process would fill its CSpace.
+fn drop_fault_page(process: &mut ProcessDescriptor, page: u64, keep_cap: u32) {
+    let root = process.page_table_root;
+    let mut dropped = 0;
+    process.vm_regions.retain(|r| {
+        let replaced = r.start == page && r.size == FRAME_SIZE && r.object_id != 0;
+        if replaced {
+            if root != 0 {
+                unmap_region(&mut AddressSpace::from_root(root), page, FRAME_SIZE, false);
+            }
+            dropped = r.cap_id;
+        }
+        !replaced
+    });
+
+    if dropped == 0 || dropped == keep_cap || process.vm_regions.iter().any(|r| r.cap_id == dropped) {
+        return;
+    }
+    for slot in process.cspace.slots.iter_mut() {
+        if slot.is_some_and(|c| c.id == dropped) {
+            *slot = None;
+        }
+    }
+}
+
+/// Resolve a memory object capability of `pid` for mapping with `flags`
+///
+/// Mapping needs `CAP_READ`, plus `CAP_WRITE` for VM_WRITE and
+/// `CAP_EXECUTE` for VM_EXEC. Returns the object's index in
+/// `state.memory_objects` and the capability's ID.
+fn lookup_object(state: &KernelState, pid: u32, slot: u32, flags: u32) -> Result<(usize, u32), u64> {
+    let cap = match state.lookup_cap(pid, slot) {
+        Some(c) if !c.revoked && c.kind == CapabilityKind::MemoryObject => c,
+        _ => return Err(E_CAP_INVALID),
+    };
+
+    let mut required = CAP_READ;
+    if (flags & VM_WRITE) != 0 {
+        required |= CAP_WRITE;
+    }
+    if (flags & VM_EXEC) != 0 {
+        required |= CAP_EXECUTE;
+    }
+    if (cap.rights & required) != required {
+        return Err(E_NO_RIGHTS);
+    }
+
+    // Object IDs are never reused, so a missing object was destroyed
+    match state.memory_objects.iter().position(|o| o.id == cap.target_id) {
+        Some(idx) => Ok((idx, cap.id)),
+        None => Err(E_PORT_DEAD),
+    }
+}
+
+/// Map `pages` of `state.memory_objects[idx]` into process `pid` at `hint`
+/// (0 = anywhere), recording the region against capability `cap_id`
+fn map_object(
+    state: &mut KernelState,
+    pid: u32,
+    idx: usize,
+    pages: Range<usize>,
+    hint: u64,
+    flags: u32,
+    cap_id: u32,
+) -> Result<u64, u64> {
+    let page_flags = protection_flags(flags)?;
+
+    let object = &state.memory_objects[idx];
+    let process = match state.processes.iter_mut().find(|p| p.id == pid) {
+        Some(p) => p,
+        None => return Err(E_PROCESS_NOT_FOUND),
+    };
+
+    if process.page_table_root == 0 {
+        return Err(E_INVAL);  // No address space to map into
+    }
+
+    let size = pages.len() as u64 * FRAME_SIZE;
+    let start = place_region(&process.vm_regions, hint, size)?;
+    reserve_one(&mut process.vm_regions)?;
+
+    let mut space = AddressSpace::from_root(process.page_table_root);
+    for (i, &frame) in object.frames[pages].iter().enumerate() {
+        let offset = i as u64 * FRAME_SIZE;
+        if let Err(e) = space.map_user_page(start + offset, frame, page_flags, &mut PageTableFrames) {
+            unmap_region(&mut space, start, offset, false);
+            return Err(e);
+        }
+    }
+
+    let region = VmRegion { start, size, flags, object_id: object.id, cap_id };
+    let idx = process.vm_regions.partition_point(|r| r.start < start);
+    process.vm_regions.insert(idx, region);
+
+    Ok(start)
+}
+
+/// Destroy the memory object in `slot` of the current process
+///
+/// Needs `CAP_DESTROY`. Every mapping of it, in any process, is removed
+/// before its frames are freed; later uses of its capabilities fail with
+/// `E_PORT_DEAD`.
+pub fn destroy_memory_object(state: &mut KernelState, slot: u32) -> u64 {
+    let current_pid = state.current_process_id;
+
+    let cap = match state.lookup_cap(current_pid, slot) {
+        Some(c) if !c.revoked && c.kind == CapabilityKind::MemoryObject => c,
+        _ => return E_CAP_INVALID,
+    };
+
+    if !cap.has_right(CAP_DESTROY) {
+        return E_NO_RIGHTS;
+    }
+
+    let idx = match state.memory_objects.iter().position(|o| o.id == cap.target_id) {
+        Some(i) => i,
+        None => return E_PORT_DEAD,
+    };
+    let object = state.memory_objects.remove(idx);
+
+    unmap_where(state, |r| r.object_id == object.id);
+
+    for &frame in object.frames.iter() {
+        free_frame(frame);
+    }
+
+    E_OK
+}
+
+/// Remove mappings of memory objects made through capabilities that are
+/// no longer live
+///
+/// Run after a revocation. A capability is live while it is unrevoked,
+/// whether in a CSpace or in transit.
+pub fn unmap_revoked(state: &mut KernelState) {
+    let live: Vec<u32> = state
+        .processes
+        .iter()
+        .flat_map(|p| p.cspace.slots.iter().flatten())
+        .chain(state.in_transit.iter())
+        .filter(|c| !c.revoked)
+        .map(|c| c.id)
+        .collect();
+
+    unmap_where(state, |r| r.cap_id != 0 && !live.contains(&r.cap_id));
+}
+
+/// Unmap every memory object region matching `pred`, in every process
+fn unmap_where(state: &mut KernelState, pred: impl Fn(&VmRegion) -> bool) {
+    for process in state.processes.iter_mut() {
+        let root = process.page_table_root;
+        process.vm_regions.retain(|region| {
+            if !pred(region) {
+                return true;
+            }
+            if root != 0 {
+                unmap_region(&mut AddressSpace::from_root(root), region.start, region.size, false);
+            }
+            false
+        });
+    }
+}
+
+/// Give a new process private copies of the regions of `parent` holding
+/// its entry point and initial stack, at the same addresses
+///
+/// `entry` must lie in an executable region and the byte below `stack` in
+/// a writable one (so never the same region, by W^X). The copies are made
+/// in `child` and returned as its vm_regions; on failure nothing is left
+/// mapped there.
+pub fn copy_spawn_regions(
+    parent: &ProcessDescriptor,
+    child: &mut AddressSpace,
+    entry: u64,
+    stack: u64,
+) -> Result<Vec<VmRegion>, u64> {
+    let (code, data) = spawn_regions(&parent.vm_regions, entry, stack)?;
+    if parent.page_table_root == 0 {
+        return Err(E_INVAL);
+    }
+
+    let mut copied: Vec<VmRegion> = Vec::new();
+    copied.try_reserve_exact(2).map_err(|_| E_NOMEM)?;
+
+    let from = AddressSpace::from_root(parent.page_table_root);
+    for region in [code, data] {
+        if let Err(e) = copy_region(&from, child, &region) {
+            for r in copied.iter() {
+                unmap_region(child, r.start, r.size, true);
+            }
+            return Err(e);
+        }
+        copied.push(VmRegion { object_id: 0, cap_id: 0, ..region });
+    }
+    Ok(copied)
+}
+
+/// The regions among `regions` holding `entry` (executable) and the top of
+/// the stack below `stack` (writable)
+fn spawn_regions(regions: &[VmRegion], entry: u64, stack: u64) -> Result<(VmRegion, VmRegion), u64> {
+    let find = |addr: u64, flag: u32| {
+        regions
+            .iter()
+            .find(|r| r.overlaps(addr, 1) && (r.flags & flag) != 0)
+            .copied()
+            .ok_or(E_INVAL)
+    };
+    Ok((find(entry, VM_EXEC)?, find(stack.wrapping_sub(1), VM_WRITE)?))
+}
+
+/// Map fresh frames for `region` in `to` and copy its pages from `from`
+fn copy_region(from: &AddressSpace, to: &mut AddressSpace, region: &VmRegion) -> Result<(), u64> {
+    map_region(to, region.start, region.size, protection_flags(region.flags)?)?;
+
+    for page in (region.start..region.end()).step_by(FRAME_SIZE as usize) {
+        // Pages a pager has not filled in yet stay zeroed
+        if let (Some(src), Some(dst)) = (from.translate(page), to.translate(page)) {
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (PHYS_OFFSET + src) as *const u8,
+                    (PHYS_OFFSET + dst) as *mut u8,
+                    FRAME_SIZE as usize,
+                )
+            };
+        }
+    }
+    Ok(())
+}
+
+/// Unmap a process's private regions, freeing their frames, then free its
+/// page tables
+pub fn destroy_address_space(mut space: AddressSpace, regions: &[VmRegion]) {
+    for region in regions {
+        unmap_region(&mut space, region.start, region.size, region.object_id == 0);
+    }
+    space.destroy(&mut PageTableFrames);
+}
+
+/// Page table flags for VM_* protection bits, enforcing W^X
+pub fn protection_flags(flags: u32) -> Result<PageTableFlags, u64> {
+    if (flags & !(VM_READ | VM_WRITE | VM_EXEC)) != 0 {
+        return Err(E_INVAL);
+    }
+
+    if (flags & VM_WRITE) != 0 && (flags & VM_EXEC) != 0 {
+        return Err(E_INVAL);
+    }
+
+    let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if (flags & VM_WRITE) != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if (flags & VM_EXEC) == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    Ok(page_flags)
+}
+
+/// Choose where a new region of `size` bytes goes among `regions`
+/// (sorted by address)
+///
+/// A non-zero `hint` is taken as the exact address and fails with E_INVAL
+/// if the region would overlap another or leave user space. Otherwise the
+/// lowest gap that fits is used, failing with E_NOMEM if there is none.
+pub fn place_region(regions: &[VmRegion], hint: u64, size: u64) -> Result<u64, u64> {
+    if hint != 0 {
+        let fits = hint >= USER_SPACE_START
+            && hint.checked_add(size).is_some_and(|end| end <= USER_SPACE_END);
+        if !fits || regions.iter().any(|r| r.overlaps(hint, size)) {
+            return Err(E_INVAL);
+        }
+        return Ok(hint);
+    }
+
+    let mut start = USER_SPACE_START;
+    for region in regions {
+        if start + size <= region.start {
+            break;
+        }
+        start = start.max(region.end());
+    }
+
+    if start + size <= USER_SPACE_END {
+        Ok(start)
+    } else {
+        Err(E_NOMEM)
+    }
+}
+
+/// Back `[start, start + size)` with fresh zeroed frames
+///
+/// On failure everything mapped so far is undone.
+fn map_region(space: &mut AddressSpace, start: u64, size: u64, flags: PageTableFlags) -> Result<(), u64> {
+    for offset in (0..size).step_by(FRAME_SIZE as usize) {
+        let mapped = allocate_frame().ok_or(E_NOMEM).and_then(|frame| {
+            unsafe { core::ptr::write_bytes((PHYS_OFFSET + frame) as *mut u8, 0, FRAME_SIZE as usize) };
+            space
+                .map_user_page(start + offset, frame, flags, &mut PageTableFrames)
+                .inspect_err(|_| {
+                    free_frame(frame);
+                })
+        });
+
+        if let Err(e) = mapped {
+            unmap_region(space, start, offset, true);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Unmap `[start, start + size)`, freeing the frames behind it if `owned`
+///
+/// Each page is flushed from the TLB in case `space` is the one loaded;
+/// other address spaces have nothing cached, as loading CR3 flushes
+/// everything but global (kernel) pages.
+fn unmap_region(space: &mut AddressSpace, start: u64, size: u64, owned: bool) {
+    for offset in (0..size).step_by(FRAME_SIZE as usize) {
+        if let Some(frame) = space.unmap_user_page(start + offset) {
+            tlb::flush(VirtAddr::new(start + offset));
+            if owned {
+                free_frame(frame);
+            }
+        }
+    }
+}
+
+/// Back the kernel page containing `virt` with a fresh frame
+///
+/// Kernel mappings go into the shared kernel half, so they are visible in
+/// every address space. Fails with E_NOMEM if no frame is left for the
+/// page or its page tables.
+pub fn map_kernel_page(virt: u64) -> Result<(), u64> {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt));
+    let frame = allocate_frame().ok_or(E_NOMEM)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::GLOBAL;
+
+    let mapped = unsafe {
+        let (level_4, _) = Cr3::read();
+        OffsetPageTable::new(table_mut(level_4.start_address().as_u64()), VirtAddr::new(PHYS_OFFSET))
+            .map_to(page, phys_frame(frame), flags, &mut PageTableFrames)
+    };
+
+    match mapped {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(_) => {
+            free_frame(frame);
+            Err(E_NOMEM)
+        }
+    }
+}
+
+/// Source of frames for page tables
+pub trait TableFrames: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB> {}
+
+impl<T: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>> TableFrames for T {}
+
+/// Supplies frames for page tables from the frame allocator
+pub struct PageTableFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for PageTableFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        allocate_frame().map(phys_frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for PageTableFrames {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        free_frame(frame.start_address().as_u64());
+    }
+}
+
+fn phys_frame(addr: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(addr))
+}
+
+/// Free the page table at `phys` and the tables below it, skipping its
+/// first `skip` entries; `level` 3 is a PDPT and 1 a page table
+fn free_tables(phys: u64, level: u32, skip: usize, frames: &mut impl TableFrames) {
+    if level > 1 {
+        let table = unsafe { table_mut(phys) };
+        for entry in table.iter().skip(skip) {
+            if !entry.is_unused() && !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                free_tables(entry.addr().as_u64(), level - 1, 0, frames);
+            }
+        }
+    }
+    unsafe { frames.deallocate_frame(phys_frame(phys)) };
+}
+
+/// A zeroed page table from `frames`
+fn new_table(frames: &mut impl TableFrames) -> Option<PhysFrame> {
+    let frame = frames.allocate_frame()?;
+    unsafe { table_mut(frame.start_address().as_u64()) }.zero();
+    Some(frame)
+}
+
+/// The page table at physical address `phys`
+///
+/// # Safety
+///
+/// `phys` must hold a page table and the caller must not alias it.
+unsafe fn table_mut(phys: u64) -> &'static mut PageTable {
+    &mut *((PHYS_OFFSET + phys) as *mut PageTable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uaccess::UserSpace;
+    use alloc::boxed::Box;
+
+    const RW: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+    /// Page tables in host memory; with PHYS_OFFSET 0 their address works
+    /// as a physical address
+    struct HostFrames;
+
+    unsafe impl FrameAllocator<Size4KiB> for HostFrames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame> {
+            let table = Box::leak(Box::new(PageTable::new()));
+            Some(phys_frame(table as *mut PageTable as u64))
+        }
+    }
+
+    impl FrameDeallocator<Size4KiB> for HostFrames {
+        unsafe fn deallocate_frame(&mut self, _frame: PhysFrame) {}
+    }
+
+    /// A kernel PML4 with a heap table in the kernel half and the physical
+    /// memory window in entry 0
+    fn kernel_tables() -> u64 {
+        let pml4 = new_table(&mut HostFrames).unwrap();
+        let heap = new_table(&mut HostFrames).unwrap();
+        let pdpt = new_table(&mut HostFrames).unwrap();
+        let window = new_table(&mut HostFrames).unwrap();
+
+        unsafe {
+            let kernel = table_mut(pml4.start_address().as_u64());
+            kernel[272].set_frame(heap, RW);
+            kernel[0].set_frame(pdpt, RW);
+            table_mut(pdpt.start_address().as_u64())[0].set_frame(window, RW);
+        }
+        pml4.start_address().as_u64()
+    }
+
+    #[test]
+    fn test_kernel_mappings_are_shared() {
+        let kernel_root = kernel_tables();
+        let a = AddressSpace::with_kernel(kernel_root, &mut HostFrames).unwrap();
+        let b = AddressSpace::with_kernel(kernel_root, &mut HostFrames).unwrap();
+        assert_ne!(a.root(), b.root());
+
+        let (kernel, a4, b4) = unsafe { (table_mut(kernel_root), table_mut(a.root()), table_mut(b.root())) };
+        assert_eq!(a4[272].addr(), kernel[272].addr(), "Kernel half points at the same tables");
+        assert_eq!(b4[272].addr(), kernel[272].addr());
+
+        // Entry 0 is private, but the window below it is the kernel's
+        assert_ne!(a4[0].addr(), kernel[0].addr());
+        let (kernel_pdpt, a_pdpt) = unsafe { (table_mut(kernel[0].addr().as_u64()), table_mut(a4[0].addr().as_u64())) };
+        assert_eq!(a_pdpt[0].addr(), kernel_pdpt[0].addr());
+        assert!(a_pdpt[PHYS_WINDOW_PDPT_ENTRIES].is_unused());
+    }
+
+    #[test]
+    fn test_user_pages_are_private() {
+        let kernel_root = kernel_tables();
+        let mut a = AddressSpace::with_kernel(kernel_root, &mut HostFrames).unwrap();
+        let b = AddressSpace::with_kernel(kernel_root, &mut HostFrames).unwrap();
+
+        let page = USER_SPACE_START + 0x5000;
+        let frame = HostFrames.allocate_frame().unwrap().start_address().as_u64();
+        assert_eq!(a.map_user_page(page, frame, PageTableFlags::WRITABLE, &mut HostFrames), Ok(()));
+
+        assert_eq!(a.translate(page + 0x10), Some(frame + 0x10));
+        assert!(a.is_user_accessible(page, true));
+        assert_eq!(b.translate(page), None, "Another process's page is not mapped here");
+        assert!(!b.is_user_accessible(page, false));
+
+        // The kernel window is mapped but never user accessible
+        assert!(!a.is_user_accessible(0x10_0000, false));
+
+        assert_eq!(a.map_user_page(page, frame, RW, &mut HostFrames), Err(E_INVAL), "Already mapped");
+        assert_eq!(a.map_user_page(0x10_0000, frame, RW, &mut HostFrames), Err(E_FAULT));
+        assert_eq!(a.map_user_page(USER_SPACE_END, frame, RW, &mut HostFrames), Err(E_FAULT));
+
+        assert_eq!(a.unmap_user_page(page), Some(frame));
+        assert_eq!(a.translate(page), None);
+        assert_eq!(a.unmap_user_page(page), None, "Nothing left to unmap");
+    }
+
+    /// Host frames that records what is freed
+    struct Recorded(Vec<u64>);
+
+    unsafe impl FrameAllocator<Size4KiB> for Recorded {
+        fn allocate_frame(&mut self) -> Option<PhysFrame> {
+            HostFrames.allocate_frame()
+        }
+    }
+
+    impl FrameDeallocator<Size4KiB> for Recorded {
+        unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+            self.0.push(frame.start_address().as_u64());
+        }
+    }
+
+    #[test]
+    fn test_destroy_frees_only_private_tables() {
+        let kernel_root = kernel_tables();
+        let mut space = AddressSpace::with_kernel(kernel_root, &mut HostFrames).unwrap();
+        let page = USER_SPACE_START + 0x5000;
+        assert_eq!(space.map_user_page(page, 0x1000, RW, &mut HostFrames), Ok(()));
+        assert_eq!(space.unmap_user_page(page), Some(0x1000));
+
+        // PML4, the private PDPT of entry 0, and a PD and page table below it
+        let mut frames = Recorded(Vec::new());
+        let root = space.root();
+        space.destroy(&mut frames);
+        assert_eq!(frames.0.len(), 4);
+        assert_eq!(frames.0.last(), Some(&root));
+
+        let kernel = unsafe { table_mut(kernel_root) };
+        let window = unsafe { table_mut(kernel[0].addr().as_u64()) }[0].addr().as_u64();
+        assert!(!frames.0.contains(&kernel[272].addr().as_u64()), "Kernel half is shared");
+        assert!(!frames.0.contains(&window), "So is the physical memory window");
+    }
+
+    #[test]
+    fn test_spawn_regions() {
+        let page = FRAME_SIZE;
+        let region = |start, flags| VmRegion { start, size: 2 * page, flags, object_id: 0, cap_id: 0 };
+        let code = region(USER_SPACE_START, VM_READ | VM_EXEC);
+        let data = region(USER_SPACE_START + 4 * page, VM_READ | VM_WRITE);
+        let regions = [code, data];
+
+        let stack = data.end();
+        assert_eq!(spawn_regions(&regions, code.start + 0x10, stack), Ok((code, data)));
+        assert_eq!(spawn_regions(&regions, data.start, stack), Err(E_INVAL), "Entry not executable");
+        assert_eq!(spawn_regions(&regions, code.start, code.end()), Err(E_INVAL), "Stack not writable");
+        assert_eq!(spawn_regions(&regions, code.start, stack + page), Err(E_INVAL), "Stack unmapped");
+        assert_eq!(spawn_regions(&[], code.start, stack), Err(E_INVAL));
+    }
+
+    #[test]
+    fn test_user_writes_need_writable_pages() {
+        let mut space = AddressSpace::with_kernel(kernel_tables(), &mut HostFrames).unwrap();
+
+        // Host pages mapped at their own address, so copies reach them
+        let mut map = |flags| {
+            let page = HostFrames.allocate_frame().unwrap().start_address().as_u64();
+            space.map_user_page(page, page, protection_flags(flags).unwrap(), &mut HostFrames).unwrap();
+            page
+        };
+        let ro = map(VM_READ);
+        let rx = map(VM_READ | VM_EXEC);
+        let rw = map(VM_READ | VM_WRITE);
+        let unmapped = HostFrames.allocate_frame().unwrap().start_address().as_u64();
+        let user = UserSpace { start: USER_SPACE_START, end: USER_SPACE_END, root: space.root() };
+
+        assert_eq!(user.write(rw, &7u64), Ok(()));
+        assert_eq!(user.read::<u64>(rw), Ok(7));
+        assert_eq!(user.read::<u64>(ro), Ok(0));
+
+        assert_eq!(user.write(ro, &7u64), Err(E_FAULT), "Read-only mapping");
+        assert_eq!(user.write(rx, &7u64), Err(E_FAULT), "Code");
+        assert_eq!(user.read::<u64>(unmapped), Err(E_FAULT));
+        assert_eq!(user.read::<u64>(ro), Ok(0), "Left untouched");
+    }
+
+    #[test]
+    fn test_protection_flags() {
+        let rw = protection_flags(VM_READ | VM_WRITE).unwrap();
+        assert!(rw.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+        let rx = protection_flags(VM_READ | VM_EXEC).unwrap();
+        assert!(!rx.intersects(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+
+        let ro = protection_flags(VM_READ).unwrap();
+        assert!(!ro.contains(PageTableFlags::WRITABLE));
+        assert!(ro.contains(PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE));
+
+        assert_eq!(protection_flags(VM_WRITE | VM_EXEC), Err(E_INVAL), "W^X");
+        assert_eq!(protection_flags(1 << 7), Err(E_INVAL));
+    }
+
+    #[test]
+    fn test_place_region() {
+        let page = FRAME_SIZE;
+        let regions = [
+            VmRegion { start: USER_SPACE_START, size: 2 * page, flags: VM_READ, object_id: 0, cap_id: 0 },
+            VmRegion { start: USER_SPACE_START + 3 * page, size: page, flags: VM_READ, object_id: 0, cap_id: 0 },
+        ];
+
+        // First fit: the one-page gap, then after the last region
+        assert_eq!(place_region(&regions, 0, page), Ok(USER_SPACE_START + 2 * page));
+        assert_eq!(place_region(&regions, 0, 2 * page), Ok(USER_SPACE_START + 4 * page));
+        assert_eq!(place_region(&[], 0, page), Ok(USER_SPACE_START));
+
+        // Fixed addresses must not overlap or leave user space
+        assert_eq!(place_region(&regions, USER_SPACE_START + 2 * page, page), Ok(USER_SPACE_START + 2 * page));
+        assert_eq!(place_region(&regions, USER_SPACE_START + 2 * page, 2 * page), Err(E_INVAL));
+        assert_eq!(place_region(&regions, USER_SPACE_START + page, page), Err(E_INVAL));
+        assert_eq!(place_region(&regions, 0x10_0000, page), Err(E_INVAL));
+        assert_eq!(place_region(&regions, USER_SPACE_END - page, 2 * page), Err(E_INVAL));
+    }
+
+    #[test]
+    fn test_object_mappings_follow_capabilities() {
+        let mut state = KernelState::new();
+        let page = FRAME_SIZE;
+        state.processes.push(ProcessDescriptor::new(1, 0, 0));
+        state.processes.push(ProcessDescriptor::new(2, 0, 0));
+        state.memory_objects.push(MemoryObject { id: 7, owner_pid: 1, frames: Vec::new() });
+
+        let mut root = Capability::new(10, 1, 7, CAP_READ | CAP_WRITE | CAP_DESTROY | CAP_DERIVE);
+        root.kind = CapabilityKind::MemoryObject;
+        let shared = root.derive(11, 2, CAP_READ);
+        let slot = state.processes[0].cspace.insert(root).unwrap();
+        state.processes[1].cspace.insert(shared).unwrap();
+
+        let region = |start, object_id, cap_id| VmRegion { start, size: page, flags: VM_READ, object_id, cap_id };
+        state.processes[0].vm_regions.push(region(USER_SPACE_START, 7, 10));
+        state.processes[1].vm_regions.push(region(USER_SPACE_START, 0, 0));
+        state.processes[1].vm_regions.push(region(USER_SPACE_START + page, 7, 11));
+
+        // Revoking the shared copy drops only the mapping made through it
+        state.revoke_tree(11);
+        unmap_revoked(&mut state);
+        assert_eq!(state.processes[0].vm_regions.len(), 1);
+        assert_eq!(state.processes[1].vm_regions, [region(USER_SPACE_START, 0, 0)]);
+
+        // Destroying the object needs CAP_DESTROY and unmaps it everywhere
+        state.current_process_id = 2;
+        assert_eq!(destroy_memory_object(&mut state, 0), E_CAP_INVALID);
+        state.current_process_id = 1;
+        assert_eq!(destroy_memory_object(&mut state, slot), E_OK);
+        assert!(state.memory_objects.is_empty());
+        assert!(state.processes[0].vm_regions.is_empty());
+        assert_eq!(destroy_memory_object(&mut state, slot), E_PORT_DEAD);
+    }
+
+    #[test]
+    fn test_replaced_fault_page_drops_its_capability() {
+        let page = FRAME_SIZE;
+        let mut process = ProcessDescriptor::new(2, 0, 0);
+        let region = |start, cap_id| VmRegion { start, size: page, flags: VM_READ, object_id: 7, cap_id };
+        for id in [10, 11, 12] {
+            let mut cap = Capability::new(id, 2, 7, CAP_READ);
+            cap.kind = CapabilityKind::MemoryObject;
+            process.cspace.insert(cap).unwrap();
+        }
+        process.vm_regions.push(region(USER_SPACE_START, 10));
+        process.vm_regions.push(region(USER_SPACE_START + page, 11));
+        process.vm_regions.push(region(USER_SPACE_START + 2 * page, 11));
+
+        // Replacing the first page drops capability 10 with it
+        drop_fault_page(&mut process, USER_SPACE_START, 12);
+        assert_eq!(process.vm_regions.len(), 2);
+        assert!(process.cspace.get(1).is_none());
+
+        // Capability 11 still maps the third page
+        drop_fault_page(&mut process, USER_SPACE_START + page, 12);
+        assert!(process.cspace.get(2).is_some());
+        drop_fault_page(&mut process, USER_SPACE_START + 2 * page, 12);
+        assert!(process.cspace.get(2).is_none());
+        assert!(process.cspace.get(3).is_some(), "The new mapping's capability stays");
+    }
+}
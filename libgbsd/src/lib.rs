@@ -16,6 +16,9 @@ pub mod error {
     pub const E_NOT_OWNER: u64 = 0xFFFFFFFF_00000008;
     pub const E_ALIGN: u64 = 0xFFFFFFFF_00000009;
     pub const E_INVALID_SYSCALL: u64 = 0xFFFFFFFF_0000000A;
+    pub const E_WOULD_BLOCK: u64 = 0xFFFFFFFF_0000000B;
+    pub const E_PORT_DEAD: u64 = 0xFFFFFFFF_0000000C;
+    pub const E_FAULT: u64 = 0xFFFFFFFF_0000000D;
 }
 
 /// Syscall numbers
@@ -30,6 +33,24 @@ pub mod syscall {
     pub const SYS_SCHED_YIELD: u64 = 8;
     pub const SYS_SCHED_SWITCH: u64 = 9;
     pub const SYS_TIME: u64 = 10;
+    pub const SYS_PORT_CALL: u64 = 11;
+    pub const SYS_PORT_REPLY: u64 = 12;
+    pub const SYS_PORT_DESTROY: u64 = 13;
+    pub const SYS_PORT_WATCH: u64 = 14;
+    pub const SYS_PORT_SET_ALLOCATE: u64 = 15;
+    pub const SYS_PORT_SET_ADD: u64 = 16;
+    pub const SYS_PORT_SET_REMOVE: u64 = 17;
+    pub const SYS_NOTIFY_ALLOCATE: u64 = 18;
+    pub const SYS_NOTIFY_SIGNAL: u64 = 19;
+    pub const SYS_MEM_STATS: u64 = 20;
+    pub const SYS_MEMOBJ_ALLOCATE: u64 = 21;
+    pub const SYS_MEMOBJ_MAP: u64 = 22;
+    pub const SYS_CAP_REVOKE: u64 = 23;
+    pub const SYS_VM_SET_PAGER: u64 = 24;
+    pub const SYS_SET_EXCEPTION_PORT: u64 = 25;
+    pub const SYS_TIMER_SET: u64 = 26;
+    pub const SYS_TIME_WALL: u64 = 27;
+    pub const SYS_SCHED_SET_PORT: u64 = 28;
 }
 
 /// Capability rights
@@ -43,62 +64,510 @@ pub mod capability {
     pub const CAP_EXECUTE: u32 = 1 << 6;
 }
 
+/// Memory protection for vm_allocate (pages are always readable;
+/// writable and executable together is refused)
+pub mod vm {
+    pub const VM_READ: u32 = 1 << 0;
+    pub const VM_WRITE: u32 = 1 << 1;
+    pub const VM_EXEC: u32 = 1 << 2;
+
+    /// Page faults, sent to pagers as a call (sender_pid = 0):
+    /// [MSG_PAGE_FAULT, pid, addr, access, rip]
+    pub const MSG_PAGE_FAULT: u64 = 0xFFFF_0002;
+
+    /// `access` bits of MSG_PAGE_FAULT
+    pub const FAULT_WRITE: u64 = 1 << 0;
+    pub const FAULT_EXEC: u64 = 1 << 1;
+    pub const FAULT_PROTECTION: u64 = 1 << 2;  // Page mapped, access not allowed
+
+    /// Pager replies: [PAGER_MAP, offset, flags] with a memory object
+    /// capability attached, or [PAGER_KILL]
+    pub const PAGER_MAP: u64 = 1;
+    pub const PAGER_KILL: u64 = 2;
+}
+
+/// IPC flags
+pub mod ipc {
+    pub const IPC_NONBLOCK: u64 = 1 << 0;
+    pub const IPC_CAP_MOVE: u64 = 1 << 1;
+
+    /// Port queue limits (message lengths in u64s)
+    pub const PORT_DEFAULT_QUEUE_DEPTH: u32 = 64;
+    pub const PORT_MAX_QUEUE_DEPTH: u32 = 1024;
+    pub const PORT_DEFAULT_MSG_LEN: u32 = 8;
+    pub const PORT_MAX_MSG_LEN: u32 = 64;
+    pub const PORT_MAX_QUEUE_WORDS: u32 = 16384;
+
+    /// Out-of-line buffer limits, per message
+    pub const IPC_MAX_OOL: usize = 4;
+    pub const IPC_MAX_OOL_BYTES: usize = 65536;
+
+    /// Kernel-generated messages (sender_pid = 0)
+    pub const MSG_PORT_DIED: u64 = 0xFFFF_0001;  // [MSG_PORT_DIED, cookie]
+    pub const MSG_TASK_YIELD: u64 = 0xFFFF_0004;  // [MSG_TASK_YIELD, pid] to the scheduler port
+
+    /// Exceptions, sent to exception ports as a call (sender_pid = 0):
+    /// [MSG_EXCEPTION, pid, vector, code, rip, rsp, addr], where `vector`
+    /// is the CPU exception number and `addr` the page fault address
+    pub const MSG_EXCEPTION: u64 = 0xFFFF_0003;
+
+    /// Exception port replies: [EXCEPTION_RESUME, rip, rsp] (0 keeps
+    /// either), or [EXCEPTION_KILL]
+    pub const EXCEPTION_RESUME: u64 = 1;
+    pub const EXCEPTION_KILL: u64 = 2;
+}
+
+/// Timer ticks for the scheduler (timer_set)
+pub mod timer {
+    /// Signalled on the registered notification every tick
+    pub const NOTIFY_TIMER_TICK: u64 = 1 << 0;
+
+    /// Tick rates timer_set accepts (0 asks for the default)
+    pub const TIMER_DEFAULT_HZ: u32 = 100;
+    pub const TIMER_MIN_HZ: u32 = 19;
+    pub const TIMER_MAX_HZ: u32 = 1000;
+}
+
 /// Message format (8 u64s = 64 bytes)
 pub type Message = [u64; 8];
 
+/// Out-of-band information filled in by port_receive
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct MessageInfo {
+    pub reply_cap: u64,   // One-shot reply capability, 0 for plain sends
+    pub sender_pid: u64,  // PID of the sending process (kernel-stamped)
+    pub badge: u64,       // Badge of the sender's capability, 0 if unbadged
+    pub cap: u64,         // Slot of a capability transferred with the message, 0 if none
+    pub port: u64,        // Slot of the port the message arrived on (member slot for port sets)
+}
+
+/// An out-of-line buffer in our address space
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct OolDescriptor {
+    pub addr: u64,  // Start of the buffer
+    pub len: u64,   // Length in bytes
+}
+
+/// Out-of-line buffers sent with a message, or offered to port_receive as
+/// space for the buffers of the next one (the kernel writes back `count`
+/// and each `desc[i].len`)
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct OolBuffers {
+    pub count: u64,
+    pub desc: [OolDescriptor; ipc::IPC_MAX_OOL],
+}
+
+/// Physical memory and kernel heap usage, filled in by mem_stats
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct MemoryStats {
+    pub frames_total: u64,     // Usable 4 KB physical frames
+    pub frames_free: u64,
+    pub frames_reserved: u64,  // Taken at boot (kernel image, modules, boot info)
+    pub heap_size: u64,        // Bytes of kernel heap mapped so far
+    pub heap_used: u64,
+    pub heap_max: u64,
+    pub heap_failures: u64,    // Kernel allocations refused for lack of memory
+    pub frames_allocated: u64, // Handed out since boot and not yet freed
+}
+
 /// Syscall wrappers for userspace
 #[cfg(target_arch = "x86_64")]
 pub mod x86_64_syscalls {
     use super::*;
 
-    /// Allocate a new port
+    /// Allocate a new port holding `depth` messages of up to `msg_len`
+    /// u64s (0 = default for either)
     #[inline]
-    pub unsafe fn port_allocate() -> u64 {
+    pub unsafe fn port_allocate(depth: u32, msg_len: u32) -> u64 {
         let result: u64;
         asm!("syscall",
-             inout("rax") syscall::SYS_PORT_ALLOCATE => result);
+             inout("rax") syscall::SYS_PORT_ALLOCATE => result,
+             in("rdi") depth as u64,
+             in("rsi") msg_len as u64,
+             lateout("rcx") _, lateout("r11") _);
         result
     }
 
     /// Send a message to a port
     #[inline]
     pub unsafe fn port_send(port: u32, msg: *const Message) -> u64 {
+        port_send_cap(port, msg, 0, 0, 0)
+    }
+
+    /// Send a message carrying the capability in `cap_slot` with `rights`
+    /// (`flags`: IPC_CAP_MOVE to give up our own capability)
+    #[inline]
+    pub unsafe fn port_send_cap(port: u32, msg: *const Message, cap_slot: u32, rights: u32, flags: u64) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_SEND => result,
+             in("rdi") port as u64,
+             in("rsi") msg as u64,
+             in("rdx") 8,
+             in("r10") cap_slot as u64 | (rights as u64) << 32,
+             in("r8") 0,
+             in("r9") flags,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Send a message with out-of-line buffers; the kernel copies them
+    /// before returning, so they may be reused at once
+    #[inline]
+    pub unsafe fn port_send_ool(port: u32, msg: *const Message, ool: *const OolBuffers) -> u64 {
         let result: u64;
         asm!("syscall",
              inout("rax") syscall::SYS_PORT_SEND => result,
              in("rdi") port as u64,
              in("rsi") msg as u64,
-             in("rdx") 8);
+             in("rdx") 8,
+             in("r10") 0,
+             in("r8") ool as u64,
+             in("r9") 0,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Receive a message from a port or port set, sleeping until one arrives
+    /// (`info` may be null)
+    #[inline]
+    pub unsafe fn port_receive(port: u32, buf: *mut Message, info: *mut MessageInfo) -> u64 {
+        port_receive_ool(port, buf, info, core::ptr::null_mut())
+    }
+
+    /// Receive a message and its out-of-line buffers into the space `ool`
+    /// describes (E_INVAL, message left queued, if they do not fit)
+    #[inline]
+    pub unsafe fn port_receive_ool(port: u32, buf: *mut Message, info: *mut MessageInfo, ool: *mut OolBuffers) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_RECEIVE => result,
+             in("rdi") port as u64,
+             in("rsi") buf as u64,
+             in("rdx") 8,
+             in("r10") 0,
+             in("r8") info as u64,
+             in("r9") ool as u64,
+             lateout("rcx") _, lateout("r11") _);
         result
     }
 
-    /// Receive a message from a port
+    /// Receive a message from a port without blocking (E_WOULD_BLOCK if empty)
     #[inline]
-    pub unsafe fn port_receive(port: u32, buf: *mut Message) -> u64 {
+    pub unsafe fn port_try_receive(port: u32, buf: *mut Message, info: *mut MessageInfo) -> u64 {
         let result: u64;
         asm!("syscall",
              inout("rax") syscall::SYS_PORT_RECEIVE => result,
              in("rdi") port as u64,
              in("rsi") buf as u64,
-             in("rdx") 8);
+             in("rdx") 8,
+             in("r10") ipc::IPC_NONBLOCK,
+             in("r8") info as u64,
+             in("r9") 0,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Send a request to a port and wait for the reply (`info` may be null)
+    #[inline]
+    pub unsafe fn port_call(port: u32, msg: *const Message, reply: *mut Message, info: *mut MessageInfo) -> u64 {
+        port_call_ool(port, msg, core::ptr::null(), reply, info)
+    }
+
+    /// Send a request carrying out-of-line buffers and wait for the reply
+    #[inline]
+    pub unsafe fn port_call_ool(
+        port: u32,
+        msg: *const Message,
+        ool: *const OolBuffers,
+        reply: *mut Message,
+        info: *mut MessageInfo,
+    ) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_CALL => result,
+             in("rdi") port as u64,
+             in("rsi") msg as u64,
+             in("rdx") 8 | 8 << 32,  // Request and reply lengths
+             in("r10") reply as u64,
+             in("r8") ool as u64,
+             in("r9") info as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Answer a port_call using the reply capability from MessageInfo
+    #[inline]
+    pub unsafe fn port_reply(reply_cap: u64, msg: *const Message) -> u64 {
+        port_reply_cap(reply_cap, msg, 0, 0, 0)
+    }
+
+    /// Answer a port_call, handing the caller the capability in `cap_slot`
+    #[inline]
+    pub unsafe fn port_reply_cap(reply_cap: u64, msg: *const Message, cap_slot: u32, rights: u32, flags: u64) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_REPLY => result,
+             in("rdi") reply_cap,
+             in("rsi") msg as u64,
+             in("rdx") 8,
+             in("r10") cap_slot as u64 | (rights as u64) << 32,
+             in("r9") flags,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Copy a capability to another process with reduced rights and an
+    /// optional badge (0 = keep the source's badge); needs CAP_DERIVE
+    #[inline]
+    pub unsafe fn cap_move(cap: u32, dst_pid: u32, rights: u32, badge: u64) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_CAP_MOVE => result,
+             in("rdi") cap as u64,
+             in("rsi") dst_pid as u64,
+             in("rdx") rights as u64,
+             in("r10") badge,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Destroy a port (needs CAP_DESTROY); pending and future sends fail
+    /// with E_PORT_DEAD
+    #[inline]
+    pub unsafe fn port_destroy(port: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_DESTROY => result,
+             in("rdi") port as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Ask for [MSG_PORT_DIED, cookie] on `notify_port` when `port` is destroyed
+    #[inline]
+    pub unsafe fn port_watch(port: u32, notify_port: u32, cookie: u64) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_WATCH => result,
+             in("rdi") port as u64,
+             in("rsi") notify_port as u64,
+             in("rdx") cookie,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Allocate an empty port set; pass its slot to port_receive to wait on
+    /// all member ports at once
+    #[inline]
+    pub unsafe fn port_set_allocate() -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_SET_ALLOCATE => result,
+             lateout("rcx") _, lateout("r11") _);
         result
     }
 
-    /// Get current time (monotonic clock)
+    /// Add a port to a set; its messages report MessageInfo.port = `port`
+    #[inline]
+    pub unsafe fn port_set_add(set: u32, port: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_SET_ADD => result,
+             in("rdi") set as u64,
+             in("rsi") port as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Remove a port from a set
+    #[inline]
+    pub unsafe fn port_set_remove(set: u32, port: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_PORT_SET_REMOVE => result,
+             in("rdi") set as u64,
+             in("rsi") port as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Allocate a notification; port_receive on it returns [bits]
+    #[inline]
+    pub unsafe fn notify_allocate() -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_NOTIFY_ALLOCATE => result,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// OR `bits` into a notification (never fails for lack of space)
+    #[inline]
+    pub unsafe fn notify_signal(notify: u32, bits: u64) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_NOTIFY_SIGNAL => result,
+             in("rdi") notify as u64,
+             in("rsi") bits,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Map `size` bytes of zeroed memory with `vm::VM_*` protection at
+    /// `hint` (0 = anywhere); returns the address
+    #[inline]
+    pub unsafe fn vm_allocate(hint: u64, size: u64, flags: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_VM_ALLOCATE => result,
+             in("rdi") hint,
+             in("rsi") size,
+             in("rdx") flags as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Unmap a region returned by vm_allocate
+    #[inline]
+    pub unsafe fn vm_deallocate(addr: u64, size: u64) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_VM_DEALLOCATE => result,
+             in("rdi") addr,
+             in("rsi") size,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Get current time (monotonic clock), in nanoseconds since boot
     #[inline]
     pub unsafe fn sys_time() -> u64 {
         let result: u64;
         asm!("syscall",
-             inout("rax") syscall::SYS_TIME => result);
+             inout("rax") syscall::SYS_TIME => result,
+             lateout("rcx") _, lateout("r11") _);
         result
     }
 
-    /// Yield CPU to scheduler
+    /// Get wall-clock time, in nanoseconds since the Unix epoch (UTC);
+    /// E_INVAL if the kernel could not read the RTC
+    #[inline]
+    pub unsafe fn sys_time_wall() -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_TIME_WALL => result,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Yield CPU to scheduler; returns once the scheduler switches back
     #[inline]
     pub unsafe fn sched_yield() -> u64 {
         let result: u64;
         asm!("syscall",
-             inout("rax") syscall::SYS_SCHED_YIELD => result);
+             inout("rax") syscall::SYS_SCHED_YIELD => result,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Read physical memory and kernel heap usage
+    #[inline]
+    pub unsafe fn mem_stats(stats: *mut MemoryStats) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_MEM_STATS => result,
+             in("rdi") stats as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Create a shared memory object of `size` zeroed bytes; returns the
+    /// slot of a capability to it (destroy with port_destroy)
+    #[inline]
+    pub unsafe fn memobj_allocate(size: u64) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_MEMOBJ_ALLOCATE => result,
+             in("rdi") size,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Map the memory object in `slot` with `vm::VM_*` protection at
+    /// `hint` (0 = anywhere); returns the address
+    #[inline]
+    pub unsafe fn memobj_map(slot: u32, hint: u64, flags: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_MEMOBJ_MAP => result,
+             in("rdi") slot as u64,
+             in("rsi") hint,
+             in("rdx") flags as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Send this process's page faults (and its future children's) to
+    /// `port` (0 = none, faults kill the process)
+    #[inline]
+    pub unsafe fn vm_set_pager(port: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_VM_SET_PAGER => result,
+             in("rdi") port as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Send this process's exceptions to `port` (0 = init_server's port)
+    #[inline]
+    pub unsafe fn set_exception_port(port: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_SET_EXCEPTION_PORT => result,
+             in("rdi") port as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Signal `notify` with timer::NOTIFY_TIMER_TICK `hz` times a second
+    /// (0 = timer::TIMER_DEFAULT_HZ); only scheduler_server (PID 3) may
+    #[inline]
+    pub unsafe fn timer_set(notify: u32, hz: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_TIMER_SET => result,
+             in("rdi") notify as u64,
+             in("rsi") hz as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Receive ipc::MSG_TASK_YIELD on `port` whenever a process yields;
+    /// only scheduler_server (PID 3) may
+    #[inline]
+    pub unsafe fn sched_set_port(port: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_SCHED_SET_PORT => result,
+             in("rdi") port as u64,
+             lateout("rcx") _, lateout("r11") _);
+        result
+    }
+
+    /// Revoke a capability and everything derived from it
+    #[inline]
+    pub unsafe fn cap_revoke(slot: u32) -> u64 {
+        let result: u64;
+        asm!("syscall",
+             inout("rax") syscall::SYS_CAP_REVOKE => result,
+             in("rdi") slot as u64,
+             lateout("rcx") _, lateout("r11") _);
         result
     }
 }
@@ -0,0 +1,50 @@
+#!/bin/bash
+# Boot the kernel built with the qemu-test feature and check that a
+# syscall made from ring 3 round-trips (kernel/src/arch/x86_64/qemu_test.rs)
+#
+# Needs what build.py needs to build a bootable kernel (nightly Rust with
+# rust-src and the x86_64-gbsd.json target spec), plus grub-mkrescue and
+# qemu-system-x86_64. Run from anywhere: scripts/qemu_test.sh
+set -e
+
+cd "$(dirname "$0")/.."
+
+for tool in cargo grub-mkrescue qemu-system-x86_64; do
+    if ! command -v "$tool" >/dev/null; then
+        echo "qemu-test: $tool not found" >&2
+        exit 2
+    fi
+done
+if [ ! -f x86_64-gbsd.json ]; then
+    echo "qemu-test: x86_64-gbsd.json target spec not found" >&2
+    exit 2
+fi
+
+cargo build --release --target x86_64-gbsd.json -p kernel --features qemu-test
+
+mkdir -p target/qemu-test/iso/boot/grub
+cp target/x86_64-gbsd/release/kernel target/qemu-test/iso/boot/
+cat > target/qemu-test/iso/boot/grub/grub.cfg <<'CFG'
+menuentry "GBSD qemu-test" {
+    multiboot2 /boot/kernel
+    boot
+}
+CFG
+grub-mkrescue -o target/qemu-test/gbsd-test.iso target/qemu-test/iso
+
+# isa-debug-exit turns the kernel's verdict into QEMU's exit status:
+# 33 on success, 35 on failure
+set +e
+timeout 60 qemu-system-x86_64 \
+    -cdrom target/qemu-test/gbsd-test.iso \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -serial stdio -display none -no-reboot
+status=$?
+set -e
+
+if [ "$status" -eq 33 ]; then
+    echo "qemu-test: passed"
+else
+    echo "qemu-test: failed (QEMU exit status $status)"
+    exit 1
+fi
@@ -153,17 +153,21 @@ fn print_u32(n: u32) {
     }
 }
 
-/// Allocate a port via syscall
-unsafe fn allocate_port() -> u32 {
+/// Allocate a port via syscall (queue depth and message length in u64s, 0 = default)
+unsafe fn allocate_port(depth: u32, msg_len: u32) -> u32 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
         inout("rax") 1u64 => result,  // SYS_PORT_ALLOCATE = 1
+        in("rdi") depth as u64,
+        in("rsi") msg_len as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result as u32
 }
 
-/// Receive a message from a port via syscall
+/// Receive a message from a port via syscall (blocks while the queue is empty)
 unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
     let result: u64;
     core::arch::x86_64::asm!(
@@ -172,6 +176,11 @@ unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
         in("rdi") port as u64,
         in("rsi") buf as *mut [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // flags: sleep until a message arrives
+        in("r8") 0,   // no message info needed
+        in("r9") 0,   // no out-of-line buffers expected
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
@@ -185,6 +194,10 @@ unsafe fn send_message(port: u32, msg: &[u64; 8]) -> u64 {
         in("rdi") port as u64,
         in("rsi") msg as *const [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // no capability attached
+        in("r8") 0,   // no out-of-line buffers
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
@@ -195,7 +208,7 @@ pub extern "C" fn _start() -> ! {
     print_str("[ext4] ext4_server started (PID 5)\n");
 
     unsafe {
-        let ext4_port = allocate_port();
+        let ext4_port = allocate_port(0, 0);
         print_str("[ext4] Allocated port ");
         print_u32(ext4_port);
         print_str(" for ext4\n");
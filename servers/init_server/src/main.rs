@@ -10,9 +10,17 @@ use core::panic::PanicInfo;
 use core::fmt::Write;
 
 // Message types
-const CMD_SERVICE_DIED: u64 = 1;
 const CMD_REBOOT: u64 = 2;
 const CMD_STATUS: u64 = 3;
+const CMD_LOOKUP_SERVICE: u64 = 4;  // [CMD_LOOKUP_SERVICE, service_idx] via port_call
+const MSG_PORT_DIED: u64 = 0xFFFF_0001;  // From the kernel: [MSG_PORT_DIED, service_idx]
+const MSG_EXCEPTION: u64 = 0xFFFF_0003;  // From the kernel: [MSG_EXCEPTION, pid, vector, code, rip, rsp, addr]
+const EXCEPTION_KILL: u64 = 2;           // Reply to MSG_EXCEPTION
+
+// Service deaths are signalled on init's notification as bit (1 << service index)
+
+// Capability rights handed to clients of a service
+const CAP_SEND: u32 = 1 << 0;
 
 // Service IDs
 const LOG_SERVER_IDX: usize = 0;
@@ -70,6 +78,16 @@ static mut SERVICES: [ServiceDescriptor; MAX_SERVICES] = [
     }; MAX_SERVICES
 ];
 
+/// Out-of-band message information filled in by the kernel on receive
+#[repr(C)]
+struct MessageInfo {
+    reply_cap: u64,   // One-shot reply capability, 0 for plain sends
+    sender_pid: u64,  // Kernel-stamped PID of the sender
+    badge: u64,       // Badge of the sender's capability
+    cap: u64,         // Slot of a transferred capability, 0 if none
+    port: u64,        // Slot of the port the message arrived on
+}
+
 /// Print a string to serial console
 fn print_str(s: &str) {
     for c in s.bytes() {
@@ -80,12 +98,16 @@ fn print_str(s: &str) {
     }
 }
 
-/// Allocate a port via syscall
-unsafe fn allocate_port() -> u32 {
+/// Allocate a port via syscall (queue depth and message length in u64s, 0 = default)
+unsafe fn allocate_port(depth: u32, msg_len: u32) -> u32 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
         inout("rax") 1u64 => result,  // SYS_PORT_ALLOCATE = 1
+        in("rdi") depth as u64,
+        in("rsi") msg_len as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result as u32
 }
@@ -99,6 +121,8 @@ unsafe fn spawn_process(entry: u64, stack: u64) -> u32 {
         in("rdi") entry,
         in("rsi") stack,
         in("rdx") 0,  // name pointer
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result as u32
 }
@@ -112,12 +136,16 @@ unsafe fn send_message(port: u32, msg: &[u64; 8]) -> u64 {
         in("rdi") port as u64,
         in("rsi") msg as *const [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // no capability attached
+        in("r8") 0,   // no out-of-line buffers
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
 
-/// Receive a message from a port via syscall
-unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
+/// Receive a message from a port via syscall (blocks while the queue is empty)
+unsafe fn recv_message(port: u32, buf: &mut [u64; 8], info: &mut MessageInfo) -> u64 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
@@ -125,15 +153,117 @@ unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
         in("rdi") port as u64,
         in("rsi") buf as *mut [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // flags: sleep until a message arrives
+        in("r8") info as *mut MessageInfo as u64,
+        in("r9") 0,  // no out-of-line buffers expected
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result
+}
+
+/// Answer a port_call, handing the caller a send capability copied from `cap_slot`
+unsafe fn reply_with_port(reply_cap: u64, msg: &[u64; 8], cap_slot: u32) -> u64 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 12u64 => result,  // SYS_PORT_REPLY = 12
+        in("rdi") reply_cap,
+        in("rsi") msg as *const [u64; 8] as u64,
+        in("rdx") 8,
+        in("r10") cap_slot as u64 | (CAP_SEND as u64) << 32,  // clients may only send to the service
+        in("r9") 0,  // copy, init keeps its own capability
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result
+}
+
+/// Receive exceptions of every process without its own exception port on `port`
+unsafe fn set_exception_port(port: u32) -> u64 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 25u64 => result,  // SYS_SET_EXCEPTION_PORT = 25
+        in("rdi") port as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result
+}
+
+/// Allocate a port set via syscall
+unsafe fn allocate_port_set() -> u32 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 15u64 => result,  // SYS_PORT_SET_ALLOCATE = 15
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result as u32
+}
+
+/// Add a port or notification to a port set via syscall
+unsafe fn port_set_add(set: u32, member: u32) -> u64 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 16u64 => result,  // SYS_PORT_SET_ADD = 16
+        in("rdi") set as u64,
+        in("rsi") member as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result
+}
+
+/// Allocate a notification via syscall
+unsafe fn allocate_notification() -> u32 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 18u64 => result,  // SYS_NOTIFY_ALLOCATE = 18
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result as u32
+}
+
+/// Destroy a port via syscall; senders get E_PORT_DEAD from now on
+unsafe fn destroy_port(port: u32) -> u64 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 13u64 => result,  // SYS_PORT_DESTROY = 13
+        in("rdi") port as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result
+}
+
+/// Ask the kernel for [MSG_PORT_DIED, cookie] on `notify_port` when `port` dies
+unsafe fn watch_port(port: u32, notify_port: u32, cookie: u64) -> u64 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 14u64 => result,  // SYS_PORT_WATCH = 14
+        in("rdi") port as u64,
+        in("rsi") notify_port as u64,
+        in("rdx") cookie,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
 
 /// Start log_server
-unsafe fn start_log_server() -> u32 {
+unsafe fn start_log_server(init_port: u32) -> u32 {
     print_str("[init] Starting log_server...\n");
 
-    let log_port = allocate_port();
+    let log_port = allocate_port(0, 0);
+    watch_port(log_port, init_port, LOG_SERVER_IDX as u64);
     let pid = spawn_process(LOG_SERVER_ADDR, 0x500000);
 
     let desc = &mut SERVICES[LOG_SERVER_IDX];
@@ -151,10 +281,11 @@ unsafe fn start_log_server() -> u32 {
 }
 
 /// Start scheduler_server
-unsafe fn start_scheduler_server() -> u32 {
+unsafe fn start_scheduler_server(init_port: u32) -> u32 {
     print_str("[init] Starting scheduler_server...\n");
 
-    let sched_port = allocate_port();
+    let sched_port = allocate_port(0, 0);
+    watch_port(sched_port, init_port, SCHEDULER_SERVER_IDX as u64);
     let pid = spawn_process(SCHEDULER_SERVER_ADDR, 0x600000);
 
     let desc = &mut SERVICES[SCHEDULER_SERVER_IDX];
@@ -202,14 +333,24 @@ pub extern "C" fn _start() -> ! {
 
     unsafe {
         // Allocate init_server's own port
-        let init_port = allocate_port();
+        let init_port = allocate_port(0, 0);
         print_str("[init] Allocated port ");
         print_u32(init_port);
         print_str(" for init_server\n");
 
-        // Start bootstrap services
-        start_log_server();
-        start_scheduler_server();
+        // Deaths are signalled as bits so they can never be lost to a full queue
+        let deaths = allocate_notification();
+        let init_set = allocate_port_set();
+        port_set_add(init_set, init_port);
+        port_set_add(init_set, deaths);
+
+        // Faulting processes are reported here instead of crashing the system
+        set_exception_port(init_port);
+
+        // Start bootstrap services; the kernel takes scheduling calls only
+        // from PID 3, so scheduler_server must be the second one started
+        start_log_server(init_port);
+        start_scheduler_server(init_port);
 
         print_str("[init] All bootstrap services started\n");
         print_str("[init] Waiting for events...\n");
@@ -217,19 +358,69 @@ pub extern "C" fn _start() -> ! {
         // Main event loop - handle service messages
         loop {
             let mut msg = [0u64; 8];
-            let result = recv_message(init_port, &mut msg);
+            let mut info = MessageInfo { reply_cap: 0, sender_pid: 0, badge: 0, cap: 0, port: 0 };
+            let result = recv_message(init_set, &mut msg, &mut info);
+
+            if result == 0 && info.port == deaths as u64 {
+                // msg[0] holds one bit per dead service
+                for idx in 0..MAX_SERVICES {
+                    let desc = &SERVICES[idx];
+                    if (msg[0] & (1 << idx)) == 0 || desc.status != SERVICE_STATUS_RUNNING {
+                        continue;
+                    }
 
-            if result == 0 {
+                    print_str("[init] Service PID ");
+                    print_u32(desc.pid);
+                    print_str(" died\n");
+
+                    // Tear down its port; the kernel answers with MSG_PORT_DIED
+                    destroy_port(desc.port);
+                }
+            } else if result == 0 {
                 match msg[0] {
-                    CMD_SERVICE_DIED => {
+                    MSG_PORT_DIED if info.sender_pid == 0 => {
+                        let idx = msg[1] as usize;
+                        if idx < MAX_SERVICES {
+                            SERVICES[idx].status = SERVICE_STATUS_FAILED;
+                            print_str("[init] Port of service ");
+                            print_u32(idx as u32);
+                            print_str(" is gone\n");
+                        }
+                    }
+                    MSG_EXCEPTION if info.sender_pid == 0 => {
                         let pid = msg[1] as u32;
-                        print_str("[init] Service PID ");
+                        print_str("[init] PID ");
                         print_u32(pid);
-                        print_str(" died\n");
+                        print_str(" raised exception ");
+                        print_u32(msg[2] as u32);
+                        print_str(", killing it\n");
+
+                        let reply = [EXCEPTION_KILL, 0, 0, 0, 0, 0, 0, 0];
+                        let _ = reply_with_port(info.reply_cap, &reply, 0);
+
+                        // Its death is handled like any other service's
+                        for idx in 0..MAX_SERVICES {
+                            if SERVICES[idx].pid == pid && SERVICES[idx].status == SERVICE_STATUS_RUNNING {
+                                destroy_port(SERVICES[idx].port);
+                            }
+                        }
                     }
                     CMD_REBOOT => {
                         print_str("[init] Reboot requested\n");
                     }
+                    CMD_LOOKUP_SERVICE => {
+                        // Hand the client a capability for the service's port
+                        let idx = msg[1] as usize;
+                        if idx < MAX_SERVICES && SERVICES[idx].status == SERVICE_STATUS_RUNNING {
+                            // Our slot number means nothing to the client; it finds
+                            // its own handle in MessageInfo.cap
+                            let reply = [0, 0, 0, 0, 0, 0, 0, 0];
+                            let _ = reply_with_port(info.reply_cap, &reply, SERVICES[idx].port);
+                        } else {
+                            let reply = [1, 0, 0, 0, 0, 0, 0, 0];  // No such service
+                            let _ = reply_with_port(info.reply_cap, &reply, 0);
+                        }
+                    }
                     _ => {
                         print_str("[init] Unknown message: ");
                         print_u32(msg[0] as u32);
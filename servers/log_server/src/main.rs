@@ -19,6 +19,13 @@ const LOG_INFO: u32 = 1;
 const LOG_WARN: u32 = 2;
 const LOG_ERROR: u32 = 3;
 
+// vm_allocate protection flags
+const VM_READ: u32 = 1 << 0;
+const VM_WRITE: u32 = 1 << 1;
+
+// Depth of the log port's queue, in messages
+const LOG_QUEUE_DEPTH: u32 = 512;
+
 /// Log entry
 #[repr(C)]
 struct LogEntry {
@@ -28,33 +35,30 @@ struct LogEntry {
     message: [u8; 256],
 }
 
-impl LogEntry {
-    fn new() -> Self {
-        LogEntry {
-            timestamp: 0,
-            source_pid: 0,
-            level: 0,
-            message: [0u8; 256],
-        }
-    }
-}
+// Entries kept in the ring buffer (about 4 MB)
+const LOG_CAPACITY: usize = 16384;
 
-/// Ring buffer for log entries
+/// Ring buffer for log entries, in memory from vm_allocate
 struct LogRingBuffer {
-    buffer: [LogEntry; 16384],  // 4 MB total
+    buffer: &'static mut [LogEntry],
     head: usize,                 // Next write position
     tail: usize,                 // Oldest entry
     count: usize,
 }
 
 impl LogRingBuffer {
-    fn new() -> Self {
-        LogRingBuffer {
-            buffer: [LogEntry::new(); 16384],
+    /// Map the buffer; None if the kernel has no memory for it
+    unsafe fn new() -> Option<Self> {
+        let size = (LOG_CAPACITY * core::mem::size_of::<LogEntry>()) as u64;
+        let addr = vm_allocate(size, VM_READ | VM_WRITE)?;
+
+        // Fresh pages are zeroed, which is a valid empty LogEntry
+        Some(LogRingBuffer {
+            buffer: core::slice::from_raw_parts_mut(addr as *mut LogEntry, LOG_CAPACITY),
             head: 0,
             tail: 0,
             count: 0,
-        }
+        })
     }
 
     fn write(&mut self, entry: &LogEntry) {
@@ -65,20 +69,46 @@ impl LogRingBuffer {
             message: entry.message,
         };
 
-        self.head = (self.head + 1) % 16384;
+        self.head = (self.head + 1) % LOG_CAPACITY;
 
-        if self.count < 16384 {
+        if self.count < LOG_CAPACITY {
             self.count += 1;
         } else {
-            self.tail = (self.tail + 1) % 16384;
+            self.tail = (self.tail + 1) % LOG_CAPACITY;
         }
     }
 
     fn is_full(&self) -> bool {
-        self.count >= 16384
+        self.count >= LOG_CAPACITY
     }
 }
 
+/// Out-of-band message information filled in by the kernel on receive
+#[repr(C)]
+struct MessageInfo {
+    reply_cap: u64,   // One-shot reply capability, 0 for plain sends
+    sender_pid: u64,  // Kernel-stamped PID of the sender
+    badge: u64,       // Badge of the sender's capability
+    cap: u64,         // Slot of a transferred capability, 0 if none
+    port: u64,        // Slot of the port the message arrived on
+}
+
+/// Out-of-line buffer descriptor (see libgbsd::OolDescriptor)
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct OolDescriptor {
+    addr: u64,
+    len: u64,
+}
+
+/// Out-of-line buffers offered to port_receive; the kernel writes back
+/// how many arrived and their lengths
+#[repr(C)]
+struct OolBuffers {
+    count: u64,
+    desc: [OolDescriptor; 4],
+}
+
 /// Print a string to serial console
 fn print_str(s: &str) {
     for c in s.bytes() {
@@ -99,6 +129,8 @@ fn print_log_entry(entry: &LogEntry) {
         _ => print_str("[?]     "),
     }
 
+    print_timestamp(entry.timestamp);
+
     // Source PID
     print_str("PID ");
     print_u32(entry.source_pid);
@@ -116,6 +148,22 @@ fn print_log_entry(entry: &LogEntry) {
     print_str("\n");
 }
 
+/// Print nanoseconds since boot as "[seconds.milliseconds] "
+fn print_timestamp(ns: u64) {
+    let millis = (ns / 1_000_000 % 1000) as u32;
+    print_str("[");
+    print_u32((ns / 1_000_000_000) as u32);
+    print_str(".");
+    if millis < 100 {
+        print_str("0");
+    }
+    if millis < 10 {
+        print_str("0");
+    }
+    print_u32(millis);
+    print_str("] ");
+}
+
 /// Helper to print u32
 fn print_u32(n: u32) {
     if n == 0 {
@@ -140,18 +188,34 @@ fn print_u32(n: u32) {
     }
 }
 
-/// Allocate a port via syscall
-unsafe fn allocate_port() -> u32 {
+/// Allocate a port via syscall (queue depth and message length in u64s, 0 = default)
+unsafe fn allocate_port(depth: u32, msg_len: u32) -> u32 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
         inout("rax") 1u64 => result,  // SYS_PORT_ALLOCATE = 1
+        in("rdi") depth as u64,
+        in("rsi") msg_len as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result as u32
 }
 
-/// Receive a message from a port via syscall
-unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
+/// Receive a message from a port via syscall (blocks while the queue is empty)
+///
+/// Log text travels out of line and lands in `text`; `ool.count` is 0 for
+/// messages without any.
+unsafe fn recv_message(
+    port: u32,
+    buf: &mut [u64; 8],
+    info: &mut MessageInfo,
+    text: &mut [u8; 256],
+    ool: &mut OolBuffers,
+) -> u64 {
+    ool.count = 1;
+    ool.desc[0] = OolDescriptor { addr: text.as_mut_ptr() as u64, len: text.len() as u64 };
+
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
@@ -159,16 +223,38 @@ unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
         in("rdi") port as u64,
         in("rsi") buf as *mut [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // flags: sleep until a message arrives
+        in("r8") info as *mut MessageInfo as u64,
+        in("r9") ool as *mut OolBuffers as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
 
-/// Get current time via syscall
+/// Map zeroed memory anywhere via syscall; None on error
+unsafe fn vm_allocate(size: u64, flags: u32) -> Option<u64> {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 4u64 => result,  // SYS_VM_ALLOCATE = 4
+        in("rdi") 0,  // hint: anywhere
+        in("rsi") size,
+        in("rdx") flags as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    if result >= 0xFFFFFFFF_00000000 { None } else { Some(result) }
+}
+
+/// Get nanoseconds since boot via syscall
 unsafe fn sys_time() -> u64 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
         inout("rax") 10u64 => result,  // SYS_TIME = 10
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
@@ -179,28 +265,45 @@ pub extern "C" fn _start() -> ! {
     print_str("[log] log_server started (PID 2)\n");
 
     unsafe {
-        let log_port = allocate_port();
+        // Many small fire-and-forget messages: a deep queue absorbs bursts
+        let log_port = allocate_port(LOG_QUEUE_DEPTH, 8);
         print_str("[log] Allocated port ");
         print_u32(log_port);
         print_str(" for logging\n");
 
-        let mut buffer = LogRingBuffer::new();
+        let mut buffer = match LogRingBuffer::new() {
+            Some(buffer) => buffer,
+            None => panic!("no memory for the log buffer"),
+        };
         print_str("[log] Ready for log messages\n");
 
         // Main loop
         loop {
             let mut msg = [0u64; 8];
-            let result = recv_message(log_port, &mut msg);
+            let mut info = MessageInfo { reply_cap: 0, sender_pid: 0, badge: 0, cap: 0, port: 0 };
+            let mut text = [0u8; 256];
+            let mut ool = OolBuffers { count: 0, desc: [OolDescriptor { addr: 0, len: 0 }; 4] };
+            let result = recv_message(log_port, &mut msg, &mut info, &mut text, &mut ool);
 
             if result == 0 {
                 match msg[0] {
                     LOG_WRITE => {
-                        // Parse log message: [LOG_WRITE, timestamp, level, pid, ...]
+                        // Parse log message: [LOG_WRITE, timestamp, level, ...]
+                        // The source PID and the time come from the kernel, not
+                        // the payload, so entries from every client line up
+                        // The text arrives out of line; keep at most 255 bytes
+                        // so the entry stays null-terminated
+                        let mut message = [0u8; 256];
+                        if ool.count >= 1 {
+                            let len = (ool.desc[0].len as usize).min(255);
+                            message[..len].copy_from_slice(&text[..len]);
+                        }
+
                         let entry = LogEntry {
-                            timestamp: msg[1],
-                            source_pid: msg[3] as u32,
+                            timestamp: sys_time(),
+                            source_pid: info.sender_pid as u32,
                             level: msg[2] as u32,
-                            message: [0u8; 256],  // TODO: copy from msg
+                            message,
                         };
 
                         buffer.write(&entry);
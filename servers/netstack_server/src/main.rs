@@ -150,6 +150,16 @@ impl SocketTable {
     }
 }
 
+/// Out-of-band message information filled in by the kernel on receive
+#[repr(C)]
+struct MessageInfo {
+    reply_cap: u64,   // One-shot reply capability, 0 for plain sends
+    sender_pid: u64,  // Kernel-stamped PID of the sender
+    badge: u64,       // Badge of the sender's capability
+    cap: u64,         // Slot of a transferred capability, 0 if none
+    port: u64,        // Slot of the port the message arrived on
+}
+
 /// Print a string to serial console
 fn print_str(s: &str) {
     for c in s.bytes() {
@@ -183,18 +193,22 @@ fn print_u32(n: u32) {
     }
 }
 
-/// Allocate a port via syscall
-unsafe fn allocate_port() -> u32 {
+/// Allocate a port via syscall (queue depth and message length in u64s, 0 = default)
+unsafe fn allocate_port(depth: u32, msg_len: u32) -> u32 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
         inout("rax") 1u64 => result,  // SYS_PORT_ALLOCATE = 1
+        in("rdi") depth as u64,
+        in("rsi") msg_len as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result as u32
 }
 
-/// Receive a message from a port via syscall
-unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
+/// Receive a message from a port via syscall (blocks while the queue is empty)
+unsafe fn recv_message(port: u32, buf: &mut [u64; 8], info: &mut MessageInfo) -> u64 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
@@ -202,19 +216,27 @@ unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
         in("rdi") port as u64,
         in("rsi") buf as *mut [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // flags: sleep until a message arrives
+        in("r8") info as *mut MessageInfo as u64,
+        in("r9") 0,  // no out-of-line buffers expected
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
 
-/// Send a message to a port via syscall
-unsafe fn send_message(port: u32, msg: &[u64; 8]) -> u64 {
+/// Answer a client's port_call via syscall
+unsafe fn reply_message(reply_cap: u64, msg: &[u64; 8]) -> u64 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
-        inout("rax") 2u64 => result,  // SYS_PORT_SEND = 2
-        in("rdi") port as u64,
+        inout("rax") 12u64 => result,  // SYS_PORT_REPLY = 12
+        in("rdi") reply_cap,
         in("rsi") msg as *const [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // no capability attached
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
@@ -225,7 +247,7 @@ pub extern "C" fn _start() -> ! {
     print_str("[net] netstack_server started (PID 6)\n");
 
     unsafe {
-        let net_port = allocate_port();
+        let net_port = allocate_port(0, 0);
         print_str("[net] Allocated port ");
         print_u32(net_port);
         print_str(" for networking\n");
@@ -238,13 +260,14 @@ pub extern "C" fn _start() -> ! {
         // Main event loop
         loop {
             let mut msg = [0u64; 8];
-            let result = recv_message(net_port, &mut msg);
+            let mut info = MessageInfo { reply_cap: 0, sender_pid: 0, badge: 0, cap: 0, port: 0 };
+            let result = recv_message(net_port, &mut msg, &mut info);
 
             if result == 0 {
                 match msg[0] {
                     SOCKET_CREATE => {
                         let socket_type = msg[1] as u32;
-                        let owner_pid = msg[2] as u32;
+                        let owner_pid = info.sender_pid as u32;  // Kernel-stamped, not from payload
 
                         if let Some(socket_id) = socket_table.create_socket(socket_type, owner_pid) {
                             print_str("[net] Socket created: ID ");
@@ -254,7 +277,7 @@ pub extern "C" fn _start() -> ! {
                             print_str(")\n");
 
                             let reply = [socket_id as u64, 0, 0, 0, 0, 0, 0, 0];
-                            let _ = send_message(net_port, &reply);
+                            let _ = reply_message(info.reply_cap, &reply);
                         }
                     }
                     SOCKET_BIND => {
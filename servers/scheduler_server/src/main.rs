@@ -9,9 +9,14 @@ extern crate core;
 use core::panic::PanicInfo;
 
 // Scheduler message types
-const MSG_TIMER_TICK: u64 = 1;
-const MSG_TASK_YIELD: u64 = 2;
-const MSG_TASK_SLEEP: u64 = 3;
+const MSG_TASK_SLEEP: u64 = 3;  // [MSG_TASK_SLEEP, pid, duration in ns]
+const MSG_TASK_YIELD: u64 = 0xFFFF_0004;  // From the kernel: [MSG_TASK_YIELD, pid]
+
+// Bits signalled on the scheduler's event notification
+const NOTIFY_TIMER_TICK: u64 = 1 << 0;
+
+// Preemption rate, in timer ticks per second
+const TICK_HZ: u32 = 100;
 
 /// Scheduler state
 struct Scheduler {
@@ -20,7 +25,7 @@ struct Scheduler {
     queue_tail: usize,
     queue_size: usize,
 
-    sleeping: [(u32, u64); 256],  // (PID, wake_time)
+    sleeping: [(u32, u64); 256],  // (PID, wake_time in ns since boot)
     sleeping_count: usize,
 
     current_pid: u32,
@@ -73,6 +78,16 @@ impl Scheduler {
     }
 }
 
+/// Out-of-band message information filled in by the kernel on receive
+#[repr(C)]
+struct MessageInfo {
+    reply_cap: u64,   // One-shot reply capability, 0 for plain sends
+    sender_pid: u64,  // Kernel-stamped PID of the sender
+    badge: u64,       // Badge of the sender's capability
+    cap: u64,         // Slot of a transferred capability, 0 if none
+    port: u64,        // Slot of the port the message arrived on
+}
+
 /// Print a string to serial console
 fn print_str(s: &str) {
     for c in s.bytes() {
@@ -106,18 +121,87 @@ fn print_u32(n: u32) {
     }
 }
 
-/// Allocate a port via syscall
-unsafe fn allocate_port() -> u32 {
+/// Allocate a port via syscall (queue depth and message length in u64s, 0 = default)
+unsafe fn allocate_port(depth: u32, msg_len: u32) -> u32 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
         inout("rax") 1u64 => result,  // SYS_PORT_ALLOCATE = 1
+        in("rdi") depth as u64,
+        in("rsi") msg_len as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result as u32
+}
+
+/// Allocate a port set via syscall
+unsafe fn allocate_port_set() -> u32 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 15u64 => result,  // SYS_PORT_SET_ALLOCATE = 15
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result as u32
 }
 
-/// Receive a message from a port via syscall
-unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
+/// Add a port or notification to a port set via syscall
+unsafe fn port_set_add(set: u32, member: u32) -> u64 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 16u64 => result,  // SYS_PORT_SET_ADD = 16
+        in("rdi") set as u64,
+        in("rsi") member as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result
+}
+
+/// Allocate a notification via syscall
+unsafe fn allocate_notification() -> u32 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 18u64 => result,  // SYS_NOTIFY_ALLOCATE = 18
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result as u32
+}
+
+/// Have the kernel signal the notification on every timer tick via syscall
+unsafe fn set_timer(notify: u32, hz: u32) -> u64 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 26u64 => result,  // SYS_TIMER_SET = 26
+        in("rdi") notify as u64,
+        in("rsi") hz as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result
+}
+
+/// Have the kernel report yields on the port via syscall
+unsafe fn set_yield_port(port: u32) -> u64 {
+    let result: u64;
+    core::arch::x86_64::asm!(
+        "syscall",
+        inout("rax") 28u64 => result,  // SYS_SCHED_SET_PORT = 28
+        in("rdi") port as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
+    );
+    result
+}
+
+/// Receive from a port or port set via syscall (blocks while nothing is pending)
+unsafe fn recv_message(port: u32, buf: &mut [u64; 8], info: &mut MessageInfo) -> u64 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
@@ -125,16 +209,23 @@ unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
         in("rdi") port as u64,
         in("rsi") buf as *mut [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // flags: sleep until a message arrives
+        in("r8") info as *mut MessageInfo as u64,
+        in("r9") 0,  // no out-of-line buffers expected
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
 
-/// Get current time via syscall
+/// Get nanoseconds since boot via syscall
 unsafe fn sys_time() -> u64 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
         inout("rax") 10u64 => result,  // SYS_TIME = 10
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
@@ -146,6 +237,8 @@ unsafe fn sched_switch(target_pid: u32) -> u64 {
         "syscall",
         inout("rax") 9u64 => result,  // SYS_SCHED_SWITCH = 9
         in("rdi") target_pid as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
@@ -156,11 +249,23 @@ pub extern "C" fn _start() -> ! {
     print_str("[scheduler] scheduler_server started (PID 3)\n");
 
     unsafe {
-        let sched_port = allocate_port();
+        let sched_port = allocate_port(0, 0);
         print_str("[scheduler] Allocated port ");
         print_u32(sched_port);
         print_str(" for scheduling\n");
 
+        // Timer ticks arrive as notification bits, so they never fill the port
+        let events = allocate_notification();
+        let sched_set = allocate_port_set();
+        port_set_add(sched_set, sched_port);
+        port_set_add(sched_set, events);
+        if set_timer(events, TICK_HZ) != 0 {
+            print_str("[scheduler] Could not take the timer; no preemption\n");
+        }
+        if set_yield_port(sched_port) != 0 {
+            print_str("[scheduler] Could not take yields\n");
+        }
+
         let mut scheduler = Scheduler::new();
 
         print_str("[scheduler] Ready queue: empty\n");
@@ -172,25 +277,29 @@ pub extern "C" fn _start() -> ! {
             let now = sys_time();
             scheduler.wake_expired_tasks(now);
 
-            // Try to receive a message
+            // Wait for a request or an event
             let mut msg = [0u64; 8];
-            let result = recv_message(sched_port, &mut msg);
-
-            if result == 0 {
-                match msg[0] {
-                    MSG_TIMER_TICK => {
-                        // Put current task back in queue
-                        if scheduler.current_pid != 0 {
-                            scheduler.enqueue(scheduler.current_pid);
-                        }
+            let mut info = MessageInfo { reply_cap: 0, sender_pid: 0, badge: 0, cap: 0, port: 0 };
+            let result = recv_message(sched_set, &mut msg, &mut info);
+
+            if result == 0 && info.port == events as u64 {
+                // msg[0] holds the signalled bits
+                if (msg[0] & NOTIFY_TIMER_TICK) != 0 {
+                    // Put current task back in queue
+                    if scheduler.current_pid != 0 {
+                        scheduler.enqueue(scheduler.current_pid);
+                    }
 
-                        // Pick next from queue
-                        if let Some(next) = scheduler.dequeue() {
-                            scheduler.current_pid = next;
-                            let _ = sched_switch(next);
-                        }
+                    // Pick next from queue
+                    if let Some(next) = scheduler.dequeue() {
+                        scheduler.current_pid = next;
+                        let _ = sched_switch(next);
                     }
-                    MSG_TASK_YIELD => {
+                }
+            } else if result == 0 {
+                match msg[0] {
+                    // Only the kernel's word counts for who yielded
+                    MSG_TASK_YIELD if info.sender_pid == 0 => {
                         let yielding_pid = msg[1] as u32;
 
                         // Put yielding task back in queue
@@ -207,7 +316,7 @@ pub extern "C" fn _start() -> ! {
                         let duration = msg[2];
 
                         // Add to sleeping map
-                        let wake_time = sys_time() + duration;
+                        let wake_time = sys_time().saturating_add(duration);
                         if scheduler.sleeping_count < 256 {
                             scheduler.sleeping[scheduler.sleeping_count] = (pid, wake_time);
                             scheduler.sleeping_count += 1;
@@ -24,6 +24,12 @@ const VFS_UNLINK: u64 = 10;
 const S_IFREG: u32 = 0o100000;  // Regular file
 const S_IFDIR: u32 = 0o040000;  // Directory
 
+// Depth of the vfs port's queue, in messages
+const VFS_QUEUE_DEPTH: u32 = 16;
+
+// Largest VFS_WRITE payload accepted in one request, in bytes
+const VFS_MAX_WRITE: usize = 4096;
+
 /// INode representation
 #[repr(C)]
 struct INode {
@@ -158,6 +164,32 @@ impl TmpfsVolume {
     }
 }
 
+/// Out-of-band message information filled in by the kernel on receive
+#[repr(C)]
+struct MessageInfo {
+    reply_cap: u64,   // One-shot reply capability, 0 for plain sends
+    sender_pid: u64,  // Kernel-stamped PID of the sender
+    badge: u64,       // Badge of the sender's capability
+    cap: u64,         // Slot of a transferred capability, 0 if none
+    port: u64,        // Slot of the port the message arrived on
+}
+
+/// Out-of-line buffer descriptor (see libgbsd::OolDescriptor)
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct OolDescriptor {
+    addr: u64,
+    len: u64,
+}
+
+/// Out-of-line buffers offered to port_receive; the kernel writes back
+/// how many arrived and their lengths
+#[repr(C)]
+struct OolBuffers {
+    count: u64,
+    desc: [OolDescriptor; 4],
+}
+
 /// Print a string to serial console
 fn print_str(s: &str) {
     for c in s.bytes() {
@@ -191,18 +223,34 @@ fn print_u64(n: u64) {
     }
 }
 
-/// Allocate a port via syscall
-unsafe fn allocate_port() -> u32 {
+/// Allocate a port via syscall (queue depth and message length in u64s, 0 = default)
+unsafe fn allocate_port(depth: u32, msg_len: u32) -> u32 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
         inout("rax") 1u64 => result,  // SYS_PORT_ALLOCATE = 1
+        in("rdi") depth as u64,
+        in("rsi") msg_len as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result as u32
 }
 
-/// Receive a message from a port via syscall
-unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
+/// Receive a message from a port via syscall (blocks while the queue is empty)
+///
+/// Write payloads travel out of line and land in `data`; `ool.count` is 0
+/// for requests without one.
+unsafe fn recv_message(
+    port: u32,
+    buf: &mut [u64; 8],
+    info: &mut MessageInfo,
+    data: &mut [u8; VFS_MAX_WRITE],
+    ool: &mut OolBuffers,
+) -> u64 {
+    ool.count = 1;
+    ool.desc[0] = OolDescriptor { addr: data.as_mut_ptr() as u64, len: data.len() as u64 };
+
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
@@ -210,19 +258,27 @@ unsafe fn recv_message(port: u32, buf: &mut [u64; 8]) -> u64 {
         in("rdi") port as u64,
         in("rsi") buf as *mut [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // flags: sleep until a message arrives
+        in("r8") info as *mut MessageInfo as u64,
+        in("r9") ool as *mut OolBuffers as u64,
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
 
-/// Send a message to a port via syscall
-unsafe fn send_message(port: u32, msg: &[u64; 8]) -> u64 {
+/// Answer a client's port_call via syscall
+unsafe fn reply_message(reply_cap: u64, msg: &[u64; 8]) -> u64 {
     let result: u64;
     core::arch::x86_64::asm!(
         "syscall",
-        inout("rax") 2u64 => result,  // SYS_PORT_SEND = 2
-        in("rdi") port as u64,
+        inout("rax") 12u64 => result,  // SYS_PORT_REPLY = 12
+        in("rdi") reply_cap,
         in("rsi") msg as *const [u64; 8] as u64,
         in("rdx") 8,
+        in("r10") 0,  // no capability attached
+        lateout("rcx") _,
+        lateout("r11") _,
     );
     result
 }
@@ -233,7 +289,8 @@ pub extern "C" fn _start() -> ! {
     print_str("[vfs] vfs_server started (PID 4)\n");
 
     unsafe {
-        let vfs_port = allocate_port();
+        // Clients block in port_call, so a shallow queue is enough
+        let vfs_port = allocate_port(VFS_QUEUE_DEPTH, 8);
         print_str("[vfs] Allocated port ");
         print_u64(vfs_port as u64);
         print_str(" for VFS\n");
@@ -245,28 +302,36 @@ pub extern "C" fn _start() -> ! {
         // Main event loop
         loop {
             let mut msg = [0u64; 8];
-            let result = recv_message(vfs_port, &mut msg);
+            let mut info = MessageInfo { reply_cap: 0, sender_pid: 0, badge: 0, cap: 0, port: 0 };
+            let mut data = [0u8; VFS_MAX_WRITE];
+            let mut ool = OolBuffers { count: 0, desc: [OolDescriptor { addr: 0, len: 0 }; 4] };
+            let result = recv_message(vfs_port, &mut msg, &mut info, &mut data, &mut ool);
 
             if result == 0 {
                 match msg[0] {
                     VFS_OPEN => {
                         // Create a new file
-                        if let Some(inode_id) = tmpfs.create_file(0o644, msg[3] as u32) {
+                        if let Some(inode_id) = tmpfs.create_file(0o644, info.sender_pid as u32) {
                             // Send back the inode_id as file descriptor
                             let reply = [inode_id, 0, 0, 0, 0, 0, 0, 0];
-                            let _ = send_message(vfs_port, &reply);
+                            let _ = reply_message(info.reply_cap, &reply);
                         }
                     }
                     VFS_WRITE => {
-                        // Write to a file
+                        // Write to a file: [VFS_WRITE, inode_id] with the
+                        // data as an out-of-line buffer
                         let inode_id = msg[1];
-                        let size = msg[2] as usize;
-                        // In real impl, would copy data from message
+                        let size = if ool.count >= 1 { ool.desc[0].len as usize } else { 0 };
                         print_str("[vfs] Write request for inode ");
                         print_u64(inode_id);
                         print_str(" (");
                         print_u64(size as u64);
                         print_str(" bytes)\n");
+
+                        // Reply with the number of bytes written
+                        let written = tmpfs.write_file(inode_id, &data[..size]).unwrap_or(0);
+                        let reply = [written as u64, 0, 0, 0, 0, 0, 0, 0];
+                        let _ = reply_message(info.reply_cap, &reply);
                     }
                     VFS_READ => {
                         print_str("[vfs] Read request received\n");